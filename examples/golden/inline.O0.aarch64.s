@@ -0,0 +1,65 @@
+    .section .rodata
+.Lstr_0:
+    .asciz "%ld\012"
+    .text
+    .globl add
+    .p2align 2
+add:
+    stp x29, x30, [sp, #-16]!
+    mov x29, sp
+    mov x9, x0
+    mov x10, x1
+.Lblock_1:
+.Lblock_2:
+    add x9, x9, x10
+    mov x0, x9
+.Lepilogue_0:
+    ldp x29, x30, [sp], #16
+    ret
+    .globl clamp
+    .p2align 2
+clamp:
+    stp x29, x30, [sp, #-16]!
+    mov x29, sp
+    mov x9, x0
+    mov x10, x1
+.Lblock_4:
+.Lblock_5:
+    cmp x9, x10
+    b.le .Lblock_7
+.Lblock_6:
+    mov x0, x10
+    b .Lepilogue_3
+.Lblock_7:
+    mov x0, x9
+.Lepilogue_3:
+    ldp x29, x30, [sp], #16
+    ret
+    .globl main
+    .p2align 2
+main:
+    stp x29, x30, [sp, #-16]!
+    mov x29, sp
+.Lblock_9:
+.Lblock_10:
+    mov x0, #1
+    mov x1, #2
+    bl add
+    mov x9, x0
+    mov x0, x9
+    mov x1, #40
+    bl add
+    mov x9, x0
+    mov x0, x9
+    mov x1, #42
+    bl clamp
+    mov x9, x0
+    mov x1, x9
+    adrp x0, .Lstr_0
+    add x0, x0, :lo12:.Lstr_0
+    bl printf
+    mov x0, #0
+.Lepilogue_8:
+    ldp x29, x30, [sp], #16
+    ret
+    .section .note.GNU-stack,"",@progbits
@@ -0,0 +1,87 @@
+extern _printf
+section .rodata
+str_0: db "%ld", 10, 0
+global _main
+section .text
+global _add
+_add:
+    push rbp
+    mov rbp, rsp
+    sub rsp, 32
+    mov [rbp - 8], rdi
+    mov [rbp - 16], rsi
+body_1:
+    mov rax, [rbp - 8]
+    push rax
+    mov rax, [rbp - 16]
+    pop rcx
+    add rax, rcx
+    jmp epilogue_0
+    mov rax, 0
+epilogue_0:
+    mov rsp, rbp
+    pop rbp
+    ret
+global _clamp
+_clamp:
+    push rbp
+    mov rbp, rsp
+    sub rsp, 32
+    mov [rbp - 8], rdi
+    mov [rbp - 16], rsi
+body_3:
+    mov rax, [rbp - 8]
+    push rax
+    mov rax, [rbp - 16]
+    pop rcx
+    cmp rcx, rax
+    jle endif_4
+    mov rax, [rbp - 16]
+    jmp epilogue_2
+endif_4:
+    mov rax, [rbp - 8]
+    jmp epilogue_2
+    mov rax, 0
+epilogue_2:
+    mov rsp, rbp
+    pop rbp
+    ret
+_main:
+    push rbp
+    mov rbp, rsp
+    sub rsp, 32
+body_6:
+    mov rax, 1
+    push rax
+    mov rax, 2
+    push rax
+    pop rsi
+    pop rdi
+    mov rax, 0
+    call _add
+    mov [rbp - 8], rax
+    mov rax, [rbp - 8]
+    push rax
+    mov rax, 40
+    push rax
+    pop rsi
+    pop rdi
+    mov rax, 0
+    call _add
+    push rax
+    mov rax, 42
+    push rax
+    pop rsi
+    pop rdi
+    mov rax, 0
+    call _clamp
+    mov [rbp - 16], rax
+    lea rdi, [rel str_0]
+    mov rsi, [rbp - 16]
+    mov rax, 0
+    call _printf
+    mov rax, 0
+epilogue_5:
+    mov rsp, rbp
+    pop rbp
+    ret
@@ -0,0 +1,52 @@
+extern _printf
+section .rodata
+str_0: db "%ld", 10, 0
+global _main
+section .text
+_main:
+    push rbp
+    mov rbp, rsp
+    sub rsp, 96
+body_1:
+    mov rax, 1
+    mov [rbp - 8], rax
+    mov rax, 2
+    mov [rbp - 16], rax
+    mov rax, 3
+    mov [rbp - 24], rax
+inline_end_2:
+    mov rax, [rbp - 24]
+    mov [rbp - 32], rax
+    mov rax, [rbp - 32]
+    mov [rbp - 40], rax
+    mov rax, 40
+    mov [rbp - 48], rax
+    mov rax, [rbp - 40]
+    add rax, 40
+    mov [rbp - 56], rax
+inline_end_3:
+    mov rax, [rbp - 56]
+    mov [rbp - 64], rax
+    mov rax, 42
+    mov [rbp - 72], rax
+    mov rax, [rbp - 64]
+    cmp rax, 42
+    jle endif_5
+    mov rax, 42
+    mov [rbp - 80], rax
+    jmp inline_end_4
+endif_5:
+    mov rax, [rbp - 64]
+    mov [rbp - 80], rax
+inline_end_4:
+    mov rax, [rbp - 80]
+    mov [rbp - 88], rax
+    lea rdi, [rel str_0]
+    mov rsi, [rbp - 88]
+    mov rax, 0
+    call _printf
+    mov rax, 0
+epilogue_0:
+    mov rsp, rbp
+    pop rbp
+    ret
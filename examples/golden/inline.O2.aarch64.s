@@ -0,0 +1,42 @@
+    .section .rodata
+.Lstr_0:
+    .asciz "%ld\012"
+    .text
+    .globl main
+    .p2align 2
+main:
+    stp x29, x30, [sp, #-32]!
+    mov x29, sp
+    stp x19, x20, [x29, #16]
+.Lblock_1:
+.Lblock_2:
+    mov x9, #1
+    mov x9, #2
+    mov x9, #3
+.Lblock_3:
+    mov x10, #40
+    add x9, x9, #40
+.Lblock_4:
+    mov x19, x9
+    mov x9, #42
+    cmp x19, #42
+    b.le .Lblock_7
+    b .Lblock_6
+.Lblock_5:
+    mov x1, x20
+    adrp x0, .Lstr_0
+    add x0, x0, :lo12:.Lstr_0
+    bl printf
+    mov x0, #0
+    b .Lepilogue_0
+.Lblock_6:
+    mov x20, #42
+    b .Lblock_5
+.Lblock_7:
+    mov x20, x19
+    b .Lblock_5
+.Lepilogue_0:
+    ldp x19, x20, [x29, #16]
+    ldp x29, x30, [sp], #32
+    ret
+    .section .note.GNU-stack,"",@progbits
@@ -0,0 +1,44 @@
+extern _printf
+section .rodata
+str_0: db "%ld", 10, 0
+global _main
+section .text
+_main:
+    push rbp
+    mov rbp, rsp
+    sub rsp, 32
+    mov [rbp - 8], rbx
+    mov [rbp - 16], r12
+block_1:
+block_2:
+    mov r10, 1
+    mov r10, 2
+    mov r10, 3
+block_3:
+    mov r11, 40
+    add r10, 40
+block_4:
+    mov rbx, r10
+    mov r10, 42
+    cmp rbx, 42
+    jle block_7
+    jmp block_6
+block_5:
+    mov rsi, r12
+    lea rdi, [rel str_0]
+    mov rax, 0
+    call _printf
+    mov rax, 0
+    jmp epilogue_0
+block_6:
+    mov r12, 42
+    jmp block_5
+block_7:
+    mov r12, rbx
+    jmp block_5
+epilogue_0:
+    mov rbx, [rbp - 8]
+    mov r12, [rbp - 16]
+    mov rsp, rbp
+    pop rbp
+    ret
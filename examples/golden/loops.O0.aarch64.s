@@ -0,0 +1,81 @@
+    .section .rodata
+.Lstr_0:
+    .asciz "runtime error: division by zero at examples/golden/loops.bonk:11\012"
+.Lstr_1:
+    .asciz "%ld\012"
+    .text
+    .globl weights
+    .p2align 2
+weights:
+    stp x29, x30, [sp, #-16]!
+    mov x29, sp
+    mov x9, x0
+    mov x10, x1
+.Lblock_1:
+.Lblock_2:
+    mov x11, #0
+    mov x12, #0
+.Lblock_3:
+    cmp x12, x9
+    b.ge .Lblock_5
+.Lblock_4:
+    mov x17, #4
+    mul x13, x10, x17
+    mov x17, #12
+    mul x14, x12, x17
+    cmp x14, x13
+    b.ge .Lblock_7
+    b .Lblock_6
+.Lblock_5:
+    mov x0, x11
+    b .Lepilogue_0
+.Lblock_6:
+    mov x17, #8
+    cbnz x17, .Ldiv_nonzero_8
+    adrp x0, .Lstr_0
+    add x0, x0, :lo12:.Lstr_0
+    bl bonk_trap
+.Ldiv_nonzero_8:
+    sdiv x13, x12, x17
+    add x11, x11, x13
+.Lblock_7:
+    add x12, x12, #1
+    b .Lblock_3
+.Lepilogue_0:
+    ldp x29, x30, [sp], #16
+    ret
+    .globl main
+    .p2align 2
+main:
+    stp x29, x30, [sp, #-16]!
+    mov x29, sp
+.Lblock_10:
+.Lblock_11:
+    mov x0, #100
+    mov x1, #50
+    bl weights
+    mov x9, x0
+    mov x1, x9
+    adrp x0, .Lstr_1
+    add x0, x0, :lo12:.Lstr_1
+    bl printf
+    mov x0, #0
+.Lepilogue_9:
+    ldp x29, x30, [sp], #16
+    ret
+bonk_trap:
+    stp x29, x30, [sp, #-32]!
+    mov x29, sp
+    str x19, [sp, #16]
+    mov x19, x0
+    mov x0, #0
+    bl fflush
+    mov x0, x19
+    bl strlen
+    mov x2, x0
+    mov x1, x19
+    mov x0, #2
+    bl write
+    mov x0, #70
+    bl exit
+    .section .note.GNU-stack,"",@progbits
@@ -0,0 +1,125 @@
+extern _printf
+extern _fflush
+extern _strlen
+extern _write
+extern _exit
+section .rodata
+str_0: db "runtime error: division by zero at examples/golden/loops.bonk:11", 10, 0
+str_1: db "%ld", 10, 0
+global _main
+section .text
+global _weights
+_weights:
+    push rbp
+    mov rbp, rsp
+    sub rsp, 48
+    mov [rbp - 8], rdi
+    mov [rbp - 16], rsi
+body_1:
+    mov rax, 0
+    mov [rbp - 24], rax
+    mov rax, 0
+    mov [rbp - 32], rax
+while_start_2:
+    mov rax, [rbp - 32]
+    push rax
+    mov rax, [rbp - 8]
+    pop rcx
+    cmp rcx, rax
+    jge while_end_3
+    mov rax, [rbp - 16]
+    push rax
+    mov rax, 4
+    pop rcx
+    imul rax, rcx
+    mov [rbp - 40], rax
+    mov rax, [rbp - 32]
+    push rax
+    mov rax, 12
+    pop rcx
+    imul rax, rcx
+    push rax
+    mov rax, [rbp - 40]
+    pop rcx
+    cmp rcx, rax
+    jge endif_4
+    mov rax, [rbp - 24]
+    push rax
+    mov rax, [rbp - 32]
+    push rax
+    mov rax, 8
+    pop rcx
+    cmp rax, 0
+    jne div_nonzero_5
+    lea rdi, [rel str_0]
+    sub rsp, 8
+    call _bonk_trap
+    add rsp, 8
+div_nonzero_5:
+    xchg rax, rcx
+    cmp rcx, -1
+    jne div_6
+    neg rax
+    jmp div_done_7
+div_6:
+    cqo
+    idiv rcx
+div_done_7:
+    pop rcx
+    add rax, rcx
+    mov [rbp - 24], rax
+endif_4:
+    mov rax, [rbp - 32]
+    push rax
+    mov rax, 1
+    pop rcx
+    add rax, rcx
+    mov [rbp - 32], rax
+    jmp while_start_2
+while_end_3:
+    mov rax, [rbp - 24]
+    jmp epilogue_0
+    mov rax, 0
+epilogue_0:
+    mov rsp, rbp
+    pop rbp
+    ret
+_main:
+    push rbp
+    mov rbp, rsp
+    sub rsp, 16
+body_9:
+    mov rax, 100
+    push rax
+    mov rax, 50
+    push rax
+    pop rsi
+    pop rdi
+    mov rax, 0
+    call _weights
+    mov [rbp - 8], rax
+    lea rdi, [rel str_1]
+    mov rsi, [rbp - 8]
+    mov rax, 0
+    call _printf
+    mov rax, 0
+epilogue_8:
+    mov rsp, rbp
+    pop rbp
+    ret
+_bonk_trap:
+    push rbp
+    mov rbp, rsp
+    push rbx
+    sub rsp, 8
+    mov rbx, rdi
+    mov rdi, 0
+    call _fflush
+    mov rdi, rbx
+    call _strlen
+    mov rdx, rax
+    mov rsi, rbx
+    mov rdi, 2
+    call _write
+    mov rdi, 70
+    call _exit
@@ -0,0 +1,62 @@
+    .section .rodata
+.Lstr_0:
+    .asciz "%ld\012"
+    .text
+    .globl weights
+    .p2align 2
+weights:
+    stp x29, x30, [sp, #-16]!
+    mov x29, sp
+    mov x9, x0
+    mov x10, x1
+.Lblock_1:
+.Lblock_2:
+    mov x11, #0
+    mov x12, #0
+    lsl x10, x10, #2
+    mov x17, #12
+    mul x13, x12, x17
+.Lblock_3:
+    cmp x12, x9
+    b.ge .Lblock_5
+.Lblock_4:
+    mov x14, x13
+    cmp x14, x10
+    b.ge .Lblock_7
+    b .Lblock_6
+.Lblock_5:
+    mov x0, x11
+    b .Lepilogue_0
+.Lblock_6:
+    asr x14, x12, #63
+    lsr x14, x14, #61
+    add x14, x12, x14
+    asr x14, x14, #3
+    add x11, x11, x14
+.Lblock_7:
+    add x12, x12, #1
+    add x13, x13, #12
+    b .Lblock_3
+.Lepilogue_0:
+    ldp x29, x30, [sp], #16
+    ret
+    .globl main
+    .p2align 2
+main:
+    stp x29, x30, [sp, #-16]!
+    mov x29, sp
+.Lblock_9:
+.Lblock_10:
+    mov x0, #100
+    mov x1, #50
+    bl weights
+    mov x9, x0
+    mov x1, x9
+    adrp x0, .Lstr_0
+    add x0, x0, :lo12:.Lstr_0
+    bl printf
+    mov x0, #0
+.Lepilogue_8:
+    ldp x29, x30, [sp], #16
+    ret
+    .section .note.GNU-stack,"",@progbits
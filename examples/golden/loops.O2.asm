@@ -0,0 +1,75 @@
+extern _printf
+section .rodata
+str_0: db "%ld", 10, 0
+global _main
+section .text
+global _weights
+_weights:
+    push rbp
+    mov rbp, rsp
+    sub rsp, 48
+    mov [rbp - 8], rbx
+    mov [rbp - 16], r12
+    mov [rbp - 24], r13
+    mov [rbp - 32], r14
+    mov r10, rdi
+    mov r11, rsi
+block_1:
+block_2:
+    mov rbx, 0
+    mov r12, 0
+    shl r11, 2
+    mov r13, r12
+    imul r13, 12
+block_3:
+    cmp r12, r10
+    jge block_5
+block_4:
+    mov r14, r13
+    cmp r14, r11
+    jge block_7
+    jmp block_6
+block_5:
+    mov rax, rbx
+    jmp epilogue_0
+block_6:
+    mov r14, r12
+    sar r14, 63
+    shr r14, 61
+    mov rax, r12
+    add rax, r14
+    mov r14, rax
+    sar r14, 3
+    add rbx, r14
+block_7:
+    add r12, 1
+    add r13, 12
+    jmp block_3
+epilogue_0:
+    mov rbx, [rbp - 8]
+    mov r12, [rbp - 16]
+    mov r13, [rbp - 24]
+    mov r14, [rbp - 32]
+    mov rsp, rbp
+    pop rbp
+    ret
+_main:
+    push rbp
+    mov rbp, rsp
+    sub rsp, 16
+block_9:
+block_10:
+    mov rdi, 100
+    mov rsi, 50
+    mov rax, 0
+    call _weights
+    mov r10, rax
+    mov rsi, r10
+    lea rdi, [rel str_0]
+    mov rax, 0
+    call _printf
+    mov rax, 0
+epilogue_8:
+    mov rsp, rbp
+    pop rbp
+    ret
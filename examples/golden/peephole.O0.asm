@@ -0,0 +1,79 @@
+extern _printf
+section .rodata
+str_0: db "ok", 10, 0
+global _main
+section .text
+global _sum_below
+_sum_below:
+    push rbp
+    mov rbp, rsp
+    sub rsp, 32
+    mov [rbp - 8], rdi
+body_1:
+    mov rax, 0
+    mov [rbp - 16], rax
+    mov rax, 0
+    mov [rbp - 24], rax
+while_start_2:
+    mov rax, [rbp - 24]
+    push rax
+    mov rax, [rbp - 8]
+    pop rcx
+    cmp rcx, rax
+    jge while_end_3
+    mov rax, [rbp - 16]
+    push rax
+    mov rax, [rbp - 24]
+    push rax
+    mov rax, 2
+    pop rcx
+    imul rax, rcx
+    pop rcx
+    add rax, rcx
+    mov [rbp - 16], rax
+    mov rax, [rbp - 24]
+    push rax
+    mov rax, 1
+    pop rcx
+    add rax, rcx
+    mov [rbp - 24], rax
+    jmp while_start_2
+while_end_3:
+    mov rax, [rbp - 16]
+    jmp epilogue_0
+    mov rax, 0
+epilogue_0:
+    mov rsp, rbp
+    pop rbp
+    ret
+_main:
+    push rbp
+    mov rbp, rsp
+    sub rsp, 16
+body_5:
+    mov rax, 10
+    push rax
+    pop rdi
+    mov rax, 0
+    call _sum_below
+    push rax
+    mov rax, 3
+    pop rcx
+    sub rcx, rax
+    mov rax, rcx
+    mov [rbp - 8], rax
+    mov rax, [rbp - 8]
+    push rax
+    mov rax, 87
+    pop rcx
+    cmp rcx, rax
+    jne endif_6
+    lea rdi, [rel str_0]
+    mov rax, 0
+    call _printf
+endif_6:
+    mov rax, 0
+epilogue_4:
+    mov rsp, rbp
+    pop rbp
+    ret
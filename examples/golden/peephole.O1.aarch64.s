@@ -0,0 +1,51 @@
+    .section .rodata
+.Lstr_0:
+    .asciz "ok\012"
+    .text
+    .globl sum_below
+    .p2align 2
+sum_below:
+    stp x29, x30, [sp, #-16]!
+    mov x29, sp
+    mov x9, x0
+.Lblock_1:
+.Lblock_2:
+    mov x10, #0
+    mov x11, #0
+.Lblock_3:
+    cmp x11, x9
+    b.ge .Lblock_5
+.Lblock_4:
+    mov x17, #2
+    mul x12, x11, x17
+    add x10, x10, x12
+    add x11, x11, #1
+    b .Lblock_3
+.Lblock_5:
+    mov x0, x10
+.Lepilogue_0:
+    ldp x29, x30, [sp], #16
+    ret
+    .globl main
+    .p2align 2
+main:
+    stp x29, x30, [sp, #-16]!
+    mov x29, sp
+.Lblock_7:
+.Lblock_8:
+    mov x0, #10
+    bl sum_below
+    mov x9, x0
+    sub x9, x9, #3
+    cmp x9, #87
+    b.ne .Lblock_10
+.Lblock_9:
+    adrp x0, .Lstr_0
+    add x0, x0, :lo12:.Lstr_0
+    bl printf
+.Lblock_10:
+    mov x0, #0
+.Lepilogue_6:
+    ldp x29, x30, [sp], #16
+    ret
+    .section .note.GNU-stack,"",@progbits
@@ -0,0 +1,60 @@
+extern _printf
+section .rodata
+str_0: db "ok", 10, 0
+global _main
+section .text
+global _sum_below
+_sum_below:
+    push rbp
+    mov rbp, rsp
+    sub rsp, 32
+    mov [rbp - 8], rdi
+body_1:
+    mov rax, 0
+    mov [rbp - 16], rax
+    mov rax, 0
+    mov [rbp - 24], rax
+while_start_2:
+    mov rax, [rbp - 24]
+    cmp rax, [rbp - 8]
+    jge while_end_3
+    mov rax, [rbp - 16]
+    push rax
+    mov rax, [rbp - 24]
+    imul rax, 2
+    pop rcx
+    add rax, rcx
+    mov [rbp - 16], rax
+    mov rax, [rbp - 24]
+    add rax, 1
+    mov [rbp - 24], rax
+    jmp while_start_2
+while_end_3:
+    mov rax, [rbp - 16]
+epilogue_0:
+    mov rsp, rbp
+    pop rbp
+    ret
+_main:
+    push rbp
+    mov rbp, rsp
+    sub rsp, 16
+body_5:
+    mov rax, 10
+    mov rdi, rax
+    mov rax, 0
+    call _sum_below
+    sub rax, 3
+    mov [rbp - 8], rax
+    mov rax, [rbp - 8]
+    cmp rax, 87
+    jne endif_6
+    lea rdi, [rel str_0]
+    mov rax, 0
+    call _printf
+endif_6:
+    mov rax, 0
+epilogue_4:
+    mov rsp, rbp
+    pop rbp
+    ret
@@ -0,0 +1,61 @@
+extern _printf
+section .rodata
+str_0: db "ok", 10, 0
+global _main
+section .text
+global _sum_below
+_sum_below:
+    push rbp
+    mov rbp, rsp
+    sub rsp, 32
+    mov [rbp - 8], rbx
+    mov [rbp - 16], r12
+    mov [rbp - 24], r13
+    mov r10, rdi
+block_1:
+block_2:
+    mov r11, 0
+    mov rbx, 0
+    mov r12, rbx
+    shl r12, 1
+block_3:
+    cmp rbx, r10
+    jge block_5
+block_4:
+    mov r13, r12
+    add r11, r13
+    add rbx, 1
+    add r12, 2
+    jmp block_3
+block_5:
+    mov rax, r11
+epilogue_0:
+    mov rbx, [rbp - 8]
+    mov r12, [rbp - 16]
+    mov r13, [rbp - 24]
+    mov rsp, rbp
+    pop rbp
+    ret
+_main:
+    push rbp
+    mov rbp, rsp
+    sub rsp, 16
+block_7:
+block_8:
+    mov rdi, 10
+    mov rax, 0
+    call _sum_below
+    mov r10, rax
+    sub r10, 3
+    cmp r10, 87
+    jne block_10
+block_9:
+    lea rdi, [rel str_0]
+    mov rax, 0
+    call _printf
+block_10:
+    mov rax, 0
+epilogue_6:
+    mov rsp, rbp
+    pop rbp
+    ret
@@ -0,0 +1,65 @@
+    .section .rodata
+.Lstr_0:
+    .asciz "%ld\012"
+    .text
+    .globl square
+    .p2align 2
+square:
+    stp x29, x30, [sp, #-16]!
+    mov x29, sp
+    mov x9, x0
+.Lblock_1:
+.Lblock_2:
+    mul x9, x9, x9
+    mov x0, x9
+.Lepilogue_0:
+    ldp x29, x30, [sp], #16
+    ret
+    .globl sum_of_squares
+    .p2align 2
+sum_of_squares:
+    stp x29, x30, [sp, #-48]!
+    mov x29, sp
+    stp x19, x20, [x29, #16]
+    str x21, [x29, #32]
+    mov x19, x0
+.Lblock_4:
+.Lblock_5:
+    mov x20, #0
+    mov x21, #1
+.Lblock_6:
+    cmp x21, x19
+    b.gt .Lblock_8
+.Lblock_7:
+    mov x0, x21
+    bl square
+    mov x9, x0
+    add x20, x20, x9
+    add x21, x21, #1
+    b .Lblock_6
+.Lblock_8:
+    mov x0, x20
+.Lepilogue_3:
+    ldp x19, x20, [x29, #16]
+    ldr x21, [x29, #32]
+    ldp x29, x30, [sp], #48
+    ret
+    .globl main
+    .p2align 2
+main:
+    stp x29, x30, [sp, #-16]!
+    mov x29, sp
+.Lblock_10:
+.Lblock_11:
+    mov x0, #10
+    bl sum_of_squares
+    mov x9, x0
+    mov x1, x9
+    adrp x0, .Lstr_0
+    add x0, x0, :lo12:.Lstr_0
+    bl printf
+    mov x0, #0
+.Lepilogue_9:
+    ldp x29, x30, [sp], #16
+    ret
+    .section .note.GNU-stack,"",@progbits
@@ -0,0 +1,88 @@
+extern _printf
+section .rodata
+str_0: db "%ld", 10, 0
+global _main
+section .text
+global _square
+_square:
+    push rbp
+    mov rbp, rsp
+    sub rsp, 16
+    mov [rbp - 8], rdi
+body_1:
+    mov rax, [rbp - 8]
+    push rax
+    mov rax, [rbp - 8]
+    pop rcx
+    imul rax, rcx
+    jmp epilogue_0
+    mov rax, 0
+epilogue_0:
+    mov rsp, rbp
+    pop rbp
+    ret
+global _sum_of_squares
+_sum_of_squares:
+    push rbp
+    mov rbp, rsp
+    sub rsp, 32
+    mov [rbp - 8], rdi
+body_3:
+    mov rax, 0
+    mov [rbp - 16], rax
+    mov rax, 1
+    mov [rbp - 24], rax
+while_start_4:
+    mov rax, [rbp - 24]
+    push rax
+    mov rax, [rbp - 8]
+    pop rcx
+    cmp rcx, rax
+    jg while_end_5
+    mov rax, [rbp - 16]
+    push rax
+    mov rax, [rbp - 24]
+    push rax
+    pop rdi
+    mov rax, 0
+    sub rsp, 8
+    call _square
+    add rsp, 8
+    pop rcx
+    add rax, rcx
+    mov [rbp - 16], rax
+    mov rax, [rbp - 24]
+    push rax
+    mov rax, 1
+    pop rcx
+    add rax, rcx
+    mov [rbp - 24], rax
+    jmp while_start_4
+while_end_5:
+    mov rax, [rbp - 16]
+    jmp epilogue_2
+    mov rax, 0
+epilogue_2:
+    mov rsp, rbp
+    pop rbp
+    ret
+_main:
+    push rbp
+    mov rbp, rsp
+    sub rsp, 16
+body_7:
+    mov rax, 10
+    push rax
+    pop rdi
+    mov rax, 0
+    call _sum_of_squares
+    mov [rbp - 8], rax
+    lea rdi, [rel str_0]
+    mov rsi, [rbp - 8]
+    mov rax, 0
+    call _printf
+    mov rax, 0
+epilogue_6:
+    mov rsp, rbp
+    pop rbp
+    ret
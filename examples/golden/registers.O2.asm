@@ -0,0 +1,71 @@
+extern _printf
+section .rodata
+str_0: db "%ld", 10, 0
+global _main
+section .text
+global _square
+_square:
+    push rbp
+    mov rbp, rsp
+    sub rsp, 16
+    mov r10, rdi
+block_1:
+block_2:
+    imul r10, r10
+    mov rax, r10
+epilogue_0:
+    mov rsp, rbp
+    pop rbp
+    ret
+global _sum_of_squares
+_sum_of_squares:
+    push rbp
+    mov rbp, rsp
+    sub rsp, 32
+    mov [rbp - 8], rbx
+    mov [rbp - 16], r12
+    mov [rbp - 24], r13
+    mov rbx, rdi
+block_4:
+block_5:
+    mov r12, 0
+    mov r13, 1
+block_6:
+    cmp r13, rbx
+    jg block_8
+block_7:
+    mov rdi, r13
+    mov rax, 0
+    call _square
+    mov r10, rax
+    add r12, r10
+    add r13, 1
+    jmp block_6
+block_8:
+    mov rax, r12
+epilogue_3:
+    mov rbx, [rbp - 8]
+    mov r12, [rbp - 16]
+    mov r13, [rbp - 24]
+    mov rsp, rbp
+    pop rbp
+    ret
+_main:
+    push rbp
+    mov rbp, rsp
+    sub rsp, 16
+block_10:
+block_11:
+    mov rdi, 10
+    mov rax, 0
+    call _sum_of_squares
+    mov r10, rax
+    mov rsi, r10
+    lea rdi, [rel str_0]
+    mov rax, 0
+    call _printf
+    mov rax, 0
+epilogue_9:
+    mov rsp, rbp
+    pop rbp
+    ret
@@ -0,0 +1,1287 @@
+use std::collections::HashMap;
+
+use crate::ast::{BinaryOperator, Expression, Location, Statement, Type};
+use crate::builtins;
+use crate::compiler::{printf_conversion, Options, Overflow};
+use crate::ir::{self, Inst, Operand, PrintPart, Shift, Terminator, VReg};
+use crate::regalloc::{self, Allocation, Assignment, Registers};
+use crate::runtime;
+use crate::stack::StackMachine;
+use crate::types::Signatures;
+
+// AArch64 code generator, writing GNU as syntax for Linux. Functions the IR
+// can express are compiled from it at every optimization level, with their
+// values in registers; the others go through a stack machine like the x86-64
+// one in src/compiler.rs. Calls follow AAPCS64: integer arguments in x0..x7,
+// floats in d0..d7 and the result in x0 or d0, with x19..x28 preserved by the
+// callee. The frame of a function compiled from IR holds its frame record
+// (x29, x30), stored with stp at the bottom, then the callee-saved registers
+// it uses, then its spill slots, all addressed from x29.
+//
+// The stack machine keeps the value being computed in x0 and the left operand
+// of a binary operation in x1, and pushes temporaries 16 bytes at a time so
+// sp stays aligned. Its variables live in 8-byte slots below the frame record,
+// at x29 minus their offset. Floats travel through x0 as their bit pattern, as
+// they do through rax on x86-64. A function value is called with its closure
+// record in x9.
+
+// x8, x16 and x17 stay free as scratch registers, and the argument registers
+// are left alone so arguments can be moved into place in any order
+const ARG_REGS: [&str; 8] = ["x0", "x1", "x2", "x3", "x4", "x5", "x6", "x7"];
+
+const REGISTERS: Registers = Registers {
+    caller_saved: &["x9", "x10", "x11", "x12", "x13", "x14", "x15"],
+    callee_saved: &["x19", "x20", "x21", "x22", "x23", "x24", "x25", "x26", "x27", "x28"],
+};
+
+// A function as the code generator takes it
+pub enum Function<'a> {
+    // Lowered to IR, to be register allocated
+    Lowered(ir::Function),
+    // As written, for the stack machine
+    Stack(&'a Statement),
+}
+
+struct Frame {
+    allocation: Allocation,
+    // Offset from x29 of each spill slot
+    spill_offsets: Vec<usize>,
+}
+
+impl Frame {
+    fn assignment(&self, vreg: VReg) -> Assignment {
+        self.allocation.assignments[&vreg]
+    }
+}
+
+struct Generator<'a> {
+    overflow: Overflow,
+    // Parameter and return types of every function and extern
+    signatures: &'a Signatures,
+    externs: &'a [String],
+    // Externs returning a C int, sign-extended from w0 after the call
+    int_externs: &'a [String],
+    text: Vec<String>,
+    rodata: Vec<String>,
+    // Closure records of functions used as values. They hold the function's
+    // address, which the dynamic linker fills in, so they are not read-only.
+    records: Vec<String>,
+    strings: HashMap<String, String>,
+    label_count: usize,
+    // Runtime routines referenced so far, emitted after the program
+    runtime_used: Vec<&'static str>,
+    // Types of the variables each anonymous function captures, recorded where
+    // its closure is created
+    capture_types: HashMap<String, Vec<Type>>,
+    // Slot offsets and types of the variables of the stack machine function
+    // being compiled; the first assignment fixes the type
+    offset_map: HashMap<String, usize>,
+    var_types: HashMap<String, Type>,
+    var_offset: usize,
+    epilogue_label: String,
+    return_type: Type,
+    // Function being compiled, its parameter slots and the label after its
+    // prologue, where self tail calls jump back to
+    current_function: String,
+    param_slots: Vec<usize>,
+    body_label: String,
+    // Where a `send` in the inlined body being compiled goes: the label after
+    // the body, the slot of its result and the type it returns
+    inline_exit: Option<(String, usize, Type)>,
+}
+
+pub fn compile(
+    functions: &[Function],
+    signatures: &Signatures,
+    externs: &[String],
+    int_externs: &[String],
+    options: &Options,
+) -> Vec<String> {
+    let mut generator = Generator {
+        overflow: options.overflow,
+        signatures,
+        externs,
+        int_externs,
+        text: Vec::new(),
+        rodata: Vec::new(),
+        records: Vec::new(),
+        strings: HashMap::new(),
+        label_count: 0,
+        runtime_used: Vec::new(),
+        capture_types: HashMap::new(),
+        offset_map: HashMap::new(),
+        var_types: HashMap::new(),
+        var_offset: 8,
+        epilogue_label: String::new(),
+        return_type: Type::Int,
+        current_function: String::new(),
+        param_slots: Vec::new(),
+        body_label: String::new(),
+        inline_exit: None,
+    };
+    for function in functions {
+        match function {
+            Function::Lowered(function) => generator.compile_function(function),
+            Function::Stack(function) => generator.compile_stack_function(function),
+        }
+    }
+
+    let mut rodata = generator.rodata;
+    let mut bss = Vec::new();
+    for name in &generator.runtime_used {
+        let routine = runtime::aarch64_routine(name);
+        // Routines sharing state declare the same lines, so keep one copy
+        for line in routine.data {
+            if !rodata.iter().any(|existing| existing == line) {
+                rodata.push(line.to_string());
+            }
+        }
+        for line in routine.bss {
+            if !bss.contains(line) {
+                bss.push(*line);
+            }
+        }
+    }
+
+    let mut lines = Vec::new();
+    if !rodata.is_empty() {
+        lines.push("    .section .rodata".to_string());
+        lines.extend(rodata);
+    }
+    if !generator.records.is_empty() {
+        lines.push("    .section .data.rel.ro,\"aw\"".into());
+        lines.push("    .p2align 3".into());
+        lines.extend(generator.records);
+    }
+    if !bss.is_empty() {
+        lines.push("    .bss".into());
+        lines.push("    .p2align 3".into());
+        lines.extend(bss.iter().map(|line| line.to_string()));
+    }
+    lines.push("    .text".into());
+    lines.extend(generator.text);
+    for name in &generator.runtime_used {
+        lines.extend(runtime::aarch64_routine(name).code.lines().skip(1).map(String::from));
+    }
+    // The stack need not be executable
+    lines.push("    .section .note.GNU-stack,\"\",@progbits".into());
+    lines
+}
+
+impl Generator<'_> {
+    fn emit(&mut self, line: String) {
+        self.text.push(line);
+    }
+
+    fn compile_function(&mut self, function: &ir::Function) {
+        let allocation = regalloc::allocate(function, &REGISTERS);
+        let saves = allocation.callee_saved.clone();
+        let save_end = 16 + saves.len() * 8;
+        let spill_offsets = (0..allocation.spill_slots).map(|slot| save_end + slot * 8).collect();
+        let frame_size = (save_end + allocation.spill_slots * 8).div_ceil(16) * 16;
+        let frame = Frame { allocation, spill_offsets };
+        let epilogue = self.new_label("epilogue");
+        let labels: Vec<String> = function.blocks.iter().map(|_| self.new_label("block")).collect();
+
+        self.emit(format!("    .globl {}", function.name));
+        self.emit("    .p2align 2".into());
+        self.emit(format!("{}:", function.name));
+        if frame_size <= 504 {
+            self.emit(format!("    stp x29, x30, [sp, #-{}]!", frame_size));
+        } else {
+            self.adjust_sp("sub", frame_size);
+            self.emit("    stp x29, x30, [sp]".into());
+        }
+        self.emit("    mov x29, sp".into());
+        for (index, pair) in saves.chunks(2).enumerate() {
+            match pair {
+                [a, b] => self.emit(format!("    stp {}, {}, [x29, #{}]", a, b, 16 + index * 16)),
+                [a] => self.emit(format!("    str {}, [x29, #{}]", a, 16 + index * 16)),
+                _ => unreachable!(),
+            }
+        }
+        for (param, reg) in function.params.iter().zip(ARG_REGS) {
+            self.write(&frame, *param, reg);
+        }
+
+        // Numbered like the allocator's points
+        let mut point = 0;
+        for (index, block) in function.blocks.iter().enumerate() {
+            self.emit(format!("{}:", labels[index]));
+            for inst in &block.insts {
+                let saved = frame.allocation.saved_across.get(&point).cloned().unwrap_or_default();
+                self.compile_inst(inst, &frame, &saved);
+                point += 1;
+            }
+            // Falling through to the next block saves a branch
+            let next = labels.get(index + 1);
+            match &block.terminator {
+                Terminator::Jump(target) => {
+                    if Some(&labels[*target]) != next {
+                        self.emit(format!("    b {}", labels[*target]));
+                    }
+                }
+                Terminator::Branch { op, lhs, rhs, then_block, else_block } => {
+                    self.emit_compare(&frame, *lhs, *rhs);
+                    if Some(&labels[*else_block]) == next {
+                        self.emit(format!("    b.{} {}", condition_code(op), labels[*then_block]));
+                    } else {
+                        self.emit(format!("    b.{} {}", inverse_condition_code(op), labels[*else_block]));
+                        if Some(&labels[*then_block]) != next {
+                            self.emit(format!("    b {}", labels[*then_block]));
+                        }
+                    }
+                }
+                Terminator::Return(value) => {
+                    self.move_into("x0", &frame, *value);
+                    if next.is_some() {
+                        self.emit(format!("    b {}", epilogue));
+                    }
+                }
+                Terminator::TailCall { name, args } => {
+                    for (reg, arg) in ARG_REGS.iter().zip(args) {
+                        self.move_into(reg, &frame, *arg);
+                    }
+                    self.pop_frame(&saves, frame_size);
+                    self.emit(format!("    b {}", name));
+                }
+            }
+            point += 1;
+        }
+
+        self.emit(format!("{}:", epilogue));
+        self.pop_frame(&saves, frame_size);
+        self.emit("    ret".into());
+    }
+
+    // Restores the callee-saved registers and the frame record and releases
+    // the frame
+    fn pop_frame(&mut self, saves: &[&str], frame_size: usize) {
+        for (index, pair) in saves.chunks(2).enumerate() {
+            match pair {
+                [a, b] => self.emit(format!("    ldp {}, {}, [x29, #{}]", a, b, 16 + index * 16)),
+                [a] => self.emit(format!("    ldr {}, [x29, #{}]", a, 16 + index * 16)),
+                _ => unreachable!(),
+            }
+        }
+        if frame_size <= 504 {
+            self.emit(format!("    ldp x29, x30, [sp], #{}", frame_size));
+        } else {
+            self.emit("    ldp x29, x30, [sp]".into());
+            self.adjust_sp("add", frame_size);
+        }
+    }
+
+    fn adjust_sp(&mut self, instruction: &str, amount: usize) {
+        if amount < 4096 {
+            self.emit(format!("    {} sp, sp, #{}", instruction, amount));
+        } else {
+            self.load_immediate("x16", amount as i64);
+            self.emit(format!("    {} sp, sp, x16", instruction));
+        }
+    }
+
+    // `saved` are the caller-saved registers to preserve around a call
+    fn compile_inst(&mut self, inst: &Inst, frame: &Frame, saved: &[&str]) {
+        match inst {
+            Inst::Copy { dst, src: Operand::Imm(value) } => match frame.assignment(*dst) {
+                Assignment::Register(reg) => self.load_immediate(reg, *value),
+                Assignment::Spilled(_) => {
+                    self.load_immediate("x16", *value);
+                    self.write(frame, *dst, "x16");
+                }
+            },
+            Inst::Copy { dst, src } => {
+                let src = self.read(frame, *src, "x16");
+                self.write(frame, *dst, src);
+            }
+            Inst::Binary { op: BinaryOperator::Div, dst, lhs, rhs, location } => {
+                let nonzero = self.new_label("div_nonzero");
+                let rhs = self.read(frame, *rhs, "x17");
+                self.emit(format!("    cbnz {}, {}", rhs, nonzero));
+                self.emit_trap("division by zero", location);
+                self.emit(format!("{}:", nonzero));
+                let lhs = self.read(frame, *lhs, "x16");
+                // sdiv gives i64::MIN for i64::MIN / -1, which is the wrapped result
+                if self.overflow == Overflow::Trap {
+                    let divide = self.new_label("div");
+                    self.emit(format!("    cmn {}, #1", rhs));
+                    self.emit(format!("    b.ne {}", divide));
+                    self.emit(format!("    negs x8, {}", lhs));
+                    self.emit(format!("    b.vc {}", divide));
+                    self.emit_trap("integer overflow in '/'", location);
+                    self.emit(format!("{}:", divide));
+                }
+                let target = target(frame, *dst, "x16");
+                self.emit(format!("    sdiv {}, {}, {}", target, lhs, rhs));
+                self.write(frame, *dst, target);
+            }
+            Inst::Binary { op, dst, lhs, rhs, .. } if op.is_comparison() => {
+                self.emit_compare(frame, *lhs, *rhs);
+                let target = target(frame, *dst, "x16");
+                self.emit(format!("    cset {}, {}", target, condition_code(op)));
+                self.write(frame, *dst, target);
+            }
+            Inst::Binary { op, dst, lhs, rhs, location } => {
+                let trap = self.overflow == Overflow::Trap;
+                let lhs = self.read(frame, *lhs, "x16");
+                let target = target(frame, *dst, "x16");
+                let symbol = match op {
+                    BinaryOperator::Add => "+",
+                    BinaryOperator::Sub => "-",
+                    BinaryOperator::Mul => "*",
+                    _ => unreachable!("{:?} is handled above", op),
+                };
+                // Setting the flags gives overflow in V for addition and subtraction
+                let flags = if trap { "s" } else { "" };
+                match (op, rhs) {
+                    (BinaryOperator::Add | BinaryOperator::Sub, Operand::Imm(value)) if immediate(value.unsigned_abs()) => {
+                        let add = (*op == BinaryOperator::Add) == (*value >= 0);
+                        let instruction = if add { "add" } else { "sub" };
+                        self.emit(format!("    {}{} {}, {}, #{}", instruction, flags, target, lhs, value.unsigned_abs()));
+                    }
+                    (BinaryOperator::Add | BinaryOperator::Sub, _) => {
+                        let rhs = self.read(frame, *rhs, "x17");
+                        let instruction = if *op == BinaryOperator::Add { "add" } else { "sub" };
+                        self.emit(format!("    {}{} {}, {}, {}", instruction, flags, target, lhs, rhs));
+                    }
+                    _ => {
+                        let rhs = self.read(frame, *rhs, "x17");
+                        if trap {
+                            // The product fits when its high half is all copies of the low half's sign
+                            self.emit(format!("    smulh x8, {}, {}", lhs, rhs));
+                        }
+                        self.emit(format!("    mul {}, {}, {}", target, lhs, rhs));
+                        if trap {
+                            self.emit(format!("    cmp x8, {}, asr #63", target));
+                        }
+                    }
+                }
+                if trap {
+                    let ok = self.new_label("no_overflow");
+                    let condition = if *op == BinaryOperator::Mul { "eq" } else { "vc" };
+                    self.emit(format!("    b.{} {}", condition, ok));
+                    self.emit_trap(&format!("integer overflow in '{}'", symbol), location);
+                    self.emit(format!("{}:", ok));
+                }
+                self.write(frame, *dst, target);
+            }
+            Inst::Shift { op, dst, src, amount } => {
+                let src = self.read(frame, *src, "x16");
+                let target = target(frame, *dst, "x16");
+                let instruction = match op {
+                    Shift::Left => "lsl",
+                    Shift::Right => "asr",
+                    Shift::RightLogical => "lsr",
+                };
+                self.emit(format!("    {} {}, {}, #{}", instruction, target, src, amount));
+                self.write(frame, *dst, target);
+            }
+            Inst::Call { dst, name, args } => {
+                self.save(saved);
+                for (reg, arg) in ARG_REGS.iter().zip(args) {
+                    self.move_into(reg, frame, *arg);
+                }
+                self.emit(format!("    bl {}", name));
+                if self.int_externs.contains(name) {
+                    self.emit("    sxtw x0, w0".into());
+                }
+                self.restore(saved);
+                if let Some(dst) = dst {
+                    self.write(frame, *dst, "x0");
+                }
+            }
+            Inst::Print { parts, newline } => {
+                let mut format = String::new();
+                let mut values = Vec::new();
+                for part in parts {
+                    match part {
+                        PrintPart::Text(text) => format.push_str(&text.replace('%', "%%")),
+                        PrintPart::Value(value, spec) => {
+                            format.push_str(&printf_conversion(spec, Type::Int));
+                            values.push(*value);
+                        }
+                    }
+                }
+                if *newline {
+                    format.push('\n');
+                }
+                let label = self.string_literal(&format);
+                self.save(saved);
+                for (reg, value) in ARG_REGS[1..].iter().zip(values) {
+                    self.move_into(reg, frame, value);
+                }
+                self.emit(format!("    adrp x0, {}", label));
+                self.emit(format!("    add x0, x0, :lo12:{}", label));
+                self.emit("    bl printf".into());
+                self.restore(saved);
+            }
+        }
+    }
+
+    // Pushes registers two at a time, keeping sp 16-byte aligned
+    fn save(&mut self, regs: &[&str]) {
+        for pair in regs.chunks(2) {
+            match pair {
+                [a, b] => self.emit(format!("    stp {}, {}, [sp, #-16]!", a, b)),
+                [a] => self.emit(format!("    str {}, [sp, #-16]!", a)),
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    fn restore(&mut self, regs: &[&str]) {
+        for pair in regs.chunks(2).rev() {
+            match pair {
+                [a, b] => self.emit(format!("    ldp {}, {}, [sp], #16", a, b)),
+                [a] => self.emit(format!("    ldr {}, [sp], #16", a)),
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    // The register holding an operand: its own, or `scratch` loaded from its
+    // spill slot or with the immediate
+    fn read(&mut self, frame: &Frame, operand: Operand, scratch: &'static str) -> &'static str {
+        match operand {
+            Operand::Imm(value) => {
+                self.load_immediate(scratch, value);
+                scratch
+            }
+            Operand::Reg(vreg) => match frame.assignment(vreg) {
+                Assignment::Register(reg) => reg,
+                Assignment::Spilled(slot) => {
+                    self.emit(format!("    ldr {}, [x29, #{}]", scratch, frame.spill_offsets[slot]));
+                    scratch
+                }
+            },
+        }
+    }
+
+    fn move_into(&mut self, reg: &str, frame: &Frame, operand: Operand) {
+        match operand {
+            Operand::Imm(value) => self.load_immediate(reg, value),
+            Operand::Reg(vreg) => match frame.assignment(vreg) {
+                Assignment::Register(src) => self.emit(format!("    mov {}, {}", reg, src)),
+                Assignment::Spilled(slot) => self.emit(format!("    ldr {}, [x29, #{}]", reg, frame.spill_offsets[slot])),
+            },
+        }
+    }
+
+    // Puts the value in `reg` into the home of a virtual register
+    fn write(&mut self, frame: &Frame, dst: VReg, reg: &str) {
+        match frame.assignment(dst) {
+            Assignment::Register(home) if home == reg => {}
+            Assignment::Register(home) => self.emit(format!("    mov {}, {}", home, reg)),
+            Assignment::Spilled(slot) => self.emit(format!("    str {}, [x29, #{}]", reg, frame.spill_offsets[slot])),
+        }
+    }
+
+    // A mov for values a single movz or movn gives, otherwise a movz or movn
+    // for one 16-bit chunk and a movk for each other chunk that differs
+    fn load_immediate(&mut self, reg: &str, value: i64) {
+        if (-0x10000..=0xFFFF).contains(&value) {
+            self.emit(format!("    mov {}, #{}", reg, value));
+            return;
+        }
+        let chunks: Vec<u64> = (0..4).map(|i| (value as u64 >> (i * 16)) & 0xFFFF).collect();
+        let ones = chunks.iter().filter(|&&chunk| chunk == 0xFFFF).count();
+        let zeros = chunks.iter().filter(|&&chunk| chunk == 0).count();
+        let filler = if ones > zeros { 0xFFFF } else { 0 };
+        let mut first = true;
+        for (i, &chunk) in chunks.iter().enumerate() {
+            if chunk == filler {
+                continue;
+            }
+            if !first {
+                self.emit(format!("    movk {}, #{}, lsl #{}", reg, chunk, i * 16));
+            } else if filler == 0 {
+                self.emit(format!("    movz {}, #{}, lsl #{}", reg, chunk, i * 16));
+            } else {
+                self.emit(format!("    movn {}, #{}, lsl #{}", reg, !chunk & 0xFFFF, i * 16));
+            }
+            first = false;
+        }
+    }
+
+    fn emit_compare(&mut self, frame: &Frame, lhs: Operand, rhs: Operand) {
+        let lhs = self.read(frame, lhs, "x16");
+        match rhs {
+            Operand::Imm(value) if value >= 0 && immediate(value.unsigned_abs()) => {
+                self.emit(format!("    cmp {}, #{}", lhs, value));
+            }
+            Operand::Imm(value) if immediate(value.unsigned_abs()) => {
+                self.emit(format!("    cmn {}, #{}", lhs, value.unsigned_abs()));
+            }
+            _ => {
+                let rhs = self.read(frame, rhs, "x17");
+                self.emit(format!("    cmp {}, {}", lhs, rhs));
+            }
+        }
+    }
+
+    fn emit_trap(&mut self, message: &str, location: &Location) {
+        let label = self.string_literal(&format!("runtime error: {} at {}\n", message, location));
+        self.emit(format!("    adrp x0, {}", label));
+        self.emit(format!("    add x0, x0, :lo12:{}", label));
+        self.call_runtime("bonk_trap");
+    }
+
+    // Marks a routine, and those it calls, to be emitted after the program
+    fn use_runtime(&mut self, name: &'static str) {
+        if !self.runtime_used.contains(&name) {
+            self.runtime_used.push(name);
+            for &other in runtime::aarch64_routine(name).uses {
+                self.use_runtime(other);
+            }
+        }
+    }
+}
+
+// The stack machine, for the functions the IR cannot express
+impl Generator<'_> {
+    fn compile_stack_function(&mut self, function: &Statement) {
+        let Statement::Function { name, params, returns, body, captures, .. } = function else {
+            unreachable!("only functions are compiled");
+        };
+        self.offset_map.clear();
+        self.var_types.clear();
+        self.var_offset = 8;
+        self.return_type = *returns;
+        self.epilogue_label = self.new_label("epilogue");
+
+        self.emit(format!("    .globl {}", name));
+        self.emit("    .p2align 2".into());
+        self.emit(format!("{}:", name));
+        self.emit("    stp x29, x30, [sp, #-16]!".into());
+        self.emit("    mov x29, sp".into());
+        // Placeholder for the frame reservation, replaced once the body is compiled
+        let reserve_index = self.text.len();
+        self.emit("    sub sp, sp, #0".into());
+
+        // Copy captured variables out of the closure record before anything clobbers x9
+        let capture_types = self.capture_types.get(name).cloned().unwrap_or_default();
+        for (i, capture) in captures.iter().enumerate() {
+            let offset = self.temp_slot();
+            self.emit(format!("    ldr x17, [x9, #{}]", (i + 1) * 8));
+            self.store("x17", offset);
+            self.offset_map.insert(capture.clone(), offset);
+            self.var_types.insert(capture.clone(), capture_types.get(i).copied().unwrap_or(Type::Int));
+        }
+
+        if name == "main" && params.len() == 1 {
+            // main(args) receives argc/argv as a list of strings
+            self.call_runtime("bonk_args");
+            let offset = self.temp_slot();
+            self.offset_map.insert(params[0].0.clone(), offset);
+            self.var_types.insert(params[0].0.clone(), Type::Int);
+            self.store("x0", offset);
+        } else {
+            self.compile_params(params);
+        }
+        self.current_function = name.clone();
+        self.param_slots = params.iter().map(|(param, _)| self.offset_map[param]).collect();
+        self.body_label = self.new_label("body");
+        self.emit(format!("{}:", self.body_label));
+
+        self.compile_statements(body);
+
+        let frame_size = self.var_offset.div_ceil(16) * 16;
+        let rest = self.text.split_off(reserve_index + 1);
+        self.text.pop();
+        self.adjust_sp("sub", frame_size);
+        self.text.extend(rest);
+
+        // Epilogue — default return 0, then shared cleanup
+        self.emit("    mov x0, #0".into());
+        self.emit(format!("{}:", self.epilogue_label));
+        if *returns == Type::Float {
+            self.emit("    fmov d0, x0".into());
+        }
+        self.leave_frame();
+        self.emit("    ret".into());
+    }
+
+    // Releases the frame of a stack machine function, whatever it has pushed
+    fn leave_frame(&mut self) {
+        self.emit("    mov sp, x29".into());
+        self.emit("    ldp x29, x30, [sp], #16".into());
+    }
+
+    fn compile_params(&mut self, params: &[(String, Option<Type>)]) {
+        let (mut int_index, mut float_index) = (0, 0);
+        for (name, ty) in params {
+            let ty = ty.unwrap_or(Type::Int);
+            let offset = self.temp_slot();
+            self.offset_map.insert(name.clone(), offset);
+            self.var_types.insert(name.clone(), ty);
+            if ty == Type::Float {
+                self.store(&format!("d{}", float_index), offset);
+                float_index += 1;
+            } else {
+                self.store(ARG_REGS[int_index], offset);
+                int_index += 1;
+            }
+        }
+    }
+
+    fn compile_call(&mut self, name: &str, args: &[Expression]) {
+        if builtins::arity(name).is_some() {
+            self.compile_builtin(name, args);
+            return;
+        }
+        if let Some(&offset) = self.offset_map.get(name) {
+            self.compile_indirect_call(offset, args);
+            return;
+        }
+        let returns = self.load_arguments(name, args);
+        self.emit(format!("    bl {}", name));
+        if returns == Type::Float {
+            self.emit("    fmov x0, d0".into());
+        }
+        if self.int_externs.iter().any(|ext| ext == name) {
+            self.emit("    sxtw x0, w0".into());
+        }
+    }
+
+    // A plain function as a value is a closure record with no captures, shared
+    // by every `&name` of that function
+    fn compile_function_ref(&mut self, name: &str) {
+        let record = format!("closure_{}: .quad {}", name, name);
+        if !self.records.contains(&record) {
+            self.records.push(record);
+        }
+        self.load_address("x0", &format!("closure_{}", name));
+    }
+
+    // Calls the function value held in a variable. Function values are closure
+    // records whose first word is the code address; the record itself goes in
+    // x9 so anonymous functions can load their captures. Every argument and
+    // the result travel in general purpose registers.
+    fn compile_indirect_call(&mut self, offset: usize, args: &[Expression]) {
+        for arg in args {
+            self.compile_expression(arg);
+            self.push("x0");
+        }
+        for reg in ARG_REGS[..args.len()].iter().rev() {
+            self.pop(reg);
+        }
+        self.load("x9", offset);
+        self.emit("    ldr x16, [x9]".into());
+        self.emit("    blr x16".into());
+    }
+
+    fn compile_builtin(&mut self, name: &str, args: &[Expression]) {
+        match name {
+            "peek" => {
+                self.compile_expression(&args[0]);
+                self.emit("    ldr x0, [x0]".into());
+            }
+            "poke" => {
+                self.compile_expression(&args[0]);
+                self.push("x0");
+                self.compile_expression(&args[1]);
+                self.pop("x1");
+                self.emit("    str x0, [x1]".into());
+            }
+            "float" => {
+                let ty = self.expr_type(&args[0]);
+                self.compile_expression(&args[0]);
+                self.convert(ty, Type::Float);
+            }
+            "int" => {
+                let ty = self.expr_type(&args[0]);
+                self.compile_expression(&args[0]);
+                match ty {
+                    // Truncates toward zero
+                    Type::Float => {
+                        self.emit("    fmov d0, x0".into());
+                        self.emit("    fcvtzs x0, d0".into());
+                    }
+                    Type::Str => self.call_runtime("bonk_str_to_int"),
+                    Type::Int => {}
+                }
+            }
+            "str" => {
+                let ty = self.expr_type(&args[0]);
+                self.compile_expression(&args[0]);
+                match ty {
+                    Type::Int => self.call_runtime("bonk_str_from_int"),
+                    Type::Float => {
+                        self.emit("    fmov d0, x0".into());
+                        self.call_runtime("bonk_str_from_float");
+                    }
+                    Type::Str => {}
+                }
+            }
+            "len" => {
+                self.compile_expression(&args[0]);
+                self.call_runtime("bonk_str_len");
+            }
+            "read_int" => self.call_runtime("bonk_read_int"),
+            "read_line" => self.call_runtime("bonk_read_line"),
+            "eof" => self.call_runtime("bonk_eof"),
+            "arg" => {
+                self.compile_expression(&args[0]);
+                self.push("x0");
+                self.compile_expression(&args[1]);
+                self.emit("    mov x1, x0".into());
+                self.pop("x0");
+                self.call_runtime("bonk_arg");
+            }
+            "exit" => {
+                self.compile_expression(&args[0]);
+                self.call_runtime("bonk_exit");
+            }
+            _ => unreachable!("unknown builtin {}", name),
+        }
+    }
+
+    fn push(&mut self, reg: &str) {
+        self.emit(format!("    str {}, [sp, #-16]!", reg));
+    }
+
+    fn pop(&mut self, reg: &str) {
+        self.emit(format!("    ldr {}, [sp], #16", reg));
+    }
+
+    // Divides x1 by x0 into x0, trapping on a zero divisor
+    fn compile_division(&mut self, location: &Location) {
+        let nonzero = self.new_label("div_nonzero");
+        self.emit(format!("    cbnz x0, {}", nonzero));
+        self.emit_trap("division by zero", location);
+        self.emit(format!("{}:", nonzero));
+        // sdiv gives i64::MIN for i64::MIN / -1, which is the wrapped result
+        if self.overflow == Overflow::Trap {
+            let divide = self.new_label("div");
+            self.emit("    cmn x0, #1".into());
+            self.emit(format!("    b.ne {}", divide));
+            self.emit("    negs x2, x1".into());
+            self.emit(format!("    b.vc {}", divide));
+            self.emit_trap("integer overflow in '/'", location);
+            self.emit(format!("{}:", divide));
+        }
+        self.emit("    sdiv x0, x1, x0".into());
+    }
+
+    // Traps unless `condition` holds after the preceding instruction, when
+    // --overflow=trap is on
+    fn check_overflow(&mut self, op: &str, condition: &str, location: &Location) {
+        if self.overflow == Overflow::Wrap {
+            return;
+        }
+        let ok = self.new_label("no_overflow");
+        self.emit(format!("    b.{} {}", condition, ok));
+        self.emit_trap(&format!("integer overflow in '{}'", op), location);
+        self.emit(format!("{}:", ok));
+    }
+
+    fn compile_assert(&mut self, condition: &Expression, message: Option<&Expression>, text: &str, location: &Location) {
+        let ok = self.new_label("assert_ok");
+        self.compile_expression(condition);
+        self.emit(format!("    cbnz x0, {}", ok));
+        match message {
+            Some(message) => {
+                self.compile_expression(message);
+                self.emit("    mov x1, x0".into());
+            }
+            None => {
+                let label = self.string_literal(text);
+                self.load_address("x1", &label);
+            }
+        }
+        let label = self.string_literal(&format!("assertion failed at {}: ", location));
+        self.load_address("x0", &label);
+        self.call_runtime("bonk_assert_fail");
+        self.emit(format!("{}:", ok));
+    }
+
+    fn compile_float_op(&mut self, left: &Expression, op: &BinaryOperator, right: &Expression) {
+        self.compile_float_operands(left, right);
+        let instruction = match op {
+            BinaryOperator::Add => "fadd",
+            BinaryOperator::Sub => "fsub",
+            BinaryOperator::Mul => "fmul",
+            BinaryOperator::Div => "fdiv",
+            _ => {
+                self.emit("    fcmp d0, d1".into());
+                self.emit(format!("    cset x0, {}", float_condition_code(op)));
+                return;
+            }
+        };
+        self.emit(format!("    {} d0, d0, d1", instruction));
+        self.emit("    fmov x0, d0".into());
+    }
+
+    // Branches to false_label unless the condition of an `if` or `while`
+    // holds. A comparison branches on the flags of its own compare instead of
+    // materialising 0 or 1 and testing that.
+    fn compile_condition(&mut self, condition: &Expression, false_label: &str) {
+        let (left, op, right) = match condition {
+            Expression::BinaryOp { left, op, right, .. } if op.is_comparison() => (left, op, right),
+            _ => {
+                self.compile_expression(condition);
+                self.emit(format!("    cbz x0, {}", false_label));
+                return;
+            }
+        };
+        if self.expr_type(left) == Type::Str || self.expr_type(right) == Type::Str {
+            self.compile_string_operands(left, right);
+            self.call_runtime("bonk_str_compare");
+            self.emit("    cmp x0, #0".into());
+            self.emit(format!("    b.{} {}", inverse_condition_code(op), false_label));
+        } else if self.expr_type(left) == Type::Float || self.expr_type(right) == Type::Float {
+            self.compile_float_operands(left, right);
+            self.emit("    fcmp d0, d1".into());
+            self.emit(format!("    b.{} {}", inverse_float_condition_code(op), false_label));
+        } else {
+            self.compile_expression(left);
+            self.push("x0");
+            self.compile_expression(right);
+            self.pop("x1");
+            self.emit("    cmp x1, x0".into());
+            self.emit(format!("    b.{} {}", inverse_condition_code(op), false_label));
+        }
+    }
+
+    fn compile_print(&mut self, args: &[Expression], newline: bool) {
+        let (format, values) = self.print_format(args, newline);
+        let label = self.string_literal(&format);
+        self.emit_printf_call("printf", &[], &label, &values);
+    }
+
+    // The address of the frame slot at `offset` below x29. Offsets beyond the
+    // reach of a load or store go through x16.
+    fn slot(&mut self, offset: usize) -> String {
+        if offset <= 256 {
+            return format!("[x29, #-{}]", offset);
+        }
+        if offset < 4096 {
+            self.emit(format!("    sub x16, x29, #{}", offset));
+        } else {
+            self.load_immediate("x16", offset as i64);
+            self.emit("    sub x16, x29, x16".into());
+        }
+        "[x16]".into()
+    }
+
+    fn load(&mut self, reg: &str, offset: usize) {
+        let slot = self.slot(offset);
+        self.emit(format!("    ldr {}, {}", reg, slot));
+    }
+
+    fn store(&mut self, reg: &str, offset: usize) {
+        let slot = self.slot(offset);
+        self.emit(format!("    str {}, {}", reg, slot));
+    }
+
+    fn load_address(&mut self, reg: &str, label: &str) {
+        self.emit(format!("    adrp {}, {}", reg, label));
+        self.emit(format!("    add {}, {}, :lo12:{}", reg, reg, label));
+    }
+}
+
+// The register a value for `dst` is computed in: its own, or `scratch` when
+// it lives in a spill slot
+impl StackMachine for Generator<'_> {
+    type Slot = usize;
+
+    fn signatures(&self) -> &Signatures {
+        self.signatures
+    }
+
+    fn variables(&self) -> &HashMap<String, Type> {
+        &self.var_types
+    }
+
+    fn variable_slot(&self, name: &str) -> Option<usize> {
+        self.offset_map.get(name).copied()
+    }
+
+    fn bind(&mut self, name: &str, slot: usize, ty: Type) {
+        self.offset_map.insert(name.to_string(), slot);
+        self.var_types.insert(name.to_string(), ty);
+    }
+
+    // Reserves an unnamed 8-byte slot in the current frame
+    fn temp_slot(&mut self) -> usize {
+        let offset = self.var_offset;
+        self.var_offset += 8;
+        offset
+    }
+
+    fn new_label(&mut self, label: &str) -> String {
+        let label = format!(".L{}_{}", label, self.label_count);
+        self.label_count += 1;
+        label
+    }
+
+    fn place_label(&mut self, label: &str) {
+        self.emit(format!("{}:", label));
+    }
+
+    fn is_extern(&self, name: &str) -> bool {
+        self.externs.iter().any(|e| e == name)
+    }
+
+    fn return_type(&self) -> Type {
+        self.return_type
+    }
+
+    fn current_function(&self) -> &str {
+        &self.current_function
+    }
+
+    fn param_slots(&self) -> Vec<usize> {
+        self.param_slots.clone()
+    }
+
+    fn set_inline_exit(&mut self, exit: Option<(String, usize, Type)>) -> Option<(String, usize, Type)> {
+        std::mem::replace(&mut self.inline_exit, exit)
+    }
+
+    fn compile_statements(&mut self, body: &[Statement]) {
+        for stmt in body {
+            match stmt {
+                Statement::Assign { name, value } => self.compile_assignment(name, value),
+                Statement::While { condition, body } => {
+                    let start_label = self.new_label("while_start");
+                    let end_label = self.new_label("while_end");
+                    self.emit(format!("{}:", start_label));
+                    self.compile_condition(condition, &end_label);
+                    self.compile_statements(body);
+                    self.emit(format!("    b {}", start_label));
+                    self.emit(format!("{}:", end_label));
+                }
+                Statement::If { condition, then_body, else_body } => {
+                    let end_label = self.new_label("endif");
+                    let else_label = else_body.as_ref().map(|_| self.new_label("else"));
+                    let false_label = else_label.clone().unwrap_or_else(|| end_label.clone());
+                    self.compile_condition(condition, &false_label);
+                    self.compile_statements(then_body);
+                    if let (Some(else_label), Some(else_body)) = (else_label, else_body) {
+                        self.emit(format!("    b {}", end_label));
+                        self.emit(format!("{}:", else_label));
+                        self.compile_statements(else_body);
+                    }
+                    self.emit(format!("{}:", end_label));
+                }
+                Statement::FunctionCall { name, args } => self.compile_call(name, args),
+                Statement::Print { args, newline } => self.compile_print(args, *newline),
+                Statement::Send(expr) if self.inline_exit.is_some() => {
+                    let (exit, offset, returns) = self.inline_exit.clone().unwrap();
+                    let ty = self.expr_type(expr);
+                    self.compile_expression(expr);
+                    self.convert(ty, returns);
+                    self.store("x0", offset);
+                    self.emit(format!("    b {}", exit));
+                }
+                Statement::Send(Expression::FunctionCall { name, args }) if self.is_tail_callable(name) => {
+                    self.compile_tail_call(name, args);
+                }
+                Statement::Send(expr) => {
+                    let ty = self.expr_type(expr);
+                    self.compile_expression(expr);
+                    self.convert(ty, self.return_type);
+                    self.emit(format!("    b {}", self.epilogue_label));
+                }
+                Statement::Assert { condition, message, text, location } => {
+                    self.compile_assert(condition, message.as_ref(), text, location);
+                }
+                Statement::Inlined { params, returns, args, body, result, .. } => {
+                    self.compile_inlined(params, *returns, args, body, result);
+                }
+                Statement::Function { .. } | Statement::Extern { .. } | Statement::Use(_) => {}
+            }
+        }
+    }
+
+    fn compile_expression(&mut self, expr: &Expression) {
+        match expr {
+            Expression::Integer(value) => self.load_immediate("x0", *value),
+            Expression::Float(value) => self.load_immediate("x0", value.to_bits() as i64),
+            Expression::Variable(name) => match self.offset_map.get(name) {
+                Some(&offset) => self.load("x0", offset),
+                None => unreachable!("the checker rejects undefined variable {}", name),
+            },
+            Expression::StringLiteral(text) => {
+                let label = self.string_literal(text);
+                self.load_address("x0", &label);
+            }
+            Expression::BinaryOp { left, op, right, .. }
+                if self.expr_type(left) == Type::Str || self.expr_type(right) == Type::Str =>
+            {
+                self.compile_string_op(left, op, right);
+            }
+            Expression::BinaryOp { left, op, right, .. }
+                if self.expr_type(left) == Type::Float || self.expr_type(right) == Type::Float =>
+            {
+                self.compile_float_op(left, op, right);
+            }
+            Expression::BinaryOp { left, op, right, location } => {
+                self.compile_expression(left);
+                self.push("x0");
+                self.compile_expression(right);
+                self.pop("x1");
+                let flags = if self.overflow == Overflow::Trap { "s" } else { "" };
+                match op {
+                    BinaryOperator::Add => {
+                        self.emit(format!("    add{} x0, x1, x0", flags));
+                        self.check_overflow("+", "vc", location);
+                    }
+                    BinaryOperator::Sub => {
+                        self.emit(format!("    sub{} x0, x1, x0", flags));
+                        self.check_overflow("-", "vc", location);
+                    }
+                    BinaryOperator::Mul => {
+                        // The product fits when its high half is all copies of the low half's sign
+                        if self.overflow == Overflow::Trap {
+                            self.emit("    smulh x2, x1, x0".into());
+                        }
+                        self.emit("    mul x0, x1, x0".into());
+                        if self.overflow == Overflow::Trap {
+                            self.emit("    cmp x2, x0, asr #63".into());
+                        }
+                        self.check_overflow("*", "eq", location);
+                    }
+                    BinaryOperator::Div => self.compile_division(location),
+                    _ => {
+                        self.emit("    cmp x1, x0".into());
+                        self.emit(format!("    cset x0, {}", condition_code(op)));
+                    }
+                }
+            }
+            Expression::FunctionCall { name, args } => self.compile_call(name, args),
+            Expression::Format(_) => self.compile_format_string(expr),
+            Expression::FunctionRef(name) => self.compile_function_ref(name),
+            Expression::Closure { function, captures } if captures.is_empty() => {
+                self.compile_function_ref(function);
+            }
+            Expression::Closure { function, captures } => {
+                let types = captures.iter().map(|capture| self.var_types.get(capture).copied().unwrap_or(Type::Int)).collect();
+                self.capture_types.insert(function.clone(), types);
+                // Record layout: [code, captures...]
+                self.load_immediate("x0", ((captures.len() + 1) * 8) as i64);
+                self.call_runtime("bonk_alloc");
+                self.load_address("x1", function);
+                self.emit("    str x1, [x0]".into());
+                for (i, capture) in captures.iter().enumerate() {
+                    match self.offset_map.get(capture) {
+                        Some(&offset) => self.load("x1", offset),
+                        None => unreachable!("the checker rejects undefined variable {}", capture),
+                    }
+                    self.emit(format!("    str x1, [x0, #{}]", (i + 1) * 8));
+                }
+            }
+            Expression::Lambda { .. } => unreachable!("anonymous functions are lifted before code generation"),
+            Expression::Index { value, index } => {
+                self.compile_expression(value);
+                self.push("x0");
+                self.compile_expression(index);
+                self.pop("x1");
+                self.emit("    ldrb w0, [x1, x0]".into());
+            }
+        }
+    }
+
+    // Converts the value in x0 between numeric types
+    fn convert(&mut self, from: Type, to: Type) {
+        if from == Type::Int && to == Type::Float {
+            self.emit("    scvtf d0, x0".into());
+            self.emit("    fmov x0, d0".into());
+        }
+    }
+
+    fn load_integer(&mut self, value: i64) {
+        self.load_immediate("x0", value);
+    }
+
+    fn load_result(&mut self, slot: usize) {
+        self.load("x0", slot);
+    }
+
+    fn store_result(&mut self, slot: usize) {
+        self.store("x0", slot);
+    }
+
+    fn push_result(&mut self) {
+        self.push("x0");
+    }
+
+    fn pop_result(&mut self) {
+        self.pop("x0");
+    }
+
+    // Integers take x0..x7 and floats d0..d7
+    fn pop_argument(&mut self, ty: Type, index: usize) {
+        if ty == Type::Float {
+            self.pop(&format!("d{}", index));
+        } else {
+            self.pop(ARG_REGS[index]);
+        }
+    }
+
+    fn pop_operands(&mut self) {
+        self.emit("    mov x1, x0".into());
+        self.pop("x0");
+    }
+
+    fn pop_float_operands(&mut self) {
+        self.pop("x1");
+        self.emit("    fmov d0, x1".into());
+        self.emit("    fmov d1, x0".into());
+    }
+
+    fn set_if(&mut self, op: &BinaryOperator) {
+        self.emit("    cmp x0, #0".into());
+        self.emit(format!("    cset x0, {}", condition_code(op)));
+    }
+
+    fn call_runtime(&mut self, name: &'static str) {
+        self.use_runtime(name);
+        self.emit(format!("    bl {}", name));
+    }
+
+    fn string_literal(&mut self, s: &str) -> String {
+        if let Some(label) = self.strings.get(s) {
+            return label.clone();
+        }
+        let label = format!(".Lstr_{}", self.strings.len());
+        self.strings.insert(s.to_string(), label.clone());
+        self.rodata.push(format!("{}:", label));
+        self.rodata.push(format!("    .asciz \"{}\"", escape(s)));
+        label
+    }
+
+    // Values that do not fit in registers are passed on the stack
+    fn emit_printf_call(&mut self, symbol: &str, fixed: &[Option<usize>], label: &str, values: &[(usize, Type)]) {
+        let mut int_count = fixed.len() + 1;
+        let mut float_count = 0;
+        let mut in_regs = Vec::new();
+        let mut on_stack = Vec::new();
+        for &(slot, ty) in values {
+            if ty == Type::Float && float_count < 8 {
+                in_regs.push((format!("d{}", float_count), slot));
+                float_count += 1;
+            } else if ty != Type::Float && int_count < ARG_REGS.len() {
+                in_regs.push((ARG_REGS[int_count].to_string(), slot));
+                int_count += 1;
+            } else {
+                on_stack.push(slot);
+            }
+        }
+
+        // Reserve the outgoing stack arguments, keeping sp 16-byte aligned
+        let reserved = (on_stack.len() * 8).div_ceil(16) * 16;
+        if reserved > 0 {
+            self.adjust_sp("sub", reserved);
+        }
+        for (i, &slot) in on_stack.iter().enumerate() {
+            self.load("x17", slot);
+            self.emit(format!("    str x17, [sp, #{}]", i * 8));
+        }
+
+        for (reg, slot) in ARG_REGS.iter().zip(fixed) {
+            match slot {
+                Some(slot) => self.load(reg, *slot),
+                None => self.emit(format!("    mov {}, #0", reg)),
+            }
+        }
+        self.load_address(ARG_REGS[fixed.len()], label);
+        for (reg, slot) in in_regs {
+            self.load(&reg, slot);
+        }
+        self.emit(format!("    bl {}", symbol));
+
+        if reserved > 0 {
+            self.adjust_sp("add", reserved);
+        }
+    }
+
+    fn store_buffer_size(&mut self, slot: usize) {
+        self.emit("    sxtw x0, w0".into());
+        self.emit("    add x0, x0, #1".into());
+        self.store("x0", slot);
+    }
+
+    fn restart_body(&mut self) {
+        self.emit(format!("    b {}", self.body_label));
+    }
+
+    fn jump_to_function(&mut self, name: &str) {
+        self.leave_frame();
+        self.emit(format!("    b {}", name));
+    }
+}
+
+fn target(frame: &Frame, dst: VReg, scratch: &'static str) -> &'static str {
+    match frame.assignment(dst) {
+        Assignment::Register(reg) => reg,
+        Assignment::Spilled(_) => scratch,
+    }
+}
+
+// Whether add, sub, cmp and cmn can take the value as an immediate
+fn immediate(value: u64) -> bool {
+    value < 4096
+}
+
+fn condition_code(op: &BinaryOperator) -> &'static str {
+    match op {
+        BinaryOperator::Eq => "eq",
+        BinaryOperator::NEq => "ne",
+        BinaryOperator::Lt => "lt",
+        BinaryOperator::LtEq => "le",
+        BinaryOperator::Gt => "gt",
+        _ => unreachable!("{:?} is not a comparison", op),
+    }
+}
+
+fn inverse_condition_code(op: &BinaryOperator) -> &'static str {
+    match op {
+        BinaryOperator::Eq => "ne",
+        BinaryOperator::NEq => "eq",
+        BinaryOperator::Lt => "ge",
+        BinaryOperator::LtEq => "gt",
+        BinaryOperator::Gt => "le",
+        _ => unreachable!("{:?} is not a comparison", op),
+    }
+}
+
+// After fcmp, an unordered compare (NaN) sets C and V, which satisfies only
+// `ne` here and every inverse condition but `eq`
+fn float_condition_code(op: &BinaryOperator) -> &'static str {
+    match op {
+        BinaryOperator::Eq => "eq",
+        BinaryOperator::NEq => "ne",
+        BinaryOperator::Lt => "mi",
+        BinaryOperator::LtEq => "ls",
+        BinaryOperator::Gt => "gt",
+        _ => unreachable!("{:?} is not a comparison", op),
+    }
+}
+
+fn inverse_float_condition_code(op: &BinaryOperator) -> &'static str {
+    match op {
+        BinaryOperator::Eq => "ne",
+        BinaryOperator::NEq => "eq",
+        BinaryOperator::Lt => "pl",
+        BinaryOperator::LtEq => "hi",
+        BinaryOperator::Gt => "le",
+        _ => unreachable!("{:?} is not a comparison", op),
+    }
+}
+
+// The contents of an .asciz string; quotes, backslashes and control
+// characters are written as octal escapes
+fn escape(s: &str) -> String {
+    let mut escaped = String::new();
+    for byte in s.bytes() {
+        if byte == b'"' || byte == b'\\' || !(0x20..0x7f).contains(&byte) {
+            escaped.push_str(&format!("\\{:03o}", byte));
+        } else {
+            escaped.push(byte as char);
+        }
+    }
+    escaped
+}
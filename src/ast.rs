@@ -1,22 +1,45 @@
 // abstract syntax tree
 
+use std::fmt;
+use std::rc::Rc;
+
 #[derive(Debug, Clone)]
 pub enum Statement {
     Assign{
         name: String,
         value: Expression,
     },
-    Print(Expression),
+    // `print` ends the line, `write` does not; arguments are separated by spaces
+    Print {
+        args: Vec<Expression>,
+        newline: bool,
+    },
     Send(Expression),
     Function{
         name: String,
-        params: Vec<Expression>,
+        // Parameter names with their annotation, `None` when untyped
+        params: Vec<(String, Option<Type>)>,
+        returns: Type,
         body: Vec<Statement>,
+        // Variables an anonymous function takes from its environment, loaded
+        // from the closure record passed in r10; empty for `run` functions
+        captures: Vec<String>,
+        // `@name` annotations such as `@export`
+        attributes: Vec<String>,
     },
     FunctionCall{
         name: String,
         args: Vec<Expression>
     },
+    Extern {
+        name: String,
+        params: Vec<(String, Option<Type>)>,
+        returns: Type,
+        // Declared `-> long`: the whole 64-bit result is used. A C `int`
+        // result only fills the low 32 bits and is sign-extended.
+        long: bool,
+    },
+    Use(Import),
     If {
         condition: Expression,
         then_body: Vec<Statement>,
@@ -26,27 +49,109 @@ pub enum Statement {
         condition: Expression,
         body: Vec<Statement>,
     },
+    // `assert cond;` or `assert cond, "message";`
+    Assert {
+        condition: Expression,
+        message: Option<Expression>,
+        // Source text of the condition, reported when there is no message
+        text: String,
+        location: Location,
+    },
+    // A call replaced by the body of the function, with its parameters and
+    // locals renamed. The parameters are assigned the arguments first, and a
+    // `send` in the body stores its value in `result` and continues after it.
+    Inlined {
+        function: String,
+        params: Vec<(String, Option<Type>)>,
+        returns: Type,
+        args: Vec<Expression>,
+        body: Vec<Statement>,
+        result: String,
+    },
      
 }
 
 #[derive(Debug, Clone)]
 pub enum Expression {
     Integer(i64),
+    Float(f64),
     StringLiteral(String),
-    FunctionArg(String),
     Variable(String),
     BinaryOp {
         left: Box<Expression>,
         op: BinaryOperator,
         right: Box<Expression>,
+        // Reported by the runtime checks on division and overflow
+        location: Location,
     },
     FunctionCall{
         name: String,
         args: Vec<Expression>
+    },
+    // `&name` — a function as a value, called through `~f(...)`
+    FunctionRef(String),
+    // `fn(x) => x + offset`, replaced by a Closure before checking
+    Lambda {
+        params: Vec<(String, Option<Type>)>,
+        body: Box<Expression>,
+    },
+    // A lifted anonymous function together with the variables it captures
+    Closure {
+        function: String,
+        captures: Vec<String>,
+    },
+    // `s[i]` — the byte at offset i of a string
+    Index {
+        value: Box<Expression>,
+        index: Box<Expression>,
+    },
+    // Interpolated string literal: `"x = {x}, hex = {x:08x}"`
+    Format(Vec<FormatPart>),
+}
+
+// Every `name = value` in a body, including those in nested blocks
+pub fn assignments(body: &[Statement]) -> Vec<(&str, &Expression)> {
+    let mut found = Vec::new();
+    for stmt in body {
+        match stmt {
+            Statement::Assign { name, value } => found.push((name.as_str(), value)),
+            Statement::If { then_body, else_body, .. } => {
+                found.extend(assignments(then_body));
+                if let Some(else_body) = else_body {
+                    found.extend(assignments(else_body));
+                }
+            }
+            Statement::While { body, .. } => found.extend(assignments(body)),
+            Statement::Inlined { params, args, body, .. } => {
+                found.extend(params.iter().map(|(param, _)| param.as_str()).zip(args));
+                found.extend(assignments(body));
+            }
+            _ => {}
+        }
     }
+    found
 }
 
 #[derive(Debug, Clone)]
+pub enum FormatPart {
+    Text(String),
+    Value {
+        expr: Expression,
+        spec: FormatSpec,
+    },
+}
+
+// `{value:[<][0][width][.precision][type]}` where type is one of d, x, X, o, f, e, s
+#[derive(Debug, Clone, Default)]
+pub struct FormatSpec {
+    pub left_align: bool,
+    pub zero_pad: bool,
+    pub width: Option<usize>,
+    pub precision: Option<usize>,
+    pub kind: Option<char>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum BinaryOperator {
     Add,
     Sub,
@@ -59,4 +164,217 @@ pub enum BinaryOperator {
     LtEq,
 }
 
+impl BinaryOperator {
+    pub fn is_comparison(&self) -> bool {
+        matches!(self, BinaryOperator::Eq | BinaryOperator::NEq | BinaryOperator::Gt | BinaryOperator::Lt | BinaryOperator::LtEq)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Import {
+    // `use "path/to/file.bonk";` — relative to the importing file
+    File(String),
+    // `use std::math;` — resolved against the module search path
+    Module(Vec<String>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Type {
+    Int,
+    Str,
+    Float,
+}
+
+// Source file and line of a construct, shown as `file.bonk:12`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Location {
+    pub file: Rc<str>,
+    pub line: usize,
+}
 
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Type::Int => write!(f, "int"),
+            Type::Str => write!(f, "str"),
+            Type::Float => write!(f, "float"),
+        }
+    }
+}
+
+impl fmt::Display for BinaryOperator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let symbol = match self {
+            BinaryOperator::Add => "+",
+            BinaryOperator::Sub => "-",
+            BinaryOperator::Mul => "*",
+            BinaryOperator::Div => "/",
+            BinaryOperator::Eq => "==",
+            BinaryOperator::NEq => "!=",
+            BinaryOperator::Gt => ">",
+            BinaryOperator::Lt => "<",
+            BinaryOperator::LtEq => "<=",
+        };
+        write!(f, "{}", symbol)
+    }
+}
+
+impl fmt::Display for FormatSpec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.left_align {
+            write!(f, "<")?;
+        }
+        if self.zero_pad {
+            write!(f, "0")?;
+        }
+        if let Some(width) = self.width {
+            write!(f, "{}", width)?;
+        }
+        if let Some(precision) = self.precision {
+            write!(f, ".{}", precision)?;
+        }
+        if let Some(kind) = self.kind {
+            write!(f, "{}", kind)?;
+        }
+        Ok(())
+    }
+}
+
+// Expressions as source text. Nested operations are parenthesised to show the
+// shape of the tree, as the language itself has no grouping.
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Expression::Integer(value) => write!(f, "{}", value),
+            Expression::Float(value) => write!(f, "{:?}", value),
+            Expression::StringLiteral(s) => write!(f, "\"{}\"", s),
+            Expression::Variable(name) => write!(f, "{}", name),
+            Expression::FunctionRef(name) => write!(f, "&{}", name),
+            Expression::BinaryOp { left, op, right, .. } => {
+                let operand = |operand: &Expression| match operand {
+                    Expression::BinaryOp { .. } => format!("({})", operand),
+                    _ => operand.to_string(),
+                };
+                write!(f, "{} {} {}", operand(left), op, operand(right))
+            }
+            Expression::FunctionCall { name, args } => write!(f, "~{}({})", name, list(args)),
+            Expression::Lambda { params, body } => write!(f, "fn({}) => {}", parameters(params), body),
+            Expression::Closure { function, captures } => write!(f, "&{}[{}]", function, captures.join(", ")),
+            Expression::Index { value, index } => write!(f, "{}[{}]", value, index),
+            Expression::Format(parts) => {
+                write!(f, "\"")?;
+                for part in parts {
+                    match part {
+                        FormatPart::Text(text) => write!(f, "{}", text.replace('{', "{{").replace('}', "}}"))?,
+                        FormatPart::Value { expr, spec } => {
+                            write!(f, "{{{}", expr)?;
+                            let spec = spec.to_string();
+                            if !spec.is_empty() {
+                                write!(f, ":{}", spec)?;
+                            }
+                            write!(f, "}}")?;
+                        }
+                    }
+                }
+                write!(f, "\"")
+            }
+        }
+    }
+}
+
+fn list(values: &[Expression]) -> String {
+    values.iter().map(|value| value.to_string()).collect::<Vec<_>>().join(", ")
+}
+
+fn parameters(params: &[(String, Option<Type>)]) -> String {
+    let params: Vec<String> = params
+        .iter()
+        .map(|(name, ty)| match ty {
+            Some(ty) => format!("{}: {}", name, ty),
+            None => name.clone(),
+        })
+        .collect();
+    params.join(", ")
+}
+
+// The program as source text, one line per entry, for --print-after. Inlined
+// calls, which have no syntax of their own, show as
+// `result = inline ~f(param = arg, ...)` followed by the body and `end`.
+pub fn dump(program: &[Statement]) -> Vec<String> {
+    let mut lines = Vec::new();
+    for stmt in program {
+        dump_statement(stmt, 0, &mut lines);
+    }
+    lines
+}
+
+fn dump_body(body: &[Statement], depth: usize, lines: &mut Vec<String>) {
+    for stmt in body {
+        dump_statement(stmt, depth, lines);
+    }
+}
+
+fn dump_statement(stmt: &Statement, depth: usize, lines: &mut Vec<String>) {
+    let indent = "  ".repeat(depth);
+    match stmt {
+        Statement::Assign { name, value } => lines.push(format!("{}{} = {};", indent, name, value)),
+        Statement::Print { args, newline } => {
+            let keyword = if *newline { "print" } else { "write" };
+            lines.push(format!("{}{} {};", indent, keyword, list(args)));
+        }
+        Statement::Send(value) => lines.push(format!("{}send {};", indent, value)),
+        Statement::Function { name, params, returns, body, captures, attributes } => {
+            for attribute in attributes {
+                lines.push(format!("{}@{}", indent, attribute));
+            }
+            let mut header = format!("{}run {}({})", indent, name, parameters(params));
+            if *returns != Type::Int {
+                header += &format!(" -> {}", returns);
+            }
+            if !captures.is_empty() {
+                header += &format!(" # captures {}", captures.join(", "));
+            }
+            lines.push(header);
+            dump_body(body, depth + 1, lines);
+            lines.push(format!("{}end", indent));
+        }
+        Statement::FunctionCall { name, args } => lines.push(format!("{}~{}({});", indent, name, list(args))),
+        Statement::Extern { name, params, returns, long } => {
+            let returns = if *long { "long".to_string() } else { returns.to_string() };
+            lines.push(format!("{}extern run {}({}) -> {};", indent, name, parameters(params), returns));
+        }
+        Statement::Use(Import::File(path)) => lines.push(format!("{}use \"{}\";", indent, path)),
+        Statement::Use(Import::Module(path)) => lines.push(format!("{}use {};", indent, path.join("::"))),
+        Statement::If { condition, then_body, else_body } => {
+            lines.push(format!("{}if {} then", indent, condition));
+            dump_body(then_body, depth + 1, lines);
+            if let Some(else_body) = else_body {
+                lines.push(format!("{}else", indent));
+                dump_body(else_body, depth + 1, lines);
+            }
+            lines.push(format!("{}end", indent));
+        }
+        Statement::While { condition, body } => {
+            lines.push(format!("{}while {} do", indent, condition));
+            dump_body(body, depth + 1, lines);
+            lines.push(format!("{}end", indent));
+        }
+        Statement::Assert { condition, message, .. } => match message {
+            Some(message) => lines.push(format!("{}assert {}, {};", indent, condition, message)),
+            None => lines.push(format!("{}assert {};", indent, condition)),
+        },
+        Statement::Inlined { function, params, args, body, result, .. } => {
+            let bindings: Vec<String> =
+                params.iter().zip(args).map(|((param, _), arg)| format!("{} = {}", param, arg)).collect();
+            lines.push(format!("{}{} = inline ~{}({})", indent, result, function, bindings.join(", ")));
+            dump_body(body, depth + 1, lines);
+            lines.push(format!("{}end", indent));
+        }
+    }
+}
@@ -0,0 +1,36 @@
+use crate::ast::Type;
+
+// Functions implemented by the compiler itself rather than in Bonk or C.
+// `peek`/`poke` read and write a 64-bit word at an address and are what the
+// standard library's list helpers are built on; `float`, `int` and `str`
+// convert between numbers and strings, and `len` is a string's length.
+// `read_int`/`read_line` read from stdin; both give 0/"" at end of input
+// and set the flag returned by `eof`. `arg` reads an entry of the argument
+// list `main(args)` receives and `exit` ends the process with a status.
+const BUILTINS: &[(&str, usize, Type)] = &[
+    ("peek", 1, Type::Int),
+    ("poke", 2, Type::Int),
+    ("float", 1, Type::Float),
+    ("int", 1, Type::Int),
+    ("str", 1, Type::Str),
+    ("len", 1, Type::Int),
+    ("read_int", 0, Type::Int),
+    ("read_line", 0, Type::Str),
+    ("eof", 0, Type::Int),
+    ("arg", 2, Type::Str),
+    ("exit", 1, Type::Int),
+];
+
+pub fn arity(name: &str) -> Option<usize> {
+    BUILTINS
+        .iter()
+        .find(|(builtin, _, _)| *builtin == name)
+        .map(|(_, arity, _)| *arity)
+}
+
+pub fn return_type(name: &str) -> Option<Type> {
+    BUILTINS
+        .iter()
+        .find(|(builtin, _, _)| *builtin == name)
+        .map(|(_, _, returns)| *returns)
+}
@@ -0,0 +1,338 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::ast::{assignments, BinaryOperator, Expression, FormatPart, FormatSpec, Statement, Type};
+use crate::builtins;
+use crate::compiler::printf_conversion;
+use crate::types::{self, Signatures};
+
+// Semantic checks that run between parsing and code generation
+
+struct Checker {
+    // Parameter types per callable, `None` when untyped (Bonk functions), and
+    // the return type
+    signatures: Signatures,
+    // Functions that can be taken as values with `&name`
+    addressable: HashSet<String>,
+    // Parameters and variables of the function being checked, with the arity
+    // of the function value they hold when every assignment agrees on one
+    locals: HashMap<String, Option<usize>>,
+    // Types of the variables of the function being checked that are assigned
+    // so far. As in the code generator, the first assignment fixes the type.
+    types: HashMap<String, Type>,
+    // Types of the variables each anonymous function captures, recorded where
+    // its closure is created
+    capture_types: HashMap<String, Vec<Type>>,
+    // Without the C library there is no printf, snprintf or stdin
+    freestanding: bool,
+}
+
+pub fn check_program(ast: &[Statement], freestanding: bool) -> Result<(), String> {
+    let mut checker = Checker {
+        signatures: HashMap::new(),
+        addressable: HashSet::new(),
+        locals: HashMap::new(),
+        types: HashMap::new(),
+        capture_types: HashMap::new(),
+        freestanding,
+    };
+
+    for stmt in ast {
+        let (name, params, returns) = match stmt {
+            Statement::Function { name, params, returns, .. } => {
+                // Function values pass everything in general purpose registers,
+                // and calls through them are typed as returning an integer
+                if *returns == Type::Int && params.iter().all(|(_, ty)| *ty != Some(Type::Float)) {
+                    checker.addressable.insert(name.clone());
+                }
+                (name, params.iter().map(|(_, ty)| *ty).collect::<Vec<_>>(), *returns)
+            }
+            Statement::Extern { name, params, returns, .. } => {
+                (name, params.iter().map(|(_, ty)| *ty).collect::<Vec<_>>(), *returns)
+            }
+            _ => continue,
+        };
+        if builtins::arity(name).is_some() {
+            return Err(format!("Function {} shadows a builtin", name));
+        }
+        if name == "main" && params.len() > 1 {
+            return Err("main takes no parameters or a single argument list".into());
+        }
+        if checker.signatures.insert(name.clone(), (params, returns)).is_some() {
+            return Err(format!("Function {} is defined more than once", name));
+        }
+    }
+
+    checker.check_statements(ast)
+}
+
+impl Checker {
+    fn check_statements(&mut self, body: &[Statement]) -> Result<(), String> {
+        for stmt in body {
+            match stmt {
+                Statement::Assign { name, value } => {
+                    self.check_expression(value)?;
+                    let value_ty = self.expr_type(value);
+                    match self.types.get(name) {
+                        // Integers are widened when assigned to float variables
+                        Some(&ty) if value_ty != ty && !(ty == Type::Float && value_ty == Type::Int) => {
+                            return Err(format!("Cannot assign {} value to {} variable {}", value_ty, ty, name));
+                        }
+                        Some(_) => {}
+                        None => {
+                            self.types.insert(name.clone(), value_ty);
+                        }
+                    }
+                }
+                Statement::Print { args, .. } => {
+                    args.iter().try_for_each(|arg| self.check_print_argument(arg))?
+                }
+                Statement::Send(expr) => self.check_expression(expr)?,
+                Statement::Function { name, params, body, captures, .. } => {
+                    self.locals = params
+                        .iter()
+                        .map(|(param, _)| param)
+                        .chain(captures)
+                        .map(|name| (name.clone(), None))
+                        .collect();
+                    for (name, value) in assignments(body) {
+                        let arity = match value {
+                            Expression::FunctionRef(function) | Expression::Closure { function, .. } => {
+                                self.signatures.get(function).map(|(params, _)| params.len())
+                            }
+                            _ => None,
+                        };
+                        match self.locals.get(name) {
+                            Some(known) if *known != arity => self.locals.insert(name.to_string(), None),
+                            Some(_) => None,
+                            None => self.locals.insert(name.to_string(), arity),
+                        };
+                    }
+                    let capture_types = self.capture_types.get(name).cloned().unwrap_or_default();
+                    self.types = params.iter().map(|(param, ty)| (param.clone(), ty.unwrap_or(Type::Int))).collect();
+                    for (i, capture) in captures.iter().enumerate() {
+                        self.types.insert(capture.clone(), capture_types.get(i).copied().unwrap_or(Type::Int));
+                    }
+                    self.check_statements(body)?;
+                    // Lifted anonymous functions send their body, which has to
+                    // be an integer like the result of any function value
+                    if let [Statement::Send(expr)] = body.as_slice() {
+                        let ty = self.expr_type(expr);
+                        if name.starts_with("lambda__") && ty != Type::Int {
+                            return Err(format!("Anonymous function body {} is {}, but function values return int", expr, ty));
+                        }
+                    }
+                }
+                Statement::FunctionCall { name, args } => self.check_call(name, args)?,
+                Statement::Extern { .. } | Statement::Use(_) | Statement::Inlined { .. } => {}
+                Statement::If { condition, then_body, else_body } => {
+                    self.check_expression(condition)?;
+                    self.check_statements(then_body)?;
+                    if let Some(else_body) = else_body {
+                        self.check_statements(else_body)?;
+                    }
+                }
+                Statement::While { condition, body } => {
+                    self.check_expression(condition)?;
+                    self.check_statements(body)?;
+                }
+                Statement::Assert { condition, message, location, .. } => {
+                    self.check_expression(condition)?;
+                    if let Some(message) = message {
+                        self.check_expression(message)?;
+                        if self.expr_type(message) != Type::Str {
+                            return Err(format!("Assertion message at {} must be a string", location));
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn check_expression(&mut self, expr: &Expression) -> Result<(), String> {
+        match expr {
+            Expression::Variable(name) => {
+                if !self.types.contains_key(name) {
+                    return Err(format!("Variable {} is not defined", name));
+                }
+                Ok(())
+            }
+            Expression::BinaryOp { left, op, right, .. } => {
+                self.check_expression(left)?;
+                self.check_expression(right)?;
+                let (left_ty, right_ty) = (self.expr_type(left), self.expr_type(right));
+                if left_ty != Type::Str && right_ty != Type::Str {
+                    return Ok(());
+                }
+                if left_ty != right_ty {
+                    return Err(format!("Cannot combine {} and {} with '{}'; convert with ~str()", left_ty, right_ty, op));
+                }
+                if matches!(op, BinaryOperator::Sub | BinaryOperator::Mul | BinaryOperator::Div) {
+                    return Err(format!("Operator '{}' is not defined for strings", op));
+                }
+                Ok(())
+            }
+            Expression::FunctionCall { name, args } => self.check_call(name, args),
+            Expression::FunctionRef(name) | Expression::Closure { function: name, .. } => {
+                if builtins::arity(name).is_some() {
+                    return Err(format!("Builtin {} cannot be used as a value", name));
+                } else if !self.signatures.contains_key(name) {
+                    return Err(format!("Reference to undefined function {}", name));
+                } else if !self.addressable.contains(name) {
+                    return Err(format!(
+                        "{} cannot be used as a value, only functions returning int without float parameters can",
+                        name
+                    ));
+                }
+                if let Expression::Closure { captures, .. } = expr {
+                    let mut types = Vec::new();
+                    for capture in captures {
+                        let ty = self.types.get(capture).ok_or_else(|| format!("Variable {} is not defined", capture))?;
+                        types.push(*ty);
+                    }
+                    self.capture_types.insert(name.clone(), types);
+                }
+                Ok(())
+            }
+            Expression::Index { value, index } => {
+                self.check_expression(value)?;
+                self.check_expression(index)?;
+                if self.expr_type(value) != Type::Str {
+                    return Err("Only strings can be indexed".into());
+                }
+                Ok(())
+            }
+            Expression::Format(parts) => {
+                if self.freestanding {
+                    return Err("Interpolated strings used as values need snprintf, which --freestanding does not link".into());
+                }
+                self.check_format_parts(parts)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Literal text and interpolated strings given to print are written
+    // directly; anything else is a value printed with the default format
+    fn check_print_argument(&mut self, arg: &Expression) -> Result<(), String> {
+        match arg {
+            Expression::StringLiteral(_) => Ok(()),
+            Expression::Format(parts) => self.check_format_parts(parts),
+            _ => self.check_format_value(arg, &FormatSpec::default()),
+        }
+    }
+
+    fn check_format_parts(&mut self, parts: &[FormatPart]) -> Result<(), String> {
+        parts.iter().try_for_each(|part| match part {
+            FormatPart::Value { expr, spec } => self.check_format_value(expr, spec),
+            FormatPart::Text(_) => Ok(()),
+        })
+    }
+
+    fn check_format_value(&mut self, expr: &Expression, spec: &FormatSpec) -> Result<(), String> {
+        self.check_expression(expr)?;
+        let ty = self.expr_type(expr);
+        if let Some(kind) = spec.kind {
+            let kinds = match ty {
+                Type::Int => "dxXo",
+                Type::Float => "fe",
+                Type::Str => "s",
+            };
+            if !kinds.contains(kind) {
+                return Err(format!("Format type '{}' cannot be used with {} value {}", kind, ty, expr));
+            }
+        }
+        // The freestanding runtime only writes integers and strings as they are
+        if self.freestanding && !matches!(printf_conversion(spec, ty).as_str(), "%ld" | "%s") {
+            return Err("Floats and format specifications need printf, which --freestanding does not link".into());
+        }
+        Ok(())
+    }
+
+    fn check_call(&mut self, name: &str, args: &[Expression]) -> Result<(), String> {
+        if let Some(arity) = builtins::arity(name) {
+            if arity != args.len() {
+                return Err(format!(
+                    "Builtin {} expects {} argument(s), got {}",
+                    name,
+                    arity,
+                    args.len()
+                ));
+            }
+            args.iter().try_for_each(|arg| self.check_expression(arg))?;
+            return self.check_builtin(name, args);
+        }
+
+        if let Some(arity) = self.locals.get(name) {
+            if let Some(arity) = arity.filter(|&arity| arity != args.len()) {
+                return Err(format!(
+                    "Function value {} expects {} argument(s), got {}",
+                    name,
+                    arity,
+                    args.len()
+                ));
+            }
+            for arg in args {
+                self.check_expression(arg)?;
+                if self.expr_type(arg) == Type::Float {
+                    return Err(format!("Cannot pass a float through function value {}", name));
+                }
+            }
+            return Ok(());
+        }
+
+        let (params, _) = self
+            .signatures
+            .get(name)
+            .cloned()
+            .ok_or_else(|| format!("Call to undefined function {}", name))?;
+
+        if params.len() != args.len() {
+            return Err(format!(
+                "Function {} expects {} argument(s), got {}",
+                name,
+                params.len(),
+                args.len()
+            ));
+        }
+
+        for (i, (param, arg)) in params.iter().zip(args).enumerate() {
+            self.check_expression(arg)?;
+            let found = self.expr_type(arg);
+            match param {
+                // Integers are widened when passed to float parameters
+                Some(expected) if *expected != found && !(*expected == Type::Float && found == Type::Int) => {
+                    return Err(format!(
+                        "Argument {} of {} expects {:?}, got {:?}",
+                        i + 1,
+                        name,
+                        expected,
+                        found
+                    ));
+                }
+                None if found == Type::Float => {
+                    return Err(format!("Cannot pass a float to non-float parameter {} of {}", i + 1, name));
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn check_builtin(&self, name: &str, args: &[Expression]) -> Result<(), String> {
+        match name {
+            "len" if self.expr_type(&args[0]) != Type::Str => Err("len expects a string".into()),
+            "read_int" | "read_line" | "eof" if self.freestanding => {
+                Err(format!("{} needs the C library, which --freestanding does not link", name))
+            }
+            "str" if self.freestanding && self.expr_type(&args[0]) == Type::Float => {
+                Err("Converting a float to a string needs the C library, which --freestanding does not link".into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn expr_type(&self, expr: &Expression) -> Type {
+        types::expr_type(expr, &self.types, &self.signatures)
+    }
+}
@@ -0,0 +1,161 @@
+use std::collections::HashSet;
+
+use crate::ast::{assignments, Expression, FormatPart, Statement, Type};
+
+// Closure conversion: every `fn(params) => body` is lifted into a top-level
+// function named `lambda__<n>` and replaced by a Closure that lists the
+// variables of the enclosing function it uses. Those are copied into a heap
+// record when the closure is created and loaded back by the lifted function.
+
+struct Converter {
+    lifted: Vec<Statement>,
+    count: usize,
+}
+
+pub fn convert_closures(program: Vec<Statement>) -> Result<Vec<Statement>, String> {
+    let mut converter = Converter { lifted: Vec::new(), count: 0 };
+    let mut program = program;
+
+    for stmt in &mut program {
+        if let Statement::Function { params, body, .. } = stmt {
+            let scope: HashSet<String> = params
+                .iter()
+                .map(|(param, _)| param.clone())
+                .chain(assignments(body).into_iter().map(|(name, _)| name.to_string()))
+                .collect();
+            converter.convert_statements(body, &scope)?;
+        }
+    }
+
+    // Inner functions are lifted before the ones containing them; the code
+    // generator needs to see where a closure is created before its body
+    converter.lifted.reverse();
+    program.extend(converter.lifted);
+    Ok(program)
+}
+
+impl Converter {
+    fn convert_statements(&mut self, body: &mut [Statement], scope: &HashSet<String>) -> Result<(), String> {
+        for stmt in body {
+            match stmt {
+                Statement::Assign { value, .. } => self.convert_expression(value, scope)?,
+                Statement::Print { args, .. } | Statement::FunctionCall { args, .. } => {
+                    for arg in args {
+                        self.convert_expression(arg, scope)?;
+                    }
+                }
+                Statement::Send(expr) => self.convert_expression(expr, scope)?,
+                Statement::If { condition, then_body, else_body } => {
+                    self.convert_expression(condition, scope)?;
+                    self.convert_statements(then_body, scope)?;
+                    if let Some(else_body) = else_body {
+                        self.convert_statements(else_body, scope)?;
+                    }
+                }
+                Statement::While { condition, body } => {
+                    self.convert_expression(condition, scope)?;
+                    self.convert_statements(body, scope)?;
+                }
+                Statement::Assert { condition, message, .. } => {
+                    self.convert_expression(condition, scope)?;
+                    if let Some(message) = message {
+                        self.convert_expression(message, scope)?;
+                    }
+                }
+                Statement::Function { .. } | Statement::Extern { .. } | Statement::Use(_) | Statement::Inlined { .. } => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn convert_expression(&mut self, expr: &mut Expression, scope: &HashSet<String>) -> Result<(), String> {
+        match expr {
+            Expression::Lambda { params, body } => {
+                let names: HashSet<String> = params.iter().map(|(param, _)| param.clone()).collect();
+                let inner: HashSet<String> = scope.union(&names).cloned().collect();
+                self.convert_expression(body, &inner)?;
+
+                let mut used = Vec::new();
+                uses(body, &mut used);
+                let mut captures = Vec::new();
+                for (name, is_call) in used {
+                    if names.contains(&name) || captures.contains(&name) {
+                        continue;
+                    }
+                    if scope.contains(&name) {
+                        captures.push(name);
+                    } else if !is_call {
+                        return Err(format!("Unknown variable {} in anonymous function", name));
+                    }
+                }
+
+                let function = format!("lambda__{}", self.count);
+                self.count += 1;
+                self.lifted.push(Statement::Function {
+                    name: function.clone(),
+                    params: params.clone(),
+                    returns: Type::Int,
+                    body: vec![Statement::Send(*body.clone())],
+                    captures: captures.clone(),
+                    attributes: Vec::new(),
+                });
+                *expr = Expression::Closure { function, captures };
+                Ok(())
+            }
+            Expression::BinaryOp { left, right, .. } => {
+                self.convert_expression(left, scope)?;
+                self.convert_expression(right, scope)
+            }
+            Expression::FunctionCall { args, .. } => {
+                for arg in args {
+                    self.convert_expression(arg, scope)?;
+                }
+                Ok(())
+            }
+            Expression::Index { value, index } => {
+                self.convert_expression(value, scope)?;
+                self.convert_expression(index, scope)
+            }
+            Expression::Format(parts) => {
+                for part in parts {
+                    if let FormatPart::Value { expr, .. } = part {
+                        self.convert_expression(expr, scope)?;
+                    }
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+// Names an expression refers to, in order of appearance: variables, and call
+// targets flagged `true` since those may also be functions rather than values
+fn uses(expr: &Expression, found: &mut Vec<(String, bool)>) {
+    match expr {
+        Expression::Variable(name) => found.push((name.clone(), false)),
+        Expression::BinaryOp { left, right, .. } => {
+            uses(left, found);
+            uses(right, found);
+        }
+        Expression::FunctionCall { name, args } => {
+            found.push((name.clone(), true));
+            args.iter().for_each(|arg| uses(arg, found));
+        }
+        Expression::Index { value, index } => {
+            uses(value, found);
+            uses(index, found);
+        }
+        Expression::Format(parts) => {
+            for part in parts {
+                if let FormatPart::Value { expr, .. } = part {
+                    uses(expr, found);
+                }
+            }
+        }
+        Expression::Closure { captures, .. } => {
+            found.extend(captures.iter().map(|capture| (capture.clone(), false)));
+        }
+        _ => {}
+    }
+}
@@ -1,10 +1,102 @@
 use std::collections::HashMap;
 
-use crate::ast::{Statement, Expression};
+use crate::aarch64;
+use crate::ast::{BinaryOperator, FormatSpec, Location, Statement, Expression, Type};
+use crate::builtins;
+use crate::ir::{self, Inst, Operand, PrintPart, Terminator, VReg};
+use crate::passes;
+use crate::regalloc::{self, Allocation, Assignment, Registers};
+use crate::runtime;
+use crate::ssa;
+use crate::stack::StackMachine;
+use crate::types::{self, Signatures};
 use std::slice::Iter;
 use std::{self, iter::Peekable};
 
+// What integer +, - and * do when the result does not fit in 64 bits
+#[derive(Clone, Copy, PartialEq, Default)]
+pub enum Overflow {
+    #[default]
+    Wrap,
+    Trap,
+}
+
+// The platform the program is for. The x86-64 assembly is the same for both
+// systems; the object file written with --emit=obj is Mach-O or ELF. AArch64
+// code is generated by src/aarch64.rs.
+#[derive(Clone, Copy, PartialEq, Default)]
+pub enum Target {
+    #[default]
+    X86_64MacOs,
+    X86_64Linux,
+    Aarch64Linux,
+}
+
+pub struct Options {
+    pub overflow: Overflow,
+    pub target: Target,
+    // Linux only: no C library, the program starts at its own `_start` and
+    // prints through system calls
+    pub freestanding: bool,
+    // Picks the default passes; at 2 integer functions also keep their
+    // values in registers
+    pub opt_level: u8,
+    // Take register allocated functions through SSA form on the way
+    pub ssa: bool,
+    // The optional passes to run, by name, in order
+    pub passes: Vec<String>,
+    // A pass whose output is printed, from --print-after
+    pub print_after: Option<String>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            overflow: Overflow::default(),
+            target: Target::default(),
+            freestanding: false,
+            opt_level: 1,
+            ssa: false,
+            passes: passes::default_passes(1),
+            print_after: None,
+        }
+    }
+}
+
+// Registers the allocator hands out. rax, rcx and rdx stay free as scratch
+// registers, and the argument registers are left alone so arguments can be
+// moved into place in any order.
+const ARG_REGS: [&str; 6] = ["rdi", "rsi", "rdx", "rcx", "r8", "r9"];
+
+const REGISTERS: Registers = Registers {
+    caller_saved: &["r10", "r11"],
+    callee_saved: &["rbx", "r12", "r13", "r14", "r15"],
+};
+
+// Where each virtual register of a register allocated function lives
+struct Frame {
+    allocation: Allocation,
+    spill_offsets: Vec<i32>,
+}
+
+impl Frame {
+    fn location(&self, vreg: VReg) -> String {
+        match self.allocation.assignments[&vreg] {
+            Assignment::Register(reg) => reg.to_string(),
+            Assignment::Spilled(slot) => format!("qword [rbp - {}]", self.spill_offsets[slot]),
+        }
+    }
+
+    fn operand(&self, operand: Operand) -> String {
+        match operand {
+            Operand::Reg(vreg) => self.location(vreg),
+            Operand::Imm(value) => value.to_string(),
+        }
+    }
+}
+
 pub struct Compiler {
+    options: Options,
     offset_map: HashMap<String, i32>,
     assem: Vec<String>,
     rodata: Vec<String>,
@@ -13,12 +105,36 @@ pub struct Compiler {
     var_offset: i32,
     label_count: i32,
     epilogue_label: String,
+    externs: Vec<String>,
+    // Externs returning a C int, which leaves the upper half of rax undefined
+    int_externs: Vec<String>,
+    // Parameter and return types of every function and extern
+    signatures: Signatures,
+    // Types of the variables in offset_map, fixed by their first assignment
+    var_types: HashMap<String, Type>,
+    return_type: Type,
+    // Runtime routines referenced so far, emitted after the program
+    runtime_used: Vec<&'static str>,
+    // Number of 8-byte temporaries currently pushed, used to keep calls 16-byte aligned
+    stack_depth: i32,
+    // Types of the variables each anonymous function captures, recorded where
+    // its closure is created
+    capture_types: HashMap<String, Vec<Type>>,
+    // Function being compiled, its parameter slots and the label after its
+    // prologue, where self tail calls jump back to
+    current_function: String,
+    param_slots: Vec<i32>,
+    body_label: String,
+    // Where a `send` in the inlined body being compiled goes: the label after
+    // the body, the slot of its result and the type it returns
+    inline_exit: Option<(String, i32, Type)>,
 }
 
 
 impl Compiler {
-    pub fn new() -> Compiler {
+    pub fn new(options: Options) -> Compiler {
         Compiler {
+            options,
             offset_map: HashMap::new(),
             assem: Vec::new(),
             rodata: Vec::new(),
@@ -27,66 +143,150 @@ impl Compiler {
             var_offset: 8,
             label_count: 0,
             epilogue_label: String::new(),
+            externs: Vec::new(),
+            int_externs: Vec::new(),
+            signatures: HashMap::new(),
+            var_types: HashMap::new(),
+            return_type: Type::Int,
+            runtime_used: Vec::new(),
+            stack_depth: 0,
+            capture_types: HashMap::new(),
+            current_function: String::new(),
+            param_slots: Vec::new(),
+            body_label: String::new(),
+            inline_exit: None,
         }
     }
-    pub fn compile(&mut self, ast: Vec<Statement>) -> Vec<String> {
+    pub fn compile(&mut self, ast: Vec<Statement>) -> Result<Vec<String>, String> {
+        self.collect_signatures(&ast);
+        if self.options.target == Target::Aarch64Linux {
+            return self.compile_aarch64(&ast);
+        }
         let mut iter = ast.iter().peekable();
-        self.compiler(&mut iter);
+        self.compiler(&mut iter)?;
         
         let mut result = Vec::new();
         result.extend(self.emit_data().clone());
-        result.extend(self.assem.clone());
+        result.extend(passes::run_asm(self.assem.clone(), &self.options)?);
+        for name in &self.runtime_used {
+            result.extend(runtime::routine(name, self.options.freestanding).code.lines().skip(1).map(String::from));
+        }
+        if self.options.freestanding {
+            result.extend(runtime::START.lines().skip(1).map(String::from));
+        }
 
-       result 
+        Ok(result)
     }
 
-    fn new_label(&mut self, label: &str) -> String {
-        let label = format!("{}_{}", label, self.label_count);
-        self.label_count += 1;
-        label
+    // Textual IR of every function, for --emit=ir
+    pub fn emit_ir(&mut self, ast: Vec<Statement>) -> Result<Vec<String>, String> {
+        self.collect_signatures(&ast);
+        let mut result = Vec::new();
+        for stmt in &ast {
+            if let Statement::Function { name, .. } = stmt {
+                match self.lower(stmt)? {
+                    // As the code generator gets it, unless asked for SSA form
+                    Some(mut function) => {
+                        if !self.options.ssa {
+                            function = self.optimize(function)?;
+                        }
+                        result.extend(function.to_string().lines().map(String::from));
+                    }
+                    None => result.push(format!("; {} is compiled by the stack machine, the IR only covers integer-only functions", name)),
+                }
+            }
+        }
+        Ok(result)
     }
 
-    fn register_string_literal(&mut self, s: &str) -> String {
-        if let Some(label) = self.string_constants.get(s) {
-            return label.clone();
+    // The AArch64 code generator takes the functions that lower to IR as IR,
+    // whatever the optimization level, and the rest as statements for its
+    // stack machine
+    fn compile_aarch64(&self, ast: &[Statement]) -> Result<Vec<String>, String> {
+        let mut functions = Vec::new();
+        for stmt in ast {
+            if let Statement::Function { .. } = stmt {
+                match self.lower(stmt)? {
+                    Some(function) => functions.push(aarch64::Function::Lowered(self.optimize(function)?)),
+                    None => functions.push(aarch64::Function::Stack(stmt)),
+                }
+            }
         }
+        Ok(aarch64::compile(&functions, &self.signatures, &self.externs, &self.int_externs, &self.options))
+    }
 
-        let label = format!("str_{}", self.string_count);
-        self.string_count += 1;
-        self.string_constants.insert(s.to_string(), label.clone());
-
-        self.rodata.push(format!("{}: db \"{}\", 0", label, s.replace("\"", "\\\"")));
-        label
+    // Collect signatures first so calls can see them regardless of order
+    fn collect_signatures(&mut self, ast: &[Statement]) {
+        self.signatures = types::signatures(ast);
+        for stmt in ast {
+            if let Statement::Extern { name, returns, long, .. } = stmt {
+                self.externs.push(name.clone());
+                if *returns == Type::Int && !long {
+                    self.int_externs.push(name.clone());
+                }
+            }
+        }
     }
+
     
 
-    fn compiler(&mut self, iter: &mut Peekable<Iter<Statement>>) {
+    fn compiler(&mut self, iter: &mut Peekable<Iter<Statement>>) -> Result<(), String> {
         while let Some(stmt) = iter.peek() {
-            match stmt {
-                Statement::Function { .. } => {
-                    self.compile_function(iter);
-                }
-                _ => {
+            if let Statement::Function { .. } = stmt {
+                let lowered = if self.options.opt_level >= 2 { self.lower(stmt)? } else { None };
+                match lowered {
+                    Some(function) => {
+                        let function = self.optimize(function)?;
+                        self.compile_allocated_function(&function);
+                    }
+                    None => self.compile_function(iter),
                 }
             }
             iter.next();
         }
+        Ok(())
     }
 
 
     fn emit_data(&mut self) -> Vec<String> {
+        let freestanding = self.options.freestanding;
         let mut data = Vec::new();
         // Allows the use of printf if gcc is used to link
-        data.push("extern _printf".into());
+        let mut externs = Vec::new();
+        if !freestanding {
+            data.push("extern _printf".to_string());
+            externs.push("printf");
+        }
+        // User declared C functions and those the runtime routines need
+        let runtime_externs = self.runtime_used.iter().flat_map(|name| runtime::routine(name, freestanding).externs);
+        for name in self.externs.iter().map(String::as_str).chain(runtime_externs.copied()) {
+            if !externs.contains(&name) {
+                externs.push(name);
+                data.push(format!("extern _{}", name));
+            }
+        }
         data.push("section .rodata".into());
-        // String required for printf to print ints
-        data.push("fmt: db \"%ld\", 10, 0".to_string());
-
-        // String required for printf to print string 
-        data.push("fmt_str: db \"%s\", 10, 0".to_string());
-
         // Inject all string literals here
         data.extend(self.rodata.clone());
+        let mut bss = Vec::new();
+        for name in &self.runtime_used {
+            let routine = runtime::routine(name, freestanding);
+            // Routines sharing state declare the same lines, so keep one copy
+            for line in routine.data {
+                if !data.iter().any(|existing| existing == line) {
+                    data.push(line.to_string());
+                }
+            }
+            for line in routine.bss {
+                if !bss.contains(line) {
+                    bss.push(*line);
+                }
+            }
+        }
+        if !bss.is_empty() {
+            data.push("section .bss".into());
+            data.extend(bss.iter().map(|line| line.to_string()));
+        }
 
         // main and text section
         data.push("global _main".into());
@@ -97,9 +297,11 @@ impl Compiler {
     }
 
     fn compile_function(&mut self, iter: &mut Peekable<Iter<Statement>>) {
-        if let Some(Statement::Function { name, params, body }) = iter.peek() {
+        if let Some(Statement::Function { name, params, returns, body, captures, .. }) = iter.peek() {
             // Save outer scope
             let saved_offset_map = std::mem::take(&mut self.offset_map);
+            let saved_var_types = std::mem::take(&mut self.var_types);
+            self.return_type = *returns;
             let saved_var_offset = self.var_offset;
             let saved_epilogue_label = std::mem::take(&mut self.epilogue_label);
             self.var_offset = 8;
@@ -116,24 +318,52 @@ impl Compiler {
             let sub_rsp_idx = self.assem.len();
             self.assem.push("    sub rsp, 0".into());
 
+            // Copy captured variables out of the closure record before anything clobbers r10
+            let capture_types = self.capture_types.get(name).cloned().unwrap_or_default();
+            for (i, capture) in captures.iter().enumerate() {
+                let offset = self.temp_slot();
+                self.assem.push(format!("    mov rax, [r10 + {}]", (i + 1) * 8));
+                self.assem.push(format!("    mov [rbp - {}], rax", offset));
+                self.offset_map.insert(capture.clone(), offset);
+                self.var_types.insert(capture.clone(), capture_types.get(i).copied().unwrap_or(Type::Int));
+            }
+
             // Spill params to stack slots
-            self.compile_params(params);
+            if name == "main" && params.len() == 1 {
+                // main(args) receives argc/argv as a list of strings
+                self.call_runtime("bonk_args");
+                let offset = self.temp_slot();
+                self.offset_map.insert(params[0].0.clone(), offset);
+                self.var_types.insert(params[0].0.clone(), Type::Int);
+                self.assem.push(format!("    mov [rbp - {}], rax", offset));
+            } else {
+                self.compile_params(params);
+            }
+            self.current_function = name.clone();
+            self.param_slots = params.iter().map(|(param, _)| self.offset_map[param]).collect();
+            self.body_label = self.new_label("body");
+            self.assem.push(format!("{}:", self.body_label));
 
-            self.compile_statement(body);
+            self.compile_statements(body);
 
             // Patch frame size (round up to 16-byte alignment)
-            let frame_size = ((self.var_offset as usize + 15) / 16) * 16;
+            let frame_size = (self.var_offset as usize).div_ceil(16) * 16;
             self.assem[sub_rsp_idx] = format!("    sub rsp, {}", frame_size);
 
             // Epilogue — default return 0, then shared cleanup
             self.assem.push("    mov rax, 0".into());
             self.assem.push(format!("{}:", self.epilogue_label));
+            if *returns == Type::Float {
+                // Floats are returned in xmm0
+                self.assem.push("    movq xmm0, rax".into());
+            }
             self.assem.push("    mov rsp, rbp".into());
             self.assem.push("    pop rbp".into());
             self.assem.push("    ret".into());
 
             // Restore outer scope
             self.offset_map = saved_offset_map;
+            self.var_types = saved_var_types;
             self.var_offset = saved_var_offset;
             self.epilogue_label = saved_epilogue_label;
         } else {
@@ -141,7 +371,741 @@ impl Compiler {
         }
     }
 
-    fn compile_statement(&mut self, body: &[Statement]) {
+    // Lowers a function to verified IR, in SSA form with --ssa, or returns
+    // None when the stack machine has to compile it
+    fn lower(&self, function: &Statement) -> Result<Option<ir::Function>, String> {
+        let Some(mut function) = ir::lower_function(function, &self.signatures, &self.externs) else {
+            return Ok(None);
+        };
+        ir::verify(&function)?;
+        if self.options.ssa {
+            ssa::construct(&mut function);
+            ir::verify(&function)?;
+        }
+        Ok(Some(function))
+    }
+
+    // Takes the function out of SSA form and runs the selected IR passes
+    fn optimize(&self, mut function: ir::Function) -> Result<ir::Function, String> {
+        ssa::destruct(&mut function);
+        ir::verify(&function)?;
+        passes::run_ir(function, &self.options)
+    }
+
+    // Compiles a function lowered to IR, with each virtual register in the
+    // machine register or frame slot the allocator chose
+    fn compile_allocated_function(&mut self, function: &ir::Function) {
+        let allocation = regalloc::allocate(function, &REGISTERS);
+        let saved_var_offset = self.var_offset;
+        self.var_offset = 8;
+        let saves: Vec<(&str, i32)> = allocation.callee_saved.iter().map(|&reg| (reg, self.temp_slot())).collect();
+        let spill_offsets = (0..allocation.spill_slots).map(|_| self.temp_slot()).collect();
+        let frame = Frame { allocation, spill_offsets };
+        let epilogue = self.new_label("epilogue");
+        let labels: Vec<String> = function.blocks.iter().map(|_| self.new_label("block")).collect();
+
+        if function.name != "main" {
+            self.assem.push(format!("global _{}", function.name));
+        }
+        self.assem.push(format!("_{}:", function.name));
+        self.assem.push("    push rbp".into());
+        self.assem.push("    mov rbp, rsp".into());
+        let frame_size = (self.var_offset as usize).div_ceil(16) * 16;
+        self.assem.push(format!("    sub rsp, {}", frame_size));
+        for (reg, offset) in &saves {
+            self.assem.push(format!("    mov [rbp - {}], {}", offset, reg));
+        }
+        for (param, reg) in function.params.iter().zip(ARG_REGS) {
+            self.emit_move(&frame.location(*param), reg);
+        }
+
+        // Numbered like the allocator's points
+        let mut point = 0;
+        for (index, block) in function.blocks.iter().enumerate() {
+            self.assem.push(format!("{}:", labels[index]));
+            for inst in &block.insts {
+                self.compile_allocated_inst(inst, &frame, &frame.allocation.saved_across.get(&point).cloned().unwrap_or_default());
+                point += 1;
+            }
+            // Falling through to the next block saves a jump
+            let next = labels.get(index + 1);
+            match &block.terminator {
+                Terminator::Jump(target) => {
+                    if Some(&labels[*target]) != next {
+                        self.assem.push(format!("    jmp {}", labels[*target]));
+                    }
+                }
+                Terminator::Branch { op, lhs, rhs, then_block, else_block } => {
+                    self.emit_compare(&frame, *lhs, *rhs);
+                    if Some(&labels[*else_block]) == next {
+                        self.assem.push(format!("    j{} {}", condition_code(op), labels[*then_block]));
+                    } else {
+                        self.assem.push(format!("    j{} {}", inverse_condition_code(op), labels[*else_block]));
+                        if Some(&labels[*then_block]) != next {
+                            self.assem.push(format!("    jmp {}", labels[*then_block]));
+                        }
+                    }
+                }
+                Terminator::Return(value) => {
+                    self.emit_move("rax", &frame.operand(*value));
+                    self.assem.push(format!("    jmp {}", epilogue));
+                }
+                Terminator::TailCall { name, args } => {
+                    for (reg, arg) in ARG_REGS.iter().zip(args) {
+                        self.emit_move(reg, &frame.operand(*arg));
+                    }
+                    for (reg, offset) in &saves {
+                        self.assem.push(format!("    mov {}, [rbp - {}]", reg, offset));
+                    }
+                    self.assem.push("    mov rsp, rbp".into());
+                    self.assem.push("    pop rbp".into());
+                    self.assem.push("    mov rax, 0".into());
+                    self.assem.push(format!("    jmp _{}", name));
+                }
+            }
+            point += 1;
+        }
+
+        self.assem.push(format!("{}:", epilogue));
+        for (reg, offset) in &saves {
+            self.assem.push(format!("    mov {}, [rbp - {}]", reg, offset));
+        }
+        self.assem.push("    mov rsp, rbp".into());
+        self.assem.push("    pop rbp".into());
+        self.assem.push("    ret".into());
+        self.var_offset = saved_var_offset;
+    }
+
+    // `saved` are the caller-saved registers to preserve around a call
+    fn compile_allocated_inst(&mut self, inst: &Inst, frame: &Frame, saved: &[&str]) {
+        match inst {
+            Inst::Copy { dst, src } => self.emit_move(&frame.location(*dst), &frame.operand(*src)),
+            Inst::Binary { op: BinaryOperator::Div, dst, lhs, rhs, location } => {
+                let nonzero = self.new_label("div_nonzero");
+                let divide = self.new_label("div");
+                let done = self.new_label("div_done");
+                self.emit_move("rcx", &frame.operand(*rhs));
+                self.assem.push("    cmp rcx, 0".into());
+                self.assem.push(format!("    jne {}", nonzero));
+                self.emit_trap("division by zero", location);
+                self.assem.push(format!("{}:", nonzero));
+                self.emit_move("rax", &frame.operand(*lhs));
+                // idiv faults on i64::MIN / -1, so negate instead
+                self.assem.push("    cmp rcx, -1".into());
+                self.assem.push(format!("    jne {}", divide));
+                self.assem.push("    neg rax".into());
+                self.check_overflow("/", location);
+                self.assem.push(format!("    jmp {}", done));
+                self.assem.push(format!("{}:", divide));
+                self.assem.push("    cqo".into());
+                self.assem.push("    idiv rcx".into());
+                self.assem.push(format!("{}:", done));
+                self.emit_move(&frame.location(*dst), "rax");
+            }
+            Inst::Binary { op, dst, lhs, rhs, .. } if op.is_comparison() => {
+                self.emit_compare(frame, *lhs, *rhs);
+                self.assem.push(format!("    set{} al", condition_code(op)));
+                self.assem.push("    movzx rax, al".into());
+                self.emit_move(&frame.location(*dst), "rax");
+            }
+            Inst::Binary { op, dst, lhs, rhs, location } => {
+                let (instruction, symbol) = match op {
+                    BinaryOperator::Add => ("add", "+"),
+                    BinaryOperator::Sub => ("sub", "-"),
+                    BinaryOperator::Mul => ("imul", "*"),
+                    _ => unreachable!("{:?} is handled above", op),
+                };
+                let rhs = self.allocated_operand(frame, *rhs, "rcx");
+                let lhs = frame.operand(*lhs);
+                let dst = frame.location(*dst);
+                // Work in the destination register unless that would overwrite rhs first
+                let target = if !is_memory(&dst) && (lhs == dst || rhs != dst) { dst.clone() } else { "rax".into() };
+                self.emit_move(&target, &lhs);
+                self.assem.push(format!("    {} {}, {}", instruction, target, rhs));
+                self.check_overflow(symbol, location);
+                self.emit_move(&dst, &target);
+            }
+            Inst::Shift { op, dst, src, amount } => {
+                let dst = frame.location(*dst);
+                let target = if is_memory(&dst) { "rax".to_string() } else { dst.clone() };
+                self.emit_move(&target, &frame.operand(*src));
+                self.assem.push(format!("    {} {}, {}", ir::shift_name(*op), target, amount));
+                self.emit_move(&dst, &target);
+            }
+            Inst::Call { dst, name, args } => {
+                for reg in saved {
+                    self.push(reg);
+                }
+                for (reg, arg) in ARG_REGS.iter().zip(args) {
+                    self.emit_move(reg, &frame.operand(*arg));
+                }
+                self.assem.push("    mov rax, 0".into());
+                self.emit_call(name);
+                self.extend_int_result(name);
+                for reg in saved.iter().rev() {
+                    self.pop(reg);
+                }
+                if let Some(dst) = dst {
+                    self.emit_move(&frame.location(*dst), "rax");
+                }
+            }
+            Inst::Print { parts, newline } => {
+                let mut format = String::new();
+                let mut values = Vec::new();
+                for part in parts {
+                    match part {
+                        PrintPart::Text(text) => format.push_str(&text.replace('%', "%%")),
+                        PrintPart::Value(value, spec) => {
+                            format.push_str(&printf_conversion(spec, Type::Int));
+                            values.push(*value);
+                        }
+                    }
+                }
+                if *newline {
+                    format.push('\n');
+                }
+                for reg in saved {
+                    self.push(reg);
+                }
+                if self.options.freestanding {
+                    let operands: Vec<String> = values.iter().map(|&value| frame.operand(value)).collect();
+                    self.emit_writes(&format, &operands);
+                } else {
+                    let fmt_label = self.string_literal(&format);
+                    for (reg, value) in ARG_REGS[1..].iter().zip(values) {
+                        self.emit_move(reg, &frame.operand(value));
+                    }
+                    self.assem.push(format!("    lea rdi, [rel {}]", fmt_label));
+                    self.assem.push("    mov rax, 0".into());
+                    self.emit_call("printf");
+                }
+                for reg in saved.iter().rev() {
+                    self.pop(reg);
+                }
+            }
+        }
+    }
+
+    // Moves between registers, frame slots and immediates, through rax when
+    // x86 has no direct form
+    fn emit_move(&mut self, dst: &str, src: &str) {
+        if dst == src {
+            return;
+        }
+        let wide = src.parse::<i64>().is_ok_and(|value| i32::try_from(value).is_err());
+        if is_memory(dst) && (is_memory(src) || wide) {
+            self.assem.push(format!("    mov rax, {}", src));
+            self.assem.push(format!("    mov {}, rax", dst));
+        } else {
+            self.assem.push(format!("    mov {}, {}", dst, src));
+        }
+    }
+
+    // An operand usable as the source of an arithmetic instruction; immediates
+    // wider than 32 bits are loaded into `scratch` first
+    fn allocated_operand(&mut self, frame: &Frame, operand: Operand, scratch: &str) -> String {
+        match operand {
+            Operand::Imm(value) if i32::try_from(value).is_err() => {
+                self.assem.push(format!("    mov {}, {}", scratch, value));
+                scratch.to_string()
+            }
+            _ => frame.operand(operand),
+        }
+    }
+
+    fn emit_compare(&mut self, frame: &Frame, lhs: Operand, rhs: Operand) {
+        let rhs = self.allocated_operand(frame, rhs, "rcx");
+        let mut left = frame.operand(lhs);
+        if matches!(lhs, Operand::Imm(_)) || (is_memory(&left) && is_memory(&rhs)) {
+            self.emit_move("rax", &left);
+            left = "rax".into();
+        }
+        self.assem.push(format!("    cmp {}, {}", left, rhs));
+    }
+
+    fn compile_call(&mut self, name: &str, args: &[Expression]) {
+        if builtins::arity(name).is_some() {
+            self.compile_builtin(name, args);
+            return;
+        }
+        if let Some(&offset) = self.offset_map.get(name) {
+            self.compile_indirect_call(offset, args);
+            return;
+        }
+        let returns = self.load_arguments(name, args);
+        self.emit_call(name);
+        if returns == Type::Float {
+            self.assem.push("    movq rax, xmm0".into());
+        }
+        self.extend_int_result(name);
+    }
+
+    // A plain function as a value is a closure record with no captures, shared
+    // by every `&name` of that function
+    fn compile_function_ref(&mut self, name: &str) {
+        let record = format!("closure_{}: dq _{}", name, name);
+        if !self.rodata.contains(&record) {
+            self.rodata.push(record);
+        }
+        self.assem.push(format!("    lea rax, [rel closure_{}]", name));
+    }
+
+    // Calls the function value held in a variable. Function values are closure
+    // records whose first word is the code address; the record itself goes in
+    // r10 so anonymous functions can load their captures. Every argument and
+    // the result travel in general purpose registers.
+    fn compile_indirect_call(&mut self, offset: i32, args: &[Expression]) {
+        let arg_regs = ["rdi", "rsi", "rdx", "rcx", "r8", "r9"];
+        for arg in args {
+            self.compile_expression(arg);
+            self.push("rax");
+        }
+        for reg in arg_regs[..args.len()].iter().rev() {
+            self.pop(reg);
+        }
+        self.assem.push(format!("    mov r10, [rbp - {}]", offset));
+        self.assem.push("    mov rax, 0".into());
+        self.emit_call_to("qword [r10]");
+    }
+
+    fn compile_builtin(&mut self, name: &str, args: &[Expression]) {
+        match name {
+            "peek" => {
+                self.compile_expression(&args[0]);
+                self.assem.push("    mov rax, [rax]".into());
+            }
+            "poke" => {
+                self.compile_expression(&args[0]);
+                self.push("rax");
+                self.compile_expression(&args[1]);
+                self.pop("rcx");
+                self.assem.push("    mov [rcx], rax".into());
+            }
+            "float" => {
+                let ty = self.expr_type(&args[0]);
+                self.compile_expression(&args[0]);
+                self.convert(ty, Type::Float);
+            }
+            "int" => {
+                let ty = self.expr_type(&args[0]);
+                self.compile_expression(&args[0]);
+                match ty {
+                    // Truncates toward zero
+                    Type::Float => {
+                        self.assem.push("    movq xmm0, rax".into());
+                        self.assem.push("    cvttsd2si rax, xmm0".into());
+                    }
+                    Type::Str => {
+                        self.assem.push("    mov rdi, rax".into());
+                        self.call_runtime("bonk_str_to_int");
+                    }
+                    Type::Int => {}
+                }
+            }
+            "str" => {
+                let ty = self.expr_type(&args[0]);
+                self.compile_expression(&args[0]);
+                match ty {
+                    Type::Int => {
+                        self.assem.push("    mov rdi, rax".into());
+                        self.call_runtime("bonk_str_from_int");
+                    }
+                    Type::Float => {
+                        self.assem.push("    movq xmm0, rax".into());
+                        self.call_runtime("bonk_str_from_float");
+                    }
+                    Type::Str => {}
+                }
+            }
+            "len" => {
+                self.compile_expression(&args[0]);
+                self.assem.push("    mov rdi, rax".into());
+                self.call_runtime("bonk_str_len");
+            }
+            "read_int" => self.call_runtime("bonk_read_int"),
+            "read_line" => self.call_runtime("bonk_read_line"),
+            "eof" => self.call_runtime("bonk_eof"),
+            "arg" => {
+                self.compile_expression(&args[0]);
+                self.push("rax");
+                self.compile_expression(&args[1]);
+                self.assem.push("    mov rsi, rax".into());
+                self.pop("rdi");
+                self.call_runtime("bonk_arg");
+            }
+            "exit" => {
+                self.compile_expression(&args[0]);
+                self.assem.push("    mov rdi, rax".into());
+                self.call_runtime("bonk_exit");
+            }
+            _ => unreachable!("unknown builtin {}", name),
+        }
+    }
+
+    // Marks a routine, and those it calls, to be emitted after the program
+    fn use_runtime(&mut self, name: &'static str) {
+        if !self.runtime_used.contains(&name) {
+            self.runtime_used.push(name);
+            for &other in runtime::routine(name, self.options.freestanding).uses {
+                self.use_runtime(other);
+            }
+        }
+    }
+
+    fn push(&mut self, reg: &str) {
+        self.assem.push(format!("    push {}", reg));
+        self.stack_depth += 1;
+    }
+
+    fn pop(&mut self, reg: &str) {
+        self.assem.push(format!("    pop {}", reg));
+        self.stack_depth -= 1;
+    }
+
+    // A C int result is sign-extended to the 64-bit integers Bonk works with
+    fn extend_int_result(&mut self, name: &str) {
+        if self.int_externs.iter().any(|ext| ext == name) {
+            self.assem.push("    movsxd rax, eax".into());
+        }
+    }
+
+    fn emit_call(&mut self, name: &str) {
+        self.emit_call_to(&format!("_{}", name));
+    }
+
+    fn emit_call_to(&mut self, target: &str) {
+        // Temporaries pushed by an enclosing expression would leave rsp misaligned
+        let misaligned = self.stack_depth % 2 != 0;
+        if misaligned {
+            self.assem.push("    sub rsp, 8".into());
+        }
+        self.assem.push(format!("    call {}", target));
+        if misaligned {
+            self.assem.push("    add rsp, 8".into());
+        }
+    }
+
+    // Divides rcx by rax into rax, trapping on a zero divisor
+    fn compile_division(&mut self, location: &Location) {
+        let nonzero = self.new_label("div_nonzero");
+        let divide = self.new_label("div");
+        let done = self.new_label("div_done");
+        self.assem.push("    cmp rax, 0".into());
+        self.assem.push(format!("    jne {}", nonzero));
+        self.emit_trap("division by zero", location);
+        self.assem.push(format!("{}:", nonzero));
+        self.assem.push("    xchg rax, rcx".into());
+        // idiv faults on i64::MIN / -1, so negate instead; that overflows the same way
+        self.assem.push("    cmp rcx, -1".into());
+        self.assem.push(format!("    jne {}", divide));
+        self.assem.push("    neg rax".into());
+        self.check_overflow("/", location);
+        self.assem.push(format!("    jmp {}", done));
+        self.assem.push(format!("{}:", divide));
+        self.assem.push("    cqo".into());
+        self.assem.push("    idiv rcx".into());
+        self.assem.push(format!("{}:", done));
+    }
+
+    // Traps if the preceding instruction overflowed, when --overflow=trap is on
+    fn check_overflow(&mut self, op: &str, location: &Location) {
+        if self.options.overflow == Overflow::Wrap {
+            return;
+        }
+        let ok = self.new_label("no_overflow");
+        self.assem.push(format!("    jno {}", ok));
+        self.emit_trap(&format!("integer overflow in '{}'", op), location);
+        self.assem.push(format!("{}:", ok));
+    }
+
+    fn compile_assert(&mut self, condition: &Expression, message: Option<&Expression>, text: &str, location: &Location) {
+        let ok = self.new_label("assert_ok");
+        self.compile_expression(condition);
+        self.assem.push("    cmp rax, 0".into());
+        self.assem.push(format!("    jne {}", ok));
+        match message {
+            Some(message) => {
+                self.compile_expression(message);
+                self.assem.push("    mov rsi, rax".into());
+            }
+            None => {
+                let label = self.string_literal(text);
+                self.assem.push(format!("    lea rsi, [rel {}]", label));
+            }
+        }
+        let label = self.string_literal(&format!("assertion failed at {}: ", location));
+        self.assem.push(format!("    lea rdi, [rel {}]", label));
+        self.call_runtime("bonk_assert_fail");
+        self.assem.push(format!("{}:", ok));
+    }
+
+    fn emit_trap(&mut self, message: &str, location: &Location) {
+        let label = self.string_literal(&format!("runtime error: {} at {}\n", message, location));
+        self.assem.push(format!("    lea rdi, [rel {}]", label));
+        self.call_runtime("bonk_trap");
+    }
+
+    fn compile_float_op(&mut self, left: &Expression, op: &BinaryOperator, right: &Expression) {
+        self.compile_float_operands(left, right);
+        match op {
+            BinaryOperator::Add => self.assem.push("    addsd xmm0, xmm1".into()),
+            BinaryOperator::Sub => self.assem.push("    subsd xmm0, xmm1".into()),
+            BinaryOperator::Mul => self.assem.push("    mulsd xmm0, xmm1".into()),
+            BinaryOperator::Div => self.assem.push("    divsd xmm0, xmm1".into()),
+            BinaryOperator::Eq => {
+                // Unordered (NaN) compares set PF and are never equal
+                self.assem.push("    ucomisd xmm0, xmm1".into());
+                self.assem.push("    sete al".into());
+                self.assem.push("    setnp cl".into());
+                self.assem.push("    and al, cl".into());
+            }
+            BinaryOperator::NEq => {
+                self.assem.push("    ucomisd xmm0, xmm1".into());
+                self.assem.push("    setne al".into());
+                self.assem.push("    setp cl".into());
+                self.assem.push("    or al, cl".into());
+            }
+            // Less-than tests are written as greater-than with swapped
+            // operands so an unordered compare gives false
+            BinaryOperator::Lt => {
+                self.assem.push("    ucomisd xmm1, xmm0".into());
+                self.assem.push("    seta al".into());
+            }
+            BinaryOperator::LtEq => {
+                self.assem.push("    ucomisd xmm1, xmm0".into());
+                self.assem.push("    setae al".into());
+            }
+            BinaryOperator::Gt => {
+                self.assem.push("    ucomisd xmm0, xmm1".into());
+                self.assem.push("    seta al".into());
+            }
+        }
+        if op.is_comparison() {
+            self.assem.push("    movzx rax, al".into());
+        } else {
+            self.assem.push("    movq rax, xmm0".into());
+        }
+    }
+
+    // Jumps to false_label unless the condition of an `if` or `while` holds.
+    // A comparison branches on the flags of its own compare instead of
+    // materialising 0 or 1 and testing that.
+    fn compile_condition(&mut self, condition: &Expression, false_label: &str) {
+        let (left, op, right) = match condition {
+            Expression::BinaryOp { left, op, right, .. } if op.is_comparison() => (left, op, right),
+            _ => {
+                self.compile_expression(condition);
+                self.assem.push("    cmp rax, 0".into());
+                self.assem.push(format!("    je {}", false_label));
+                return;
+            }
+        };
+        if self.expr_type(left) == Type::Str || self.expr_type(right) == Type::Str {
+            self.compile_string_operands(left, right);
+            self.call_runtime("bonk_str_compare");
+            self.assem.push("    cmp rax, 0".into());
+            self.assem.push(format!("    j{} {}", inverse_condition_code(op), false_label));
+        } else if self.expr_type(left) == Type::Float || self.expr_type(right) == Type::Float {
+            self.compile_float_operands(left, right);
+            // As in compile_float_op, an unordered compare (NaN) only satisfies !=
+            match op {
+                BinaryOperator::Eq => {
+                    self.assem.push("    ucomisd xmm0, xmm1".into());
+                    self.assem.push(format!("    jne {}", false_label));
+                    self.assem.push(format!("    jp {}", false_label));
+                }
+                BinaryOperator::NEq => {
+                    let unordered = self.new_label("unordered");
+                    self.assem.push("    ucomisd xmm0, xmm1".into());
+                    self.assem.push(format!("    jp {}", unordered));
+                    self.assem.push(format!("    je {}", false_label));
+                    self.assem.push(format!("{}:", unordered));
+                }
+                BinaryOperator::Lt => {
+                    self.assem.push("    ucomisd xmm1, xmm0".into());
+                    self.assem.push(format!("    jbe {}", false_label));
+                }
+                BinaryOperator::LtEq => {
+                    self.assem.push("    ucomisd xmm1, xmm0".into());
+                    self.assem.push(format!("    jb {}", false_label));
+                }
+                _ => {
+                    self.assem.push("    ucomisd xmm0, xmm1".into());
+                    self.assem.push(format!("    jbe {}", false_label));
+                }
+            }
+        } else {
+            self.compile_expression(left);
+            self.push("rax");
+            self.compile_expression(right);
+            self.pop("rcx");
+            self.assem.push("    cmp rcx, rax".into());
+            self.assem.push(format!("    j{} {}", inverse_condition_code(op), false_label));
+        }
+    }
+
+    fn compile_params(&mut self, params: &[(String, Option<Type>)]) {
+        let regs = ["rdi", "rsi", "rdx", "rcx", "r8", "r9"];
+        let (mut int_index, mut float_index) = (0, 0);
+        for (name, ty) in params {
+            let ty = ty.unwrap_or(Type::Int);
+            let offset = self.var_offset;
+            self.offset_map.insert(name.clone(), offset);
+            self.var_types.insert(name.clone(), ty);
+            self.var_offset += 8;
+            if ty == Type::Float {
+                self.assem.push(format!("    movq [rbp - {}], xmm{}", offset, float_index));
+                float_index += 1;
+            } else {
+                self.assem.push(format!("    mov [rbp - {}], {}", offset, regs[int_index]));
+                int_index += 1;
+            }
+        }
+    }
+
+    fn compile_print(&mut self, args: &[Expression], newline: bool) {
+        let (format, values) = self.print_format(args, newline);
+        if self.options.freestanding {
+            let operands: Vec<String> = values.iter().map(|(slot, _)| format!("qword [rbp - {}]", slot)).collect();
+            self.emit_writes(&format, &operands);
+            return;
+        }
+        let fmt_label = self.string_literal(&format);
+        self.emit_printf_call("printf", &[], &fmt_label, &values);
+    }
+
+    // Prints like printf with --freestanding, where there is none: the text
+    // of the format and each value, from a register, slot or immediate, are
+    // written by the runtime in turn. Only the plain %ld and %s conversions
+    // can be written. The values are pushed first, as the writes clobber the
+    // registers they may be in.
+    fn emit_writes(&mut self, format: &str, values: &[String]) {
+        for operand in values.iter().rev() {
+            if operand.parse::<i64>().is_ok_and(|value| i32::try_from(value).is_err()) {
+                self.assem.push(format!("    mov rax, {}", operand));
+                self.push("rax");
+            } else {
+                self.push(operand);
+            }
+        }
+        let mut text = String::new();
+        let mut rest = format;
+        while let Some(start) = rest.find('%') {
+            text.push_str(&rest[..start]);
+            rest = &rest[start..];
+            if let Some(after) = rest.strip_prefix("%%") {
+                text.push('%');
+                rest = after;
+                continue;
+            }
+            self.emit_write_text(&std::mem::take(&mut text));
+            if let Some(after) = rest.strip_prefix("%ld") {
+                self.pop("rdi");
+                self.call_runtime("bonk_write_int");
+                rest = after;
+            } else if let Some(after) = rest.strip_prefix("%s") {
+                self.pop("rsi");
+                self.assem.push("    mov rdi, 1".into());
+                self.call_runtime("bonk_write_str");
+                rest = after;
+            } else {
+                unreachable!("the checker only lets %ld and %s through with --freestanding");
+            }
+        }
+        text.push_str(rest);
+        self.emit_write_text(&text);
+    }
+
+    fn emit_write_text(&mut self, text: &str) {
+        if text.is_empty() {
+            return;
+        }
+        let label = self.string_literal(text);
+        self.assem.push(format!("    lea rsi, [rel {}]", label));
+        self.assem.push("    mov rdi, 1".into());
+        self.call_runtime("bonk_write_str");
+    }
+
+    fn use_libc(&mut self, name: &str) {
+        if !self.externs.iter().any(|ext| ext == name) {
+            self.externs.push(name.to_string());
+        }
+    }
+
+    fn compile_while(&mut self, condition: &Expression, body: &[Statement]) {
+        let start_label = self.new_label("while_start");
+        let end_label = self.new_label("while_end");
+
+        self.assem.push(format!("{}:", start_label));
+        self.compile_condition(condition, &end_label);
+
+        for stmt in body {
+            self.compile_statements(std::slice::from_ref(stmt));
+        }
+
+        self.assem.push(format!("    jmp {}", start_label));
+        self.assem.push(format!("{}:", end_label));
+    }
+}
+
+impl StackMachine for Compiler {
+    type Slot = i32;
+
+    fn signatures(&self) -> &Signatures {
+        &self.signatures
+    }
+
+    fn variables(&self) -> &HashMap<String, Type> {
+        &self.var_types
+    }
+
+    fn variable_slot(&self, name: &str) -> Option<i32> {
+        self.offset_map.get(name).copied()
+    }
+
+    fn bind(&mut self, name: &str, slot: i32, ty: Type) {
+        self.offset_map.insert(name.to_string(), slot);
+        self.var_types.insert(name.to_string(), ty);
+    }
+
+    // Reserves an unnamed 8-byte slot in the current frame
+    fn temp_slot(&mut self) -> i32 {
+        let offset = self.var_offset;
+        self.var_offset += 8;
+        offset
+    }
+
+    fn new_label(&mut self, label: &str) -> String {
+        let label = format!("{}_{}", label, self.label_count);
+        self.label_count += 1;
+        label
+    }
+
+    fn place_label(&mut self, label: &str) {
+        self.assem.push(format!("{}:", label));
+    }
+
+    fn is_extern(&self, name: &str) -> bool {
+        self.externs.iter().any(|e| e == name)
+    }
+
+    fn return_type(&self) -> Type {
+        self.return_type
+    }
+
+    fn current_function(&self) -> &str {
+        &self.current_function
+    }
+
+    fn param_slots(&self) -> Vec<i32> {
+        self.param_slots.clone()
+    }
+
+    fn set_inline_exit(&mut self, exit: Option<(String, i32, Type)>) -> Option<(String, i32, Type)> {
+        std::mem::replace(&mut self.inline_exit, exit)
+    }
+
+    fn compile_statements(&mut self, body: &[Statement]) {
         for stmt in body {
             match stmt {
                 Statement::Assign { name, value } => {
@@ -151,50 +1115,50 @@ impl Compiler {
                     self.compile_while(condition, body);
                 }
                 Statement::If { condition, then_body, else_body } => {
-                    self.compile_expression(condition);
-                    self.assem.push("    cmp rax, 0".into());
-
                     let end_label = self.new_label("endif");
                     let else_label_opt = else_body.as_ref().map(|_| self.new_label("else"));
 
-                    if let Some(ref else_label) = else_label_opt {
-                        self.assem.push(format!("    je {}", else_label));
-                    } else {
-                        self.assem.push(format!("    je {}", end_label));
-                    }
-                    self.compile_statement(&then_body);
+                    let false_label = else_label_opt.clone().unwrap_or_else(|| end_label.clone());
+                    self.compile_condition(condition, &false_label);
+                    self.compile_statements(then_body);
                     if let Some(else_body) = else_body {
                         self.assem.push(format!("    jmp {}", end_label));
                         self.assem.push(format!("{}:", else_label_opt.unwrap()));
-                        self.compile_statement(else_body);
+                        self.compile_statements(else_body);
                     }
                     self.assem.push(format!("{}:", end_label));
                 },
                 Statement::FunctionCall { name, args } => {
-                    let arg_regs = ["rdi", "rsi", "rdx", "rcx", "r8", "r9"];
-                    // Evaluate each arg and push result onto stack
-                    for arg in args {
-                        self.compile_expression(arg);
-                        self.assem.push("    push rax".into());
-                    }
-                    // Pop into registers in reverse order
-                    for i in (0..args.len()).rev() {
-                        self.assem.push(format!("    pop {}", arg_regs[i]));
-                    }
-                    // ABI: al = 0 (no floating-point args)
-                    self.assem.push("    mov rax, 0".into());
-                    self.assem.push(format!("    call _{}", name));
+                    self.compile_call(name, args);
                 },
+                Statement::Extern { .. } => {}
 
-                
-
-                Statement::Print ( value ) => {
-                    self.compile_print(value);
+                Statement::Print { args, newline } => {
+                    self.compile_print(args, *newline);
+                }
+                Statement::Send(expr) if self.inline_exit.is_some() => {
+                    let (exit, offset, returns) = self.inline_exit.clone().unwrap();
+                    let ty = self.expr_type(expr);
+                    self.compile_expression(expr);
+                    self.convert(ty, returns);
+                    self.assem.push(format!("    mov [rbp - {}], rax", offset));
+                    self.assem.push(format!("    jmp {}", exit));
+                }
+                Statement::Send(Expression::FunctionCall { name, args }) if self.is_tail_callable(name) => {
+                    self.compile_tail_call(name, args);
                 }
                 Statement::Send(expr) => {
+                    let ty = self.expr_type(expr);
                     self.compile_expression(expr);
+                    self.convert(ty, self.return_type);
                     self.assem.push(format!("    jmp {}", self.epilogue_label));
                 }
+                Statement::Assert { condition, message, text, location } => {
+                    self.compile_assert(condition, message.as_ref(), text, location);
+                }
+                Statement::Inlined { params, returns, args, body, result, .. } => {
+                    self.compile_inlined(params, *returns, args, body, result);
+                }
                 _ => {}
             }
         }
@@ -205,6 +1169,10 @@ impl Compiler {
             Expression::Integer(i) => {
                 self.assem.push(format!("    mov rax, {}", i));
             }
+            Expression::Float(f) => {
+                // Floats travel through rax as their IEEE 754 bit pattern
+                self.assem.push(format!("    mov rax, 0x{:016x}", f.to_bits()));
+            }
             Expression::Variable(var) => {
                 if let Some(offset) = self.offset_map.get(var) {
                     self.assem.push(format!("    mov rax, [rbp - {}]", offset));
@@ -214,139 +1182,325 @@ impl Compiler {
             }
             Expression::StringLiteral(s) =>  {
                 // Register string literal and get its label.
-                let label = self.register_string_literal(s);
+                let label = self.string_literal(s);
                 // Load the address of the string literal into RAX.
                 self.assem.push(format!("    lea rax, [rel {}]", label));
             },
-            Expression::BinaryOp { left, op, right } => {
+            Expression::BinaryOp { left, op, right, .. }
+                if self.expr_type(left) == Type::Str || self.expr_type(right) == Type::Str =>
+            {
+                self.compile_string_op(left, op, right);
+            }
+            Expression::BinaryOp { left, op, right, .. }
+                if self.expr_type(left) == Type::Float || self.expr_type(right) == Type::Float =>
+            {
+                self.compile_float_op(left, op, right);
+            }
+            Expression::BinaryOp { left, op, right, location } => {
                 // First, compile the left side:
                 self.compile_expression(left);
                 // Save left operand on the stack:
-                self.assem.push("    push rax".into());
+                self.push("rax");
                 // Then, compile the right side:
                 self.compile_expression(right);
                 // Retrieve left operand from the stack into rcx:
-                self.assem.push("    pop rcx".into());
+                self.pop("rcx");
 
                 // Now, perform the operation:
                 match op {
-                    crate::ast::BinaryOperator::Add => {
+                    BinaryOperator::Add => {
                         self.assem.push("    add rax, rcx".into());
+                        self.check_overflow("+", location);
                     }
-                    crate::ast::BinaryOperator::Sub => {
+                    BinaryOperator::Sub => {
                         self.assem.push("    sub rcx, rax".into());
+                        self.check_overflow("-", location);
                         self.assem.push("    mov rax, rcx".into());
                     }
-                    crate::ast::BinaryOperator::Mul => {
+                    BinaryOperator::Mul => {
                         self.assem.push("    imul rax, rcx".into());
+                        self.check_overflow("*", location);
                     }
-                    crate::ast::BinaryOperator::Div => {
-                        self.assem.push("    mov rbx, rax".into());  // Save right operand
-                        self.assem.push("    mov rax, rcx".into());    // Move left operand into RAX
-                        self.assem.push("    mov rdx, 0".into());        // Clear rdx
-                        self.assem.push("    idiv rbx".into());
-                    },
-                    crate::ast::BinaryOperator::Eq => {
+                    BinaryOperator::Div => self.compile_division(location),
+                    BinaryOperator::Eq => {
                         self.assem.push("    cmp rax, rcx".into());
                         self.assem.push("    sete al".into());
                         self.assem.push("    movzx rax, al".into());
                     }
-                    crate::ast::BinaryOperator::NEq => {
+                    BinaryOperator::NEq => {
                         self.assem.push("    cmp rax, rcx".into());
                         self.assem.push("    setne al".into());
                         self.assem.push("    movzx rax, al".into());
                     }
-                    crate::ast::BinaryOperator::Lt => {
+                    BinaryOperator::Lt => {
                         self.assem.push("    cmp rcx, rax".into());
                         self.assem.push("    setl al".into());
                         self.assem.push("    movzx rax, al".into());
                     }
-                    crate::ast::BinaryOperator::LtEq => {
+                    BinaryOperator::LtEq => {
                         self.assem.push("    cmp rcx, rax".into());
                         self.assem.push("    setle al".into());
                         self.assem.push("    movzx rax, al".into());
                     }
-                    crate::ast::BinaryOperator::Gt => {
+                    BinaryOperator::Gt => {
                         self.assem.push("    cmp rcx, rax".into());
                         self.assem.push("    setg al".into());
                         self.assem.push("    movzx rax, al".into());
                     }
                 }
             }
-            Expression::FunctionArg(name) => {
-                if let Some(offset) = self.offset_map.get(name) {
-                    self.assem.push(format!("    mov rax, [rbp - {}]", offset));
-                } else {
-                    panic!("Function argument {} not defined", name);
-                }
-            }
             Expression::FunctionCall { name, args } => {
-                let arg_regs = ["rdi", "rsi", "rdx", "rcx", "r8", "r9"];
-                for arg in args {
-                    self.compile_expression(arg);
-                    self.assem.push("    push rax".into());
-                }
-                for i in (0..args.len()).rev() {
-                    self.assem.push(format!("    pop {}", arg_regs[i]));
+                self.compile_call(name, args);
+            }
+            Expression::Format(_) => {
+                self.use_libc("snprintf");
+                self.compile_format_string(expr);
+            }
+            Expression::FunctionRef(name) => self.compile_function_ref(name),
+            Expression::Closure { function, captures } if captures.is_empty() => {
+                self.compile_function_ref(function);
+            }
+            Expression::Closure { function, captures } => {
+                let types = captures.iter().map(|capture| self.var_types.get(capture).copied().unwrap_or(Type::Int)).collect();
+                self.capture_types.insert(function.clone(), types);
+                // Record layout: [code, captures...]
+                self.assem.push(format!("    mov rdi, {}", (captures.len() + 1) * 8));
+                self.call_runtime("bonk_alloc");
+                self.assem.push(format!("    lea rcx, [rel _{}]", function));
+                self.assem.push("    mov [rax], rcx".into());
+                for (i, capture) in captures.iter().enumerate() {
+                    let offset = *self.offset_map.get(capture).unwrap_or_else(|| panic!("Variable {} not defined", capture));
+                    self.assem.push(format!("    mov rcx, [rbp - {}]", offset));
+                    self.assem.push(format!("    mov [rax + {}], rcx", (i + 1) * 8));
                 }
-                self.assem.push("    mov rax, 0".into());
-                self.assem.push(format!("    call _{}", name));
+            }
+            Expression::Lambda { .. } => unreachable!("anonymous functions are lifted before code generation"),
+            Expression::Index { value, index } => {
+                self.compile_expression(value);
+                self.push("rax");
+                self.compile_expression(index);
+                self.pop("rcx");
+                self.assem.push("    movzx rax, byte [rcx + rax]".into());
             }
         }
     }
 
-    fn compile_assignment(&mut self, name: &String, value: &crate::ast::Expression) {
-        let offset = if let Some(&offset) = self.offset_map.get(name) {
-            offset
-        } else {
-            let off = self.var_offset;
-            self.offset_map.insert(name.clone(), off);
-            self.var_offset += 8;
-            off
-        };
-        self.compile_expression(value);
-        self.assem.push(format!("    mov [rbp - {}], rax", offset));
+    // Converts the value in rax between numeric types
+    fn convert(&mut self, from: Type, to: Type) {
+        if from == Type::Int && to == Type::Float {
+            self.assem.push("    cvtsi2sd xmm0, rax".into());
+            self.assem.push("    movq rax, xmm0".into());
+        }
     }
 
-    fn compile_params(&mut self, params: &[crate::ast::Expression]) {
-        let regs = ["rdi", "rsi", "rdx", "rcx", "r8", "r9"];
-        for (i, param) in params.iter().enumerate() {
-            if let Expression::FunctionArg(name) = param {
-                let offset = self.var_offset;
-                self.offset_map.insert(name.clone(), offset);
-                self.var_offset += 8;
-                self.assem.push(format!("    mov [rbp - {}], {}", offset, regs[i]));
-            }
-        }
+    fn load_integer(&mut self, value: i64) {
+        self.assem.push(format!("    mov rax, {}", value));
+    }
+
+    fn load_result(&mut self, slot: i32) {
+        self.assem.push(format!("    mov rax, [rbp - {}]", slot));
+    }
+
+    fn store_result(&mut self, slot: i32) {
+        self.assem.push(format!("    mov [rbp - {}], rax", slot));
+    }
+
+    fn push_result(&mut self) {
+        self.push("rax");
+    }
+
+    fn pop_result(&mut self) {
+        self.pop("rax");
     }
 
-    fn compile_print(&mut self, value: &crate::ast::Expression) {
-        let fmt_label = if matches!(value, Expression::StringLiteral(_)) {
-            "fmt_str"
+    // Integers take the general purpose registers and floats the xmm registers
+    fn pop_argument(&mut self, ty: Type, index: usize) {
+        if ty == Type::Float {
+            self.pop("rax");
+            self.assem.push(format!("    movq xmm{}, rax", index));
         } else {
-            "fmt"
-        };
-        self.compile_expression(value);
+            self.pop(ARG_REGS[index]);
+        }
+    }
+
+    // ABI: al = number of vector registers used by the arguments
+    fn arguments_loaded(&mut self, float_count: usize) {
+        self.assem.push(format!("    mov rax, {}", float_count));
+    }
+
+    fn pop_operands(&mut self) {
+        self.pop("rdi");
         self.assem.push("    mov rsi, rax".into());
-        self.assem.push(format!("    lea rdi, [rel {}]", fmt_label));
-        self.assem.push("    mov rax, 0".into());
-        self.assem.push("    call _printf".into());
     }
 
-    fn compile_while(&mut self, condition: &Expression, body: &[Statement]) {
-        let start_label = self.new_label("while_start");
-        let end_label = self.new_label("while_end");
+    fn pop_float_operands(&mut self) {
+        self.pop("rcx");
+        self.assem.push("    movq xmm0, rcx".into());
+        self.assem.push("    movq xmm1, rax".into());
+    }
 
-        self.assem.push(format!("{}:", start_label));
-        self.compile_expression(condition);
+    fn set_if(&mut self, op: &BinaryOperator) {
         self.assem.push("    cmp rax, 0".into());
-        self.assem.push(format!("    je {}", end_label));
+        self.assem.push(format!("    set{} al", condition_code(op)));
+        self.assem.push("    movzx rax, al".into());
+    }
 
-        for stmt in body {
-            self.compile_statement(&[stmt.clone()]);
+    fn call_runtime(&mut self, name: &'static str) {
+        self.use_runtime(name);
+        self.emit_call(name);
+    }
+
+    fn string_literal(&mut self, s: &str) -> String {
+        if let Some(label) = self.string_constants.get(s) {
+            return label.clone();
+        }
+        let label = format!("str_{}", self.string_count);
+        self.string_count += 1;
+        self.string_constants.insert(s.to_string(), label.clone());
+        self.rodata.push(format!("{}: db {}", label, db_operands(s)));
+        label
+    }
+
+    // Values that do not fit in registers are passed on the stack
+    fn emit_printf_call(&mut self, symbol: &str, fixed: &[Option<i32>], fmt_label: &str, values: &[(i32, Type)]) {
+        let arg_regs = ["rdi", "rsi", "rdx", "rcx", "r8", "r9"];
+        let mut int_count = fixed.len() + 1;
+        let mut float_count = 0;
+        let mut in_regs = Vec::new();
+        let mut on_stack = Vec::new();
+        for &(slot, ty) in values {
+            if ty == Type::Float && float_count < 8 {
+                in_regs.push(format!("    movq xmm{}, [rbp - {}]", float_count, slot));
+                float_count += 1;
+            } else if ty != Type::Float && int_count < arg_regs.len() {
+                in_regs.push(format!("    mov {}, [rbp - {}]", arg_regs[int_count], slot));
+                int_count += 1;
+            } else {
+                on_stack.push(slot);
+            }
         }
 
-        self.assem.push(format!("    jmp {}", start_label));
-        self.assem.push(format!("{}:", end_label));
+        // Reserve the outgoing stack arguments, keeping rsp 16-byte aligned
+        let reserved = on_stack.len() as i32 + (self.stack_depth + on_stack.len() as i32) % 2;
+        if reserved > 0 {
+            self.assem.push(format!("    sub rsp, {}", reserved * 8));
+            self.stack_depth += reserved;
+        }
+        for (i, slot) in on_stack.iter().enumerate() {
+            self.assem.push(format!("    mov rax, [rbp - {}]", slot));
+            self.assem.push(format!("    mov [rsp + {}], rax", i * 8));
+        }
+
+        for (reg, slot) in arg_regs.iter().zip(fixed) {
+            match slot {
+                Some(slot) => self.assem.push(format!("    mov {}, [rbp - {}]", reg, slot)),
+                None => self.assem.push(format!("    mov {}, 0", reg)),
+            }
+        }
+        self.assem.push(format!("    lea {}, [rel {}]", arg_regs[fixed.len()], fmt_label));
+        self.assem.extend(in_regs);
+        self.assem.push(format!("    mov rax, {}", float_count));
+        self.emit_call(symbol);
+
+        if reserved > 0 {
+            self.assem.push(format!("    add rsp, {}", reserved * 8));
+            self.stack_depth -= reserved;
+        }
+    }
+
+    fn store_buffer_size(&mut self, slot: i32) {
+        self.assem.push("    lea rdi, [rax + 1]".into());
+        self.assem.push(format!("    mov [rbp - {}], rdi", slot));
+    }
+
+    fn restart_body(&mut self) {
+        self.assem.push(format!("    jmp {}", self.body_label));
+    }
+
+    fn jump_to_function(&mut self, name: &str) {
+        self.assem.push("    mov rsp, rbp".into());
+        self.assem.push("    pop rbp".into());
+        self.assem.push(format!("    jmp _{}", name));
+    }
+}
+
+fn is_memory(operand: &str) -> bool {
+    operand.contains('[')
+}
+
+// Condition code suffix for jcc and setcc after comparing the left operand with the right
+fn condition_code(op: &BinaryOperator) -> &'static str {
+    match op {
+        BinaryOperator::Eq => "e",
+        BinaryOperator::NEq => "ne",
+        BinaryOperator::Lt => "l",
+        BinaryOperator::LtEq => "le",
+        BinaryOperator::Gt => "g",
+        _ => unreachable!("{:?} is not a comparison", op),
+    }
+}
+
+// Condition code suffix for jumping when the comparison does not hold
+fn inverse_condition_code(op: &BinaryOperator) -> &'static str {
+    match op {
+        BinaryOperator::Eq => "ne",
+        BinaryOperator::NEq => "e",
+        BinaryOperator::Lt => "ge",
+        BinaryOperator::LtEq => "g",
+        BinaryOperator::Gt => "le",
+        _ => unreachable!("{:?} is not a comparison", op),
+    }
+}
+
+// printf conversion for a value of type `ty` formatted with `spec`
+pub fn printf_conversion(spec: &FormatSpec, ty: Type) -> String {
+    let mut conversion = String::from("%");
+    if spec.left_align {
+        conversion.push('-');
+    }
+    if spec.zero_pad {
+        conversion.push('0');
+    }
+    if let Some(width) = spec.width {
+        conversion.push_str(&width.to_string());
+    }
+    if let Some(precision) = spec.precision {
+        conversion.push_str(&format!(".{}", precision));
+    }
+    let kind = spec.kind.unwrap_or(match ty {
+        Type::Int => 'd',
+        Type::Float => 'f',
+        Type::Str => 's',
+    });
+    match (ty, kind) {
+        (Type::Int, 'd' | 'x' | 'X' | 'o') => {
+            conversion.push('l');
+            conversion.push(kind);
+        }
+        (Type::Float, 'f' | 'e') | (Type::Str, 's') => conversion.push(kind),
+        _ => unreachable!("the checker rejects format type '{}' for {}", kind, ty),
+    }
+    conversion
+}
+
+// NASM `db` operands for a NUL-terminated string; quotes and control
+// characters are written as byte values
+fn db_operands(s: &str) -> String {
+    let mut operands = Vec::new();
+    let mut run = String::new();
+    for byte in s.bytes() {
+        if byte == b'"' || !(0x20..0x7f).contains(&byte) {
+            if !run.is_empty() {
+                operands.push(format!("\"{}\"", std::mem::take(&mut run)));
+            }
+            operands.push(byte.to_string());
+        } else {
+            run.push(byte as char);
+        }
+    }
+    if !run.is_empty() {
+        operands.push(format!("\"{}\"", run));
     }
+    operands.push("0".into());
+    operands.join(", ")
 }
@@ -0,0 +1,153 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::ast::{Expression, FormatPart, Statement};
+
+// Dead code elimination. Statements that can never run are dropped: those
+// after a `send` (or anything else that never falls through) with a warning,
+// `while` loops whose condition is the constant 0, and the `then` branch of an
+// `if` whose condition is the constant 0, leaving its `else` branch in place
+// of the `if`. Functions that cannot be reached from `main` or an `@export`
+// function are removed entirely.
+
+pub fn eliminate_dead_code(mut program: Vec<Statement>) -> (Vec<Statement>, Vec<String>) {
+    let mut warnings = Vec::new();
+    for stmt in &mut program {
+        if let Statement::Function { name, body, .. } = stmt {
+            remove_unreachable(name, body, &mut warnings);
+        }
+    }
+
+    let live = reachable_functions(&program);
+    program.retain(|stmt| match stmt {
+        Statement::Function { name, .. } => live.contains(name),
+        _ => true,
+    });
+    (program, warnings)
+}
+
+fn remove_unreachable(function: &str, body: &mut Vec<Statement>, warnings: &mut Vec<String>) {
+    for stmt in std::mem::take(body) {
+        match stmt {
+            Statement::While { condition: Expression::Integer(0), .. } => {}
+            Statement::If { condition: Expression::Integer(0), else_body, .. } => {
+                let mut else_body = else_body.unwrap_or_default();
+                remove_unreachable(function, &mut else_body, warnings);
+                body.extend(else_body);
+            }
+            other => body.push(other),
+        }
+    }
+    for stmt in body.iter_mut() {
+        match stmt {
+            Statement::If { then_body, else_body, .. } => {
+                remove_unreachable(function, then_body, warnings);
+                if let Some(else_body) = else_body {
+                    remove_unreachable(function, else_body, warnings);
+                }
+            }
+            Statement::While { body, .. } => remove_unreachable(function, body, warnings),
+            // What the callee leaves unreachable once its arguments are known
+            // is no mistake in this function, so it goes without a warning
+            Statement::Inlined { body, .. } => remove_unreachable(function, body, &mut Vec::new()),
+            _ => {}
+        }
+    }
+    if let Some(end) = body.iter().position(never_falls_through) {
+        if end + 1 < body.len() {
+            warnings.push(format!("Unreachable statements in function {} removed", function));
+            body.truncate(end + 1);
+        }
+    }
+}
+
+// Whether control can never continue with the statement after this one
+fn never_falls_through(stmt: &Statement) -> bool {
+    match stmt {
+        Statement::Send(_) => true,
+        Statement::FunctionCall { name, .. } => name == "exit",
+        Statement::If { then_body, else_body: Some(else_body), .. } => {
+            then_body.iter().any(never_falls_through) && else_body.iter().any(never_falls_through)
+        }
+        // There is no `break`, so a loop whose condition stays true only ends through `send`
+        Statement::While { condition: Expression::Integer(value), .. } => *value != 0,
+        _ => false,
+    }
+}
+
+fn reachable_functions(program: &[Statement]) -> HashSet<String> {
+    let mut calls: HashMap<&str, Vec<&str>> = HashMap::new();
+    let mut pending = Vec::new();
+    for stmt in program {
+        if let Statement::Function { name, body, attributes, .. } = stmt {
+            let mut callees = Vec::new();
+            body.iter().for_each(|stmt| statement_references(stmt, &mut callees));
+            calls.insert(name, callees);
+            if name == "main" || attributes.iter().any(|attribute| attribute == "export") {
+                pending.push(name.as_str());
+            }
+        }
+    }
+
+    let mut live = HashSet::new();
+    while let Some(name) = pending.pop() {
+        if live.insert(name.to_string()) {
+            pending.extend(calls.get(name).into_iter().flatten());
+        }
+    }
+    live
+}
+
+// Functions a statement calls or takes as a value
+fn statement_references<'a>(stmt: &'a Statement, found: &mut Vec<&'a str>) {
+    match stmt {
+        Statement::Assign { value, .. } | Statement::Send(value) => expression_references(value, found),
+        Statement::Print { args, .. } => args.iter().for_each(|arg| expression_references(arg, found)),
+        Statement::FunctionCall { name, args } => {
+            found.push(name);
+            args.iter().for_each(|arg| expression_references(arg, found));
+        }
+        Statement::If { condition, then_body, else_body } => {
+            expression_references(condition, found);
+            then_body.iter().chain(else_body.iter().flatten()).for_each(|stmt| statement_references(stmt, found));
+        }
+        Statement::While { condition, body } => {
+            expression_references(condition, found);
+            body.iter().for_each(|stmt| statement_references(stmt, found));
+        }
+        Statement::Assert { condition, message, .. } => {
+            expression_references(condition, found);
+            message.iter().for_each(|message| expression_references(message, found));
+        }
+        Statement::Inlined { args, body, .. } => {
+            args.iter().for_each(|arg| expression_references(arg, found));
+            body.iter().for_each(|stmt| statement_references(stmt, found));
+        }
+        Statement::Function { .. } | Statement::Extern { .. } | Statement::Use(_) => {}
+    }
+}
+
+fn expression_references<'a>(expr: &'a Expression, found: &mut Vec<&'a str>) {
+    match expr {
+        Expression::BinaryOp { left, right, .. } => {
+            expression_references(left, found);
+            expression_references(right, found);
+        }
+        Expression::FunctionCall { name, args } => {
+            found.push(name);
+            args.iter().for_each(|arg| expression_references(arg, found));
+        }
+        Expression::FunctionRef(name) | Expression::Closure { function: name, .. } => found.push(name),
+        Expression::Index { value, index } => {
+            expression_references(value, found);
+            expression_references(index, found);
+        }
+        Expression::Format(parts) => {
+            for part in parts {
+                if let FormatPart::Value { expr, .. } = part {
+                    expression_references(expr, found);
+                }
+            }
+        }
+        _ => {}
+    }
+}
@@ -0,0 +1,759 @@
+use std::collections::HashMap;
+
+// Assembler for the NASM source the compiler generates: the instructions the
+// code generator and the runtime routines use, `db`, `dq`, `resb` and `resq`
+// data, labels and the section, global and extern directives. It lays out the
+// sections, gives every jump the short form when its target is in reach, and
+// resolves references within a section. References to other sections and to
+// external symbols are left as relocations for the object file writer.
+//
+// Where an instruction has several encodings, the one NASM picks by default is
+// used, so that the code and data come out byte for byte as `nasm` makes them.
+
+// The sections an object can have, in the order they are laid out
+pub const SECTIONS: [&str; 4] = [".text", ".rodata", ".data", ".bss"];
+
+pub struct Object {
+    // Indexed like SECTIONS
+    pub sections: Vec<Section>,
+    pub symbols: Vec<Symbol>,
+}
+
+pub struct Section {
+    // Empty for .bss, which only has a size
+    pub bytes: Vec<u8>,
+    pub size: u64,
+    pub relocations: Vec<Relocation>,
+}
+
+pub struct Symbol {
+    pub name: String,
+    // Section index and offset, None for an extern
+    pub definition: Option<(usize, u64)>,
+    pub global: bool,
+}
+
+pub struct Relocation {
+    // Where the field is in its section
+    pub offset: u64,
+    pub target: Target,
+    pub kind: Reference,
+    // Added to the target address. For pc-relative fields this is minus the
+    // distance from the field to the end of the instruction.
+    pub addend: i64,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Target {
+    // An extern, by symbol index
+    Symbol(usize),
+    // A label in another section, as the section index and offset
+    Section(usize, u64),
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Reference {
+    // 32-bit displacement of a call
+    Call,
+    // 32-bit rip-relative displacement
+    Relative,
+    // 64-bit address in data
+    Absolute,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Size {
+    Byte,
+    Dword,
+    Qword,
+    Xmm,
+}
+
+#[derive(Clone, Copy)]
+struct Reg {
+    number: u8,
+    size: Size,
+}
+
+struct Mem {
+    base: Option<u8>,
+    // Register and scale
+    index: Option<(u8, u8)>,
+    disp: i64,
+    // `[rel label]`
+    label: Option<String>,
+    size: Option<Size>,
+}
+
+enum Operand {
+    Reg(Reg),
+    Imm(i64),
+    Mem(Mem),
+    Label(String),
+}
+
+// Machine code of one instruction or data directive
+struct Encoded {
+    bytes: Vec<u8>,
+    reference: Option<(String, usize, Reference)>,
+}
+
+enum Item {
+    Label(String),
+    Code(Encoded),
+    // jmp, or jcc with its condition code, to a label
+    Branch(Option<u8>, String),
+    Reserve(u64),
+}
+
+const REGS64: [&str; 16] =
+    ["rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi", "r8", "r9", "r10", "r11", "r12", "r13", "r14", "r15"];
+const REGS32: [&str; 16] = [
+    "eax", "ecx", "edx", "ebx", "esp", "ebp", "esi", "edi", "r8d", "r9d", "r10d", "r11d", "r12d", "r13d", "r14d", "r15d",
+];
+const REGS8: [&str; 16] = [
+    "al", "cl", "dl", "bl", "spl", "bpl", "sil", "dil", "r8b", "r9b", "r10b", "r11b", "r12b", "r13b", "r14b", "r15b",
+];
+
+pub fn assemble(lines: &[String]) -> Result<Object, String> {
+    let mut items: Vec<Vec<Item>> = SECTIONS.iter().map(|_| Vec::new()).collect();
+    let mut section = 0;
+    let mut globals = Vec::new();
+    let mut externs = Vec::new();
+    // Labels starting with a dot belong to the last one that does not
+    let mut scope = String::new();
+
+    for line in lines {
+        let line = strip_comment(line);
+        if line.trim().is_empty() {
+            continue;
+        }
+        if line.starts_with(' ') {
+            let (mnemonic, operands) = split_instruction(line.trim());
+            let operands: Vec<Operand> =
+                operands.iter().map(|operand| parse_operand(operand, &scope)).collect::<Result<_, _>>()?;
+            let item = match (branch_condition(mnemonic), &operands[..]) {
+                (Some(condition), [Operand::Label(target)]) => Item::Branch(condition, target.clone()),
+                _ => Item::Code(encode(mnemonic, &operands).map_err(|err| format!("{} in `{}`", err, line.trim()))?),
+            };
+            items[section].push(item);
+            continue;
+        }
+        let (directive, rest) = line.split_once(' ').unwrap_or((line, ""));
+        match directive {
+            "global" => globals.push(rest.trim().to_string()),
+            "extern" => externs.push(rest.trim().to_string()),
+            "section" => {
+                section = SECTIONS
+                    .iter()
+                    .position(|name| *name == rest.trim())
+                    .ok_or_else(|| format!("Unknown section {}", rest.trim()))?;
+            }
+            _ => {
+                let Some((label, data)) = line.split_once(':') else {
+                    return Err(format!("Cannot assemble `{}`", line));
+                };
+                let label = if label.starts_with('.') {
+                    format!("{}{}", scope, label)
+                } else {
+                    scope = label.to_string();
+                    label.to_string()
+                };
+                items[section].push(Item::Label(label));
+                if !data.trim().is_empty() {
+                    items[section].push(data_item(data.trim(), &scope)?);
+                }
+            }
+        }
+    }
+
+    let offsets: Vec<HashMap<String, u64>> = items.iter().map(|items| layout(items).1).collect();
+    let mut symbols = Vec::new();
+    for (section, labels) in offsets.iter().enumerate() {
+        let mut labels: Vec<(&String, &u64)> = labels.iter().collect();
+        labels.sort_by_key(|&(name, offset)| (*offset, name.clone()));
+        for (name, offset) in labels {
+            symbols.push(Symbol { name: name.clone(), definition: Some((section, *offset)), global: globals.contains(name) });
+        }
+    }
+    for name in &externs {
+        if !symbols.iter().any(|symbol| symbol.name == *name) {
+            symbols.push(Symbol { name: name.clone(), definition: None, global: true });
+        }
+    }
+    if let Some(name) = globals.iter().find(|name| !symbols.iter().any(|symbol| symbol.name == **name)) {
+        return Err(format!("Global {} is not defined", name));
+    }
+
+    let mut sections = Vec::new();
+    for (index, items) in items.iter().enumerate() {
+        let (short, labels) = layout(items);
+        let mut bytes = Vec::new();
+        let mut size = 0;
+        let mut relocations = Vec::new();
+        for (item, short) in items.iter().zip(short) {
+            match item {
+                Item::Label(_) => {}
+                Item::Reserve(count) => size += count,
+                Item::Branch(condition, target) => {
+                    let start = bytes.len() as i64;
+                    let length = branch_length(*condition, short) as i64;
+                    let displacement = match labels.get(target) {
+                        Some(&offset) => offset as i64 - (start + length),
+                        None => return Err(format!("Jump to undefined label {}", target)),
+                    };
+                    match (condition, short) {
+                        (None, true) => bytes.extend([0xEB, displacement as u8]),
+                        (None, false) => bytes.push(0xE9),
+                        (Some(cc), true) => bytes.extend([0x70 + cc, displacement as u8]),
+                        (Some(cc), false) => bytes.extend([0x0F, 0x80 + cc]),
+                    }
+                    if !short {
+                        bytes.extend((displacement as i32).to_le_bytes());
+                    }
+                }
+                Item::Code(code) => {
+                    let start = bytes.len();
+                    bytes.extend(&code.bytes);
+                    let Some((label, at, kind)) = &code.reference else { continue };
+                    let field = start + at;
+                    let end = (start + code.bytes.len()) as i64;
+                    let addend = if *kind == Reference::Absolute { 0 } else { field as i64 - end };
+                    let target = match (labels.get(label), *kind) {
+                        (Some(&offset), Reference::Call | Reference::Relative) => {
+                            let displacement = offset as i64 - end;
+                            bytes[field..field + 4].copy_from_slice(&(displacement as i32).to_le_bytes());
+                            continue;
+                        }
+                        (Some(&offset), Reference::Absolute) => Target::Section(index, offset),
+                        (None, _) => match offsets.iter().position(|labels| labels.contains_key(label)) {
+                            Some(section) => Target::Section(section, offsets[section][label]),
+                            None => match symbols.iter().position(|symbol| symbol.name == *label) {
+                                Some(symbol) => Target::Symbol(symbol),
+                                None => return Err(format!("Undefined label {}", label)),
+                            },
+                        },
+                    };
+                    relocations.push(Relocation { offset: field as u64, target, kind: *kind, addend });
+                }
+            }
+        }
+        if index == SECTIONS.len() - 1 {
+            sections.push(Section { bytes: Vec::new(), size, relocations });
+        } else {
+            if size > 0 {
+                return Err(format!("Reserved space outside .bss in {}", SECTIONS[index]));
+            }
+            sections.push(Section { size: bytes.len() as u64, bytes, relocations });
+        }
+    }
+    Ok(Object { sections, symbols })
+}
+
+// Which branches of a section can be short, and where its labels end up.
+// Every branch starts short and is made long when its target is out of reach,
+// until none changes.
+fn layout(items: &[Item]) -> (Vec<bool>, HashMap<String, u64>) {
+    let mut short: Vec<bool> = items.iter().map(|item| matches!(item, Item::Branch(..))).collect();
+    loop {
+        let mut offset = 0;
+        let mut labels = HashMap::new();
+        let mut starts = Vec::with_capacity(items.len());
+        for (item, &short) in items.iter().zip(&short) {
+            starts.push(offset);
+            match item {
+                Item::Label(name) => {
+                    labels.insert(name.clone(), offset);
+                }
+                Item::Code(code) => offset += code.bytes.len() as u64,
+                Item::Branch(condition, _) => offset += branch_length(*condition, short),
+                Item::Reserve(count) => offset += count,
+            }
+        }
+        let mut changed = false;
+        for (i, item) in items.iter().enumerate() {
+            if let (Item::Branch(_, target), true) = (item, short[i]) {
+                let reaches = labels.get(target).is_some_and(|&target| {
+                    let displacement = target as i64 - (starts[i] as i64 + 2);
+                    i8::try_from(displacement).is_ok()
+                });
+                if !reaches {
+                    short[i] = false;
+                    changed = true;
+                }
+            }
+        }
+        if !changed {
+            return (short, labels);
+        }
+    }
+}
+
+fn branch_length(condition: Option<u8>, short: bool) -> u64 {
+    match (condition, short) {
+        (_, true) => 2,
+        (None, false) => 5,
+        (Some(_), false) => 6,
+    }
+}
+
+// None for jmp, the condition code for jcc, or not a branch at all
+fn branch_condition(mnemonic: &str) -> Option<Option<u8>> {
+    if mnemonic == "jmp" {
+        return Some(None);
+    }
+    mnemonic.strip_prefix('j').and_then(condition_code).map(Some)
+}
+
+fn condition_code(suffix: &str) -> Option<u8> {
+    Some(match suffix {
+        "o" => 0x0,
+        "no" => 0x1,
+        "b" | "c" | "nae" => 0x2,
+        "ae" | "nb" | "nc" => 0x3,
+        "e" | "z" => 0x4,
+        "ne" | "nz" => 0x5,
+        "be" | "na" => 0x6,
+        "a" | "nbe" => 0x7,
+        "s" => 0x8,
+        "ns" => 0x9,
+        "p" | "pe" => 0xA,
+        "np" | "po" => 0xB,
+        "l" | "nge" => 0xC,
+        "ge" | "nl" => 0xD,
+        "le" | "ng" => 0xE,
+        "g" | "nle" => 0xF,
+        _ => return None,
+    })
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ';' if !quoted => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn split_instruction(line: &str) -> (&str, Vec<&str>) {
+    match line.split_once(' ') {
+        Some((mnemonic, operands)) => (mnemonic, operands.split(',').map(str::trim).collect()),
+        None => (line, Vec::new()),
+    }
+}
+
+fn register(name: &str) -> Option<Reg> {
+    let find = |names: &[&str; 16], size| names.iter().position(|reg| *reg == name).map(|n| Reg { number: n as u8, size });
+    find(&REGS64, Size::Qword).or_else(|| find(&REGS32, Size::Dword)).or_else(|| find(&REGS8, Size::Byte)).or_else(|| {
+        let number: u8 = name.strip_prefix("xmm")?.parse().ok()?;
+        (number < 16).then_some(Reg { number, size: Size::Xmm })
+    })
+}
+
+fn number(text: &str) -> Option<i64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    // Hexadecimal immediates are bit patterns, such as those of floats
+    let value = match digits.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok()? as i64,
+        None => digits.parse::<u64>().ok()? as i64,
+    };
+    Some(if negative { value.wrapping_neg() } else { value })
+}
+
+fn qualify(label: &str, scope: &str) -> String {
+    if label.starts_with('.') {
+        format!("{}{}", scope, label)
+    } else {
+        label.to_string()
+    }
+}
+
+fn parse_operand(text: &str, scope: &str) -> Result<Operand, String> {
+    let (size, rest) = match text.split_once(' ') {
+        Some(("byte", rest)) => (Some(Size::Byte), rest.trim()),
+        Some(("dword", rest)) => (Some(Size::Dword), rest.trim()),
+        Some(("qword", rest)) => (Some(Size::Qword), rest.trim()),
+        _ => (None, text),
+    };
+    if let Some(inner) = rest.strip_prefix('[').and_then(|rest| rest.strip_suffix(']')) {
+        let mut mem = Mem { base: None, index: None, disp: 0, label: None, size };
+        if let Some(label) = inner.strip_prefix("rel ") {
+            mem.label = Some(qualify(label.trim(), scope));
+            return Ok(Operand::Mem(mem));
+        }
+        let inner: String = inner.chars().filter(|c| !c.is_whitespace()).collect();
+        // Split into terms, each keeping its sign
+        let mut terms = Vec::new();
+        let mut start = 0;
+        for (i, c) in inner.char_indices().skip(1) {
+            if c == '+' || c == '-' {
+                terms.push(&inner[start..i]);
+                start = i;
+            }
+        }
+        terms.push(&inner[start..]);
+        for term in terms {
+            let term = term.strip_prefix('+').unwrap_or(term);
+            if let Some(value) = number(term) {
+                mem.disp += value;
+            } else if let Some((name, scale)) = term.split_once('*') {
+                let reg = register(name).filter(|reg| reg.size == Size::Qword).ok_or("Bad index register")?;
+                let scale = number(scale).filter(|scale| [1, 2, 4, 8].contains(scale)).ok_or("Bad scale")?;
+                if mem.index.replace((reg.number, scale as u8)).is_some() {
+                    return Err("Two index registers".into());
+                }
+            } else {
+                let reg = register(term).filter(|reg| reg.size == Size::Qword).ok_or(format!("Bad address {}", text))?;
+                if mem.base.is_none() {
+                    mem.base = Some(reg.number);
+                } else if mem.index.is_none() {
+                    mem.index = Some((reg.number, 1));
+                } else {
+                    return Err(format!("Bad address {}", text));
+                }
+            }
+        }
+        return Ok(Operand::Mem(mem));
+    }
+    if size.is_some() {
+        return Err(format!("Size on a non-memory operand {}", text));
+    }
+    if let Some(reg) = register(rest) {
+        return Ok(Operand::Reg(reg));
+    }
+    if let Some(value) = number(rest) {
+        return Ok(Operand::Imm(value));
+    }
+    Ok(Operand::Label(qualify(rest, scope)))
+}
+
+// The operands of db, dq, resb and resq after a label
+fn data_item(text: &str, scope: &str) -> Result<Item, String> {
+    let (directive, operands) = text.split_once(' ').unwrap_or((text, ""));
+    match directive {
+        "db" => {
+            let mut bytes = Vec::new();
+            let mut rest = operands.trim();
+            while !rest.is_empty() {
+                if let Some(quoted) = rest.strip_prefix('"') {
+                    let end = quoted.find('"').ok_or("Unterminated string")?;
+                    bytes.extend(quoted[..end].bytes());
+                    rest = &quoted[end + 1..];
+                } else {
+                    let end = rest.find(',').unwrap_or(rest.len());
+                    let value = number(rest[..end].trim()).ok_or(format!("Bad byte {}", &rest[..end]))?;
+                    bytes.push(value as u8);
+                    rest = &rest[end..];
+                }
+                rest = rest.trim_start();
+                rest = rest.strip_prefix(',').unwrap_or(rest).trim_start();
+            }
+            Ok(Item::Code(Encoded { bytes, reference: None }))
+        }
+        "dq" => match number(operands.trim()) {
+            Some(value) => Ok(Item::Code(Encoded { bytes: value.to_le_bytes().to_vec(), reference: None })),
+            None => Ok(Item::Code(Encoded {
+                bytes: vec![0; 8],
+                reference: Some((qualify(operands.trim(), scope), 0, Reference::Absolute)),
+            })),
+        },
+        "resb" | "resq" => {
+            let count = number(operands.trim()).ok_or(format!("Bad count {}", operands))? as u64;
+            Ok(Item::Reserve(if directive == "resq" { count * 8 } else { count }))
+        }
+        _ => Err(format!("Unknown directive {}", directive)),
+    }
+}
+
+fn is_byte(value: i64) -> bool {
+    i8::try_from(value).is_ok()
+}
+
+fn is_dword(value: i64) -> bool {
+    i32::try_from(value).is_ok()
+}
+
+// An instruction with a ModRM byte: legacy prefix, REX when needed, opcode,
+// ModRM with `reg` in its reg field and `rm` as the register or memory
+// operand, then any immediate
+fn with_modrm(prefix: &[u8], wide: bool, opcode: &[u8], reg: u8, rm: &Operand, immediate: &[u8]) -> Result<Encoded, String> {
+    let mut rex = if wide { 0x48 } else { 0 };
+    if reg >= 8 {
+        rex |= 0x44;
+    }
+    let mut tail = Vec::new();
+    let mut reference = None;
+    match rm {
+        Operand::Reg(r) => {
+            if r.number >= 8 {
+                rex |= 0x41;
+            }
+            // spl, bpl, sil and dil only exist with a REX prefix
+            if r.size == Size::Byte && (4..8).contains(&r.number) {
+                rex |= 0x40;
+            }
+            tail.push(0xC0 | (reg & 7) << 3 | (r.number & 7));
+        }
+        Operand::Mem(mem) => {
+            if let Some(label) = &mem.label {
+                tail.push((reg & 7) << 3 | 0b101);
+                reference = Some((label.clone(), tail.len(), Reference::Relative));
+                tail.extend([0; 4]);
+            } else {
+                let Some(mut base) = mem.base else { return Err("Absolute address".into()) };
+                let mut index = mem.index;
+                if let Some((i, 1)) = index {
+                    // rsp cannot be an index, and rbp or r13 as the base
+                    // costs a displacement byte the other way round
+                    if i == 4 || (mem.disp == 0 && base & 7 == 5 && i & 7 != 5) {
+                        index = Some((base, 1));
+                        base = i;
+                    }
+                }
+                if base >= 8 {
+                    rex |= 0x41;
+                }
+                let mode = if mem.disp == 0 && base & 7 != 5 {
+                    0b00
+                } else if is_byte(mem.disp) {
+                    0b01
+                } else if is_dword(mem.disp) {
+                    0b10
+                } else {
+                    return Err("Displacement out of range".into());
+                };
+                match index {
+                    Some((index, scale)) => {
+                        if index == 4 {
+                            return Err("rsp cannot be an index".into());
+                        }
+                        if index >= 8 {
+                            rex |= 0x42;
+                        }
+                        tail.push(mode << 6 | (reg & 7) << 3 | 0b100);
+                        tail.push((scale.trailing_zeros() as u8) << 6 | (index & 7) << 3 | (base & 7));
+                    }
+                    None if base & 7 == 4 => {
+                        tail.push(mode << 6 | (reg & 7) << 3 | 0b100);
+                        tail.push(0x24);
+                    }
+                    None => tail.push(mode << 6 | (reg & 7) << 3 | (base & 7)),
+                }
+                match mode {
+                    0b01 => tail.push(mem.disp as u8),
+                    0b10 => tail.extend((mem.disp as i32).to_le_bytes()),
+                    _ => {}
+                }
+            }
+        }
+        _ => return Err("Expected a register or memory operand".into()),
+    }
+    let mut bytes = prefix.to_vec();
+    if rex != 0 {
+        bytes.push(rex);
+    }
+    bytes.extend(opcode);
+    let start = bytes.len();
+    bytes.extend(tail);
+    bytes.extend(immediate);
+    Ok(Encoded { bytes, reference: reference.map(|(label, at, kind)| (label, start + at, kind)) })
+}
+
+// The size of a register or memory operand
+fn size_of(operand: &Operand) -> Option<Size> {
+    match operand {
+        Operand::Reg(reg) => Some(reg.size),
+        Operand::Mem(mem) => mem.size,
+        _ => None,
+    }
+}
+
+// Whether a register or memory operand can hold a value of this size;
+// memory without a size takes that of the register
+fn fits(operand: &Operand, size: Size) -> bool {
+    match operand {
+        Operand::Reg(reg) => reg.size == size,
+        Operand::Mem(mem) => mem.size.is_none_or(|own| own == size),
+        _ => false,
+    }
+}
+
+// spl, bpl, sil and dil in the reg field would need a REX prefix, which no
+// generated instruction has a use for
+fn plain_reg(reg: &Reg) -> bool {
+    reg.size != Size::Byte || !(4..8).contains(&reg.number)
+}
+
+fn plain(bytes: &[u8]) -> Result<Encoded, String> {
+    Ok(Encoded { bytes: bytes.to_vec(), reference: None })
+}
+
+fn encode(mnemonic: &str, operands: &[Operand]) -> Result<Encoded, String> {
+    use Operand::{Imm, Label, Mem, Reg};
+    let gp = |reg: &self::Reg| reg.size == Size::Qword || reg.size == Size::Dword;
+    let xmm = |reg: &self::Reg| reg.size == Size::Xmm;
+
+    if let Some(group) = ["add", "or", "adc", "sbb", "and", "sub", "xor", "cmp"].iter().position(|op| *op == mnemonic) {
+        let group = group as u8;
+        return match operands {
+            [dst, Reg(src)] if fits(dst, src.size) && plain_reg(src) => {
+                let opcode = group << 3 | if src.size == Size::Byte { 0 } else { 1 };
+                with_modrm(&[], src.size == Size::Qword, &[opcode], src.number, dst, &[])
+            }
+            [Reg(dst), src @ Mem(_)] if plain_reg(dst) => {
+                let opcode = group << 3 | if dst.size == Size::Byte { 2 } else { 3 };
+                with_modrm(&[], dst.size == Size::Qword, &[opcode], dst.number, src, &[])
+            }
+            [dst, Imm(value)] => {
+                let size = size_of(dst).ok_or("Operation size not specified")?;
+                let wide = size == Size::Qword;
+                if size == Size::Byte {
+                    with_modrm(&[], false, &[0x80], group, dst, &[*value as u8])
+                } else if is_byte(*value) {
+                    with_modrm(&[], wide, &[0x83], group, dst, &[*value as u8])
+                } else if !is_dword(*value) {
+                    Err("Immediate out of range".into())
+                } else if matches!(dst, Reg(reg) if reg.number == 0) {
+                    let mut bytes = if wide { vec![0x48] } else { Vec::new() };
+                    bytes.push(group << 3 | 5);
+                    bytes.extend((*value as i32).to_le_bytes());
+                    plain(&bytes)
+                } else {
+                    with_modrm(&[], wide, &[0x81], group, dst, &(*value as i32).to_le_bytes())
+                }
+            }
+            _ => Err("Unsupported operands".into()),
+        };
+    }
+
+    match (mnemonic, operands) {
+        ("ret", []) => plain(&[0xC3]),
+        ("cqo", []) => plain(&[0x48, 0x99]),
+        ("syscall", []) => plain(&[0x0F, 0x05]),
+        ("push" | "pop", [Reg(reg)]) if reg.size == Size::Qword => {
+            let opcode = if mnemonic == "push" { 0x50 } else { 0x58 } + (reg.number & 7);
+            plain(&if reg.number >= 8 { vec![0x41, opcode] } else { vec![opcode] })
+        }
+        ("push", [operand @ Mem(_)]) if size_of(operand) == Some(Size::Qword) => with_modrm(&[], false, &[0xFF], 6, operand, &[]),
+        // Sign-extended to 64 bits
+        ("push", [Imm(value)]) if is_byte(*value) => plain(&[0x6A, *value as u8]),
+        ("push", [Imm(value)]) if is_dword(*value) => plain(&[vec![0x68], (*value as i32).to_le_bytes().to_vec()].concat()),
+        ("mov", [dst, Reg(src)]) if !xmm(src) && fits(dst, src.size) && plain_reg(src) => {
+            let opcode = if src.size == Size::Byte { 0x88 } else { 0x89 };
+            with_modrm(&[], src.size == Size::Qword, &[opcode], src.number, dst, &[])
+        }
+        ("mov", [Reg(dst), src @ Mem(_)]) if !xmm(dst) && plain_reg(dst) => {
+            let opcode = if dst.size == Size::Byte { 0x8A } else { 0x8B };
+            with_modrm(&[], dst.size == Size::Qword, &[opcode], dst.number, src, &[])
+        }
+        ("mov", [Reg(dst), Imm(value)]) if gp(dst) => {
+            let rex_b = if dst.number >= 8 { vec![0x41] } else { Vec::new() };
+            let opcode = 0xB8 + (dst.number & 7);
+            match u32::try_from(*value) {
+                // A 32-bit move zero-extends, so it does for any value that fits
+                Ok(low) if dst.size == Size::Qword || is_dword(*value) => {
+                    plain(&[rex_b, vec![opcode], low.to_le_bytes().to_vec()].concat())
+                }
+                _ if dst.size == Size::Dword => plain(&[rex_b, vec![opcode], (*value as u32).to_le_bytes().to_vec()].concat()),
+                _ if is_dword(*value) => with_modrm(&[], true, &[0xC7], 0, &operands[0], &(*value as i32).to_le_bytes()),
+                _ => plain(&[vec![0x48 | rex_b.len() as u8], vec![opcode], value.to_le_bytes().to_vec()].concat()),
+            }
+        }
+        ("mov", [dst @ Mem(mem), Imm(value)]) => match mem.size {
+            Some(Size::Byte) => with_modrm(&[], false, &[0xC6], 0, dst, &[*value as u8]),
+            Some(size @ (Size::Dword | Size::Qword)) if is_dword(*value) => {
+                with_modrm(&[], size == Size::Qword, &[0xC7], 0, dst, &(*value as i32).to_le_bytes())
+            }
+            _ => Err("Operation size not specified".into()),
+        },
+        ("lea", [Reg(dst), src @ Mem(_)]) if gp(dst) => with_modrm(&[], dst.size == Size::Qword, &[0x8D], dst.number, src, &[]),
+        ("imul", [Reg(dst), Imm(value)]) if gp(dst) => {
+            let wide = dst.size == Size::Qword;
+            if is_byte(*value) {
+                with_modrm(&[], wide, &[0x6B], dst.number, &operands[0], &[*value as u8])
+            } else if is_dword(*value) {
+                with_modrm(&[], wide, &[0x69], dst.number, &operands[0], &(*value as i32).to_le_bytes())
+            } else {
+                Err("Immediate out of range".into())
+            }
+        }
+        ("imul", [Reg(dst), src]) if gp(dst) => with_modrm(&[], dst.size == Size::Qword, &[0x0F, 0xAF], dst.number, src, &[]),
+        ("not" | "neg" | "mul" | "div" | "idiv", [operand]) => {
+            let extension = match mnemonic {
+                "not" => 2,
+                "neg" => 3,
+                "mul" => 4,
+                "div" => 6,
+                _ => 7,
+            };
+            let size = size_of(operand).ok_or("Operation size not specified")?;
+            with_modrm(&[], size == Size::Qword, &[0xF7], extension, operand, &[])
+        }
+        ("inc" | "dec", [operand]) => {
+            let size = size_of(operand).ok_or("Operation size not specified")?;
+            with_modrm(&[], size == Size::Qword, &[0xFF], u8::from(mnemonic == "dec"), operand, &[])
+        }
+        ("shl" | "shr" | "sar", [operand, Imm(amount)]) => {
+            let extension = match mnemonic {
+                "shl" => 4,
+                "shr" => 5,
+                _ => 7,
+            };
+            let wide = size_of(operand) == Some(Size::Qword);
+            if *amount == 1 {
+                with_modrm(&[], wide, &[0xD1], extension, operand, &[])
+            } else {
+                with_modrm(&[], wide, &[0xC1], extension, operand, &[*amount as u8])
+            }
+        }
+        ("xchg", [Reg(a), Reg(b)]) if a.size == Size::Qword && b.size == Size::Qword => {
+            if a.number == 0 || b.number == 0 {
+                let other = if a.number == 0 { b.number } else { a.number };
+                plain(&[if other >= 8 { 0x49 } else { 0x48 }, 0x90 + (other & 7)])
+            } else {
+                with_modrm(&[], true, &[0x87], b.number, &operands[0], &[])
+            }
+        }
+        ("movzx", [Reg(dst), src]) if gp(dst) && size_of(src) == Some(Size::Byte) => {
+            with_modrm(&[], dst.size == Size::Qword, &[0x0F, 0xB6], dst.number, src, &[])
+        }
+        ("movsxd", [Reg(dst), src]) if dst.size == Size::Qword && size_of(src) != Some(Size::Qword) => {
+            with_modrm(&[], true, &[0x63], dst.number, src, &[])
+        }
+        ("movq", [Reg(dst), Reg(src)]) if xmm(dst) && gp(src) => with_modrm(&[0x66], true, &[0x0F, 0x6E], dst.number, &operands[1], &[]),
+        ("movq", [Reg(dst), Reg(src)]) if gp(dst) && xmm(src) => with_modrm(&[0x66], true, &[0x0F, 0x7E], src.number, &operands[0], &[]),
+        ("movq", [Reg(dst), src]) if xmm(dst) => with_modrm(&[0xF3], false, &[0x0F, 0x7E], dst.number, src, &[]),
+        ("movq", [dst @ Mem(_), Reg(src)]) if xmm(src) => with_modrm(&[0x66], false, &[0x0F, 0xD6], src.number, dst, &[]),
+        ("addsd" | "subsd" | "mulsd" | "divsd" | "sqrtsd", [Reg(dst), src]) if xmm(dst) => {
+            let opcode = match mnemonic {
+                "addsd" => 0x58,
+                "mulsd" => 0x59,
+                "subsd" => 0x5C,
+                "divsd" => 0x5E,
+                _ => 0x51,
+            };
+            with_modrm(&[0xF2], false, &[0x0F, opcode], dst.number, src, &[])
+        }
+        ("ucomisd", [Reg(dst), src]) if xmm(dst) => with_modrm(&[0x66], false, &[0x0F, 0x2E], dst.number, src, &[]),
+        ("cvtsi2sd", [Reg(dst), src]) if xmm(dst) => with_modrm(&[0xF2], true, &[0x0F, 0x2A], dst.number, src, &[]),
+        ("cvttsd2si", [Reg(dst), src]) if gp(dst) => {
+            with_modrm(&[0xF2], dst.size == Size::Qword, &[0x0F, 0x2C], dst.number, src, &[])
+        }
+        ("call", [Label(target)]) => Ok(Encoded { bytes: vec![0xE8, 0, 0, 0, 0], reference: Some((target.clone(), 1, Reference::Call)) }),
+        ("call" | "jmp", [target @ (Reg(_) | Mem(_))]) => {
+            with_modrm(&[], false, &[0xFF], if mnemonic == "call" { 2 } else { 4 }, target, &[])
+        }
+        (set, [operand]) if set.starts_with("set") && size_of(operand) == Some(Size::Byte) => {
+            let condition = condition_code(&set[3..]).ok_or("Unknown condition")?;
+            with_modrm(&[], false, &[0x0F, 0x90 + condition], 0, operand, &[])
+        }
+        _ => Err("Unsupported instruction".into()),
+    }
+}
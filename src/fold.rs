@@ -0,0 +1,273 @@
+use std::collections::HashMap;
+
+use crate::ast::{assignments, BinaryOperator, Expression, FormatPart, Location, Statement, Type};
+use crate::types::{self, Signatures};
+
+// Constant folding and propagation. Operators applied to literals are
+// evaluated at compile time, variables holding a known literal are replaced by
+// it until they are reassigned, and `if` statements with a constant condition
+// are replaced by the branch that runs. Nothing is folded that would behave
+// differently at runtime: overflowing integer arithmetic is left for the
+// runtime checks, and dividing an integer by a constant zero is an error.
+
+struct Folder {
+    signatures: Signatures,
+    // Variables of the current function known to hold a literal at this point
+    constants: HashMap<String, Expression>,
+    // The type each variable got at its first assignment, which later values
+    // are converted to
+    types: HashMap<String, Type>,
+    // Types of the variables each anonymous function captures, recorded where
+    // its closure is created, which comes before the lifted function
+    capture_types: HashMap<String, Vec<Type>>,
+    // In an inlined body a constant division by zero comes from the caller's
+    // arguments, so it is left to trap at runtime like the call would
+    inlined: bool,
+}
+
+pub fn fold_constants(mut program: Vec<Statement>) -> Result<Vec<Statement>, String> {
+    let mut folder = Folder {
+        signatures: types::signatures(&program),
+        constants: HashMap::new(),
+        types: HashMap::new(),
+        capture_types: HashMap::new(),
+        inlined: false,
+    };
+    for stmt in &mut program {
+        if let Statement::Function { name, params, body, captures, .. } = stmt {
+            folder.constants.clear();
+            folder.types = params.iter().map(|(param, ty)| (param.clone(), ty.unwrap_or(Type::Int))).collect();
+            let capture_types = folder.capture_types.get(name).cloned().unwrap_or_default();
+            for (i, capture) in captures.iter().enumerate() {
+                folder.types.insert(capture.clone(), capture_types.get(i).copied().unwrap_or(Type::Int));
+            }
+            *body = folder.fold_statements(std::mem::take(body))?;
+        }
+    }
+    Ok(program)
+}
+
+impl Folder {
+    fn fold_statements(&mut self, body: Vec<Statement>) -> Result<Vec<Statement>, String> {
+        let mut result = Vec::new();
+        for stmt in body {
+            match stmt {
+                Statement::Assign { name, value } => {
+                    let value = self.fold_expression(value)?;
+                    if !self.types.contains_key(&name) {
+                        let ty = self.value_type(&value);
+                        self.types.insert(name.clone(), ty);
+                    }
+                    // The variable holds the value converted to its type
+                    let constant = match (&value, self.types[&name]) {
+                        (Expression::Integer(value), Type::Float) => Some(Expression::Float(*value as f64)),
+                        _ => Some(value.clone()).filter(is_literal),
+                    };
+                    match constant {
+                        Some(constant) => self.constants.insert(name.clone(), constant),
+                        None => self.constants.remove(&name),
+                    };
+                    result.push(Statement::Assign { name, value });
+                }
+                Statement::Print { args, newline } => {
+                    let args = args.into_iter().map(|arg| self.fold_expression(arg)).collect::<Result<_, _>>()?;
+                    result.push(Statement::Print { args, newline });
+                }
+                Statement::Send(expr) => result.push(Statement::Send(self.fold_expression(expr)?)),
+                Statement::FunctionCall { name, args } => {
+                    let args = args.into_iter().map(|arg| self.fold_expression(arg)).collect::<Result<_, _>>()?;
+                    result.push(Statement::FunctionCall { name, args });
+                }
+                Statement::If { condition, then_body, else_body } => {
+                    let condition = self.fold_expression(condition)?;
+                    if let Expression::Integer(value) = condition {
+                        // Only one branch can run, and it runs unconditionally
+                        let taken = if value != 0 { then_body } else { else_body.unwrap_or_default() };
+                        result.extend(self.fold_statements(taken)?);
+                        continue;
+                    }
+
+                    let before = self.constants.clone();
+                    let then_body = self.fold_statements(then_body)?;
+                    let after_then = std::mem::replace(&mut self.constants, before);
+                    let else_body = else_body.map(|body| self.fold_statements(body)).transpose()?;
+                    // Afterwards only what both paths agree on is known
+                    self.constants.retain(|name, value| {
+                        after_then.get(name).is_some_and(|other| same_literal(value, other))
+                    });
+                    result.push(Statement::If { condition, then_body, else_body });
+                }
+                Statement::While { condition, body } => {
+                    // Anything the loop assigns may differ from one iteration to the next
+                    self.forget_assigned(&body);
+                    let condition = self.fold_expression(condition)?;
+                    let body = self.fold_statements(body)?;
+                    self.forget_assigned(&body);
+                    result.push(Statement::While { condition, body });
+                }
+                Statement::Assert { condition, message, text, location } => {
+                    let condition = self.fold_expression(condition)?;
+                    let message = message.map(|message| self.fold_expression(message)).transpose()?;
+                    result.push(Statement::Assert { condition, message, text, location });
+                }
+                Statement::Inlined { function, params, returns, args, body, result: value } => {
+                    let args: Vec<Expression> = args.into_iter().map(|arg| self.fold_expression(arg)).collect::<Result<_, _>>()?;
+                    // The body sees only its parameters and its own locals
+                    let constants = std::mem::take(&mut self.constants);
+                    let types = std::mem::take(&mut self.types);
+                    let inlined = std::mem::replace(&mut self.inlined, true);
+                    for ((param, ty), arg) in params.iter().zip(&args) {
+                        self.types.insert(param.clone(), ty.unwrap_or(Type::Int));
+                        // Unless the call converts the argument, as from int to float
+                        let converted = match arg {
+                            Expression::Integer(_) => ty.is_some_and(|ty| ty != Type::Int),
+                            Expression::Float(_) => *ty != Some(Type::Float),
+                            _ => *ty != Some(Type::Str),
+                        };
+                        if is_literal(arg) && !converted {
+                            self.constants.insert(param.clone(), arg.clone());
+                        }
+                    }
+                    let body = self.fold_statements(body)?;
+                    self.constants = constants;
+                    self.types = types;
+                    self.inlined = inlined;
+                    self.constants.remove(&value);
+                    self.types.entry(value.clone()).or_insert(returns);
+                    result.push(Statement::Inlined { function, params, returns, args, body, result: value });
+                }
+                other => result.push(other),
+            }
+        }
+        Ok(result)
+    }
+
+    fn value_type(&self, expr: &Expression) -> Type {
+        types::expr_type(expr, &self.types, &self.signatures)
+    }
+
+    fn forget_assigned(&mut self, body: &[Statement]) {
+        for (name, _) in assignments(body) {
+            self.constants.remove(name);
+        }
+    }
+
+    fn fold_expression(&mut self, expr: Expression) -> Result<Expression, String> {
+        match expr {
+            Expression::Variable(name) => Ok(self.constants.get(&name).cloned().unwrap_or(Expression::Variable(name))),
+            Expression::BinaryOp { left, op, right, location } => {
+                let left = self.fold_expression(*left)?;
+                let right = self.fold_expression(*right)?;
+                if op == BinaryOperator::Div && matches!(right, Expression::Integer(0)) {
+                    // A float divided by zero is infinite or NaN, which is not an error
+                    if !self.inlined && self.value_type(&left) == Type::Int {
+                        return Err(format!("Division by zero at {}", location));
+                    }
+                    if !matches!(left, Expression::Float(_)) {
+                        return Ok(Expression::BinaryOp { left: Box::new(left), op, right: Box::new(right), location });
+                    }
+                }
+                fold_binary(left, op, right, location)
+            }
+            Expression::FunctionCall { name, args } => {
+                let args = args.into_iter().map(|arg| self.fold_expression(arg)).collect::<Result<_, _>>()?;
+                Ok(Expression::FunctionCall { name, args })
+            }
+            Expression::Index { value, index } => Ok(Expression::Index {
+                value: Box::new(self.fold_expression(*value)?),
+                index: Box::new(self.fold_expression(*index)?),
+            }),
+            Expression::Format(parts) => {
+                let parts = parts
+                    .into_iter()
+                    .map(|part| match part {
+                        FormatPart::Value { expr, spec } => Ok(FormatPart::Value { expr: self.fold_expression(expr)?, spec }),
+                        text => Ok(text),
+                    })
+                    .collect::<Result<_, String>>()?;
+                Ok(Expression::Format(parts))
+            }
+            Expression::Closure { function, captures } => {
+                let types = captures.iter().map(|capture| self.types.get(capture).copied().unwrap_or(Type::Int)).collect();
+                self.capture_types.insert(function.clone(), types);
+                Ok(Expression::Closure { function, captures })
+            }
+            other => Ok(other),
+        }
+    }
+}
+
+fn fold_binary(left: Expression, op: BinaryOperator, right: Expression, location: Location) -> Result<Expression, String> {
+    let folded = match (&left, &right) {
+        (Expression::Integer(a), Expression::Integer(b)) => fold_int(*a, &op, *b).map(Expression::Integer),
+        (Expression::Float(_), Expression::Integer(_) | Expression::Float(_))
+        | (Expression::Integer(_), Expression::Float(_)) => fold_float(as_float(&left), &op, as_float(&right)),
+        (Expression::StringLiteral(a), Expression::StringLiteral(b)) => match op {
+            BinaryOperator::Add => Some(Expression::StringLiteral(format!("{}{}", a, b))),
+            _ if op.is_comparison() => Some(Expression::Integer(compare(a, &op, b))),
+            _ => None,
+        },
+        _ => None,
+    };
+    Ok(folded.unwrap_or(Expression::BinaryOp {
+        left: Box::new(left),
+        op,
+        right: Box::new(right),
+        location,
+    }))
+}
+
+fn fold_int(a: i64, op: &BinaryOperator, b: i64) -> Option<i64> {
+    match op {
+        // Overflow is left to the runtime, which wraps or traps
+        BinaryOperator::Add => a.checked_add(b),
+        BinaryOperator::Sub => a.checked_sub(b),
+        BinaryOperator::Mul => a.checked_mul(b),
+        BinaryOperator::Div => a.checked_div(b),
+        _ => Some(compare(&a, op, &b)),
+    }
+}
+
+fn fold_float(a: f64, op: &BinaryOperator, b: f64) -> Option<Expression> {
+    let value = match op {
+        BinaryOperator::Add => a + b,
+        BinaryOperator::Sub => a - b,
+        BinaryOperator::Mul => a * b,
+        BinaryOperator::Div => a / b,
+        _ => return Some(Expression::Integer(compare(&a, op, &b))),
+    };
+    Some(Expression::Float(value))
+}
+
+fn compare<T: PartialOrd + ?Sized>(a: &T, op: &BinaryOperator, b: &T) -> i64 {
+    let result = match op {
+        BinaryOperator::Eq => a == b,
+        BinaryOperator::NEq => a != b,
+        BinaryOperator::Lt => a < b,
+        BinaryOperator::LtEq => a <= b,
+        BinaryOperator::Gt => a > b,
+        _ => unreachable!("{:?} is not a comparison", op),
+    };
+    i64::from(result)
+}
+
+fn as_float(expr: &Expression) -> f64 {
+    match expr {
+        Expression::Integer(value) => *value as f64,
+        Expression::Float(value) => *value,
+        _ => unreachable!(),
+    }
+}
+
+fn is_literal(expr: &Expression) -> bool {
+    matches!(expr, Expression::Integer(_) | Expression::Float(_) | Expression::StringLiteral(_))
+}
+
+fn same_literal(a: &Expression, b: &Expression) -> bool {
+    match (a, b) {
+        (Expression::Integer(a), Expression::Integer(b)) => a == b,
+        (Expression::Float(a), Expression::Float(b)) => a.to_bits() == b.to_bits(),
+        (Expression::StringLiteral(a), Expression::StringLiteral(b)) => a == b,
+        _ => false,
+    }
+}
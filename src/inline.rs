@@ -0,0 +1,413 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::ast::{assignments, Expression, FormatPart, Statement, Type};
+
+// Function inlining. Calls to small functions, and to those marked `@inline`,
+// are replaced by the body of the function with its parameters and locals
+// renamed to `<function>.<n>.<name>`, which no variable of the caller can be
+// called. A `send` in the body stores its value in `<function>.<n>` and
+// jumps past the body, and the call becomes a read of that variable.
+// Recursive functions, closures, `main` and anything marked `@noinline` are
+// never inlined.
+//
+// The inlined body runs before the rest of the statement containing the call,
+// so a call is only inlined when nothing evaluated before it, other than its
+// own arguments, could run code or fail. Calls in a `while` condition, which
+// is evaluated again on every iteration, are left alone.
+
+// Largest body, counting statements and expressions, inlined without `@inline`
+const MAX_SIZE: usize = 12;
+
+struct Callee {
+    params: Vec<(String, Option<Type>)>,
+    returns: Type,
+    body: Vec<Statement>,
+    // Parameters and every variable the body assigns
+    locals: HashSet<String>,
+}
+
+struct Inliner {
+    callees: HashMap<String, Callee>,
+    count: usize,
+}
+
+// What evaluating an expression up to the first call that can be inlined found
+enum Found {
+    Inlined(Statement),
+    // Nothing that runs code or can fail
+    Pure,
+    Impure,
+}
+
+pub fn inline_functions(mut program: Vec<Statement>) -> Result<Vec<Statement>, String> {
+    let recursive = recursive_functions(&program);
+    let mut callees = HashMap::new();
+    for stmt in &program {
+        let Statement::Function { name, params, returns, body, captures, attributes } = stmt else {
+            continue;
+        };
+        let inline = attributes.iter().any(|attribute| attribute == "inline");
+        let noinline = attributes.iter().any(|attribute| attribute == "noinline");
+        if inline && noinline {
+            return Err(format!("Function {} cannot be both @inline and @noinline", name));
+        }
+        if inline && recursive.contains(name.as_str()) {
+            return Err(format!("Function {} is recursive and cannot be @inline", name));
+        }
+        if inline && (!captures.is_empty() || name == "main") {
+            return Err(format!("Function {} cannot be @inline", name));
+        }
+        let eligible = !noinline
+            && !recursive.contains(name.as_str())
+            && captures.is_empty()
+            && name != "main"
+            && !creates_closure(body);
+        if eligible && (inline || size(body) <= MAX_SIZE) {
+            let locals = params
+                .iter()
+                .map(|(param, _)| param.clone())
+                .chain(assignments(body).into_iter().map(|(name, _)| name.to_string()))
+                .collect();
+            callees.insert(name.clone(), Callee { params: params.clone(), returns: *returns, body: body.clone(), locals });
+        }
+    }
+
+    let mut inliner = Inliner { callees, count: 0 };
+    for stmt in &mut program {
+        if let Statement::Function { params, body, captures, .. } = stmt {
+            let locals: HashSet<String> = params
+                .iter()
+                .map(|(param, _)| param.clone())
+                .chain(captures.iter().cloned())
+                .chain(assignments(body).into_iter().map(|(name, _)| name.to_string()))
+                .collect();
+            *body = inliner.inline_statements(std::mem::take(body), &locals);
+        }
+    }
+    Ok(program)
+}
+
+impl Inliner {
+    // `locals` are the variables of the function the statements belong to
+    fn inline_statements(&mut self, body: Vec<Statement>, locals: &HashSet<String>) -> Vec<Statement> {
+        let mut result = Vec::new();
+        for mut stmt in body {
+            // Take the calls out one at a time, in the order they are evaluated
+            while let Some(inlined) = self.inline_in_statement(&mut stmt, locals) {
+                result.push(inlined);
+            }
+            match &mut stmt {
+                Statement::If { then_body, else_body, .. } => {
+                    *then_body = self.inline_statements(std::mem::take(then_body), locals);
+                    if let Some(else_body) = else_body {
+                        *else_body = self.inline_statements(std::mem::take(else_body), locals);
+                    }
+                }
+                Statement::While { body, .. } => {
+                    *body = self.inline_statements(std::mem::take(body), locals);
+                }
+                _ => {}
+            }
+            result.push(stmt);
+        }
+        result
+    }
+
+    fn inline_in_statement(&mut self, stmt: &mut Statement, locals: &HashSet<String>) -> Option<Statement> {
+        let found = match stmt {
+            Statement::Assign { value, .. } | Statement::Send(value) => self.inline_in_expression(value, locals),
+            Statement::Print { args, .. } => self.inline_in_sequence(args, locals),
+            Statement::If { condition, .. } | Statement::Assert { condition, .. } => {
+                self.inline_in_expression(condition, locals)
+            }
+            Statement::FunctionCall { name, args } => match self.inline_in_sequence(args, locals) {
+                Found::Inlined(inlined) => Found::Inlined(inlined),
+                // A call whose value is unused becomes the body alone
+                _ => {
+                    if let Some(inlined) = self.inline_call(name, args, locals) {
+                        *stmt = inlined;
+                    }
+                    Found::Impure
+                }
+            },
+            _ => Found::Impure,
+        };
+        match found {
+            Found::Inlined(inlined) => Some(inlined),
+            _ => None,
+        }
+    }
+
+    fn inline_in_expression(&mut self, expr: &mut Expression, locals: &HashSet<String>) -> Found {
+        match expr {
+            Expression::Integer(_)
+            | Expression::Float(_)
+            | Expression::StringLiteral(_)
+            | Expression::Variable(_)
+            | Expression::FunctionRef(_)
+            | Expression::Lambda { .. }
+            | Expression::Closure { .. } => Found::Pure,
+            Expression::BinaryOp { left, op, right, .. } => {
+                let found = match self.inline_in_expression(left, locals) {
+                    Found::Pure => self.inline_in_expression(right, locals),
+                    found => found,
+                };
+                match found {
+                    // Arithmetic can overflow or divide by zero
+                    Found::Pure if !op.is_comparison() => Found::Impure,
+                    found => found,
+                }
+            }
+            Expression::FunctionCall { name, args } => {
+                if let Found::Inlined(inlined) = self.inline_in_sequence(args, locals) {
+                    return Found::Inlined(inlined);
+                }
+                match self.inline_call(name, args, locals) {
+                    Some(inlined) => {
+                        let Statement::Inlined { result, .. } = &inlined else { unreachable!() };
+                        *expr = Expression::Variable(result.clone());
+                        Found::Inlined(inlined)
+                    }
+                    None => Found::Impure,
+                }
+            }
+            Expression::Index { value, index } => match self.inline_in_expression(value, locals) {
+                Found::Pure => self.inline_in_expression(index, locals),
+                found => found,
+            },
+            Expression::Format(parts) => {
+                let mut found = Found::Pure;
+                for part in parts {
+                    if let FormatPart::Value { expr, .. } = part {
+                        found = self.inline_in_expression(expr, locals);
+                        if !matches!(found, Found::Pure) {
+                            break;
+                        }
+                    }
+                }
+                found
+            }
+        }
+    }
+
+    // Expressions evaluated one after another, such as arguments
+    fn inline_in_sequence(&mut self, exprs: &mut [Expression], locals: &HashSet<String>) -> Found {
+        for expr in exprs {
+            match self.inline_in_expression(expr, locals) {
+                Found::Pure => {}
+                found => return found,
+            }
+        }
+        Found::Pure
+    }
+
+    // The body of the callee as an Inlined statement, or None when `name` is
+    // not inlined here
+    fn inline_call(&mut self, name: &str, args: &[Expression], locals: &HashSet<String>) -> Option<Statement> {
+        // A variable holding a function value shadows the function
+        if locals.contains(name) {
+            return None;
+        }
+        let callee = self.callees.get(name)?;
+        self.count += 1;
+        let prefix = format!("{}.{}", name, self.count);
+        let renamed: HashMap<&str, String> =
+            callee.locals.iter().map(|local| (local.as_str(), format!("{}.{}", prefix, local))).collect();
+        let returns = callee.returns;
+        let params = callee.params.iter().map(|(param, ty)| (renamed[param.as_str()].clone(), *ty)).collect();
+        let mut body = callee.body.clone();
+        rename_statements(&mut body, &renamed);
+
+        let inner_locals = renamed.values().cloned().collect();
+        let body = self.inline_statements(body, &inner_locals);
+        // Functions the body calls must not be shadowed by the caller's variables
+        if references(&body).into_iter().any(|name| locals.contains(name)) {
+            return None;
+        }
+        Some(Statement::Inlined { function: name.to_string(), params, returns, args: args.to_vec(), body, result: prefix })
+    }
+}
+
+fn rename_statements(body: &mut [Statement], renamed: &HashMap<&str, String>) {
+    let rename = |name: &mut String| {
+        if let Some(new) = renamed.get(name.as_str()) {
+            *name = new.clone();
+        }
+    };
+    for stmt in body {
+        match stmt {
+            Statement::Assign { name, value } => {
+                rename(name);
+                rename_expression(value, renamed);
+            }
+            Statement::Print { args, .. } => args.iter_mut().for_each(|arg| rename_expression(arg, renamed)),
+            Statement::Send(expr) => rename_expression(expr, renamed),
+            Statement::FunctionCall { name, args } => {
+                rename(name);
+                args.iter_mut().for_each(|arg| rename_expression(arg, renamed));
+            }
+            Statement::If { condition, then_body, else_body } => {
+                rename_expression(condition, renamed);
+                rename_statements(then_body, renamed);
+                if let Some(else_body) = else_body {
+                    rename_statements(else_body, renamed);
+                }
+            }
+            Statement::While { condition, body } => {
+                rename_expression(condition, renamed);
+                rename_statements(body, renamed);
+            }
+            Statement::Assert { condition, message, .. } => {
+                rename_expression(condition, renamed);
+                if let Some(message) = message {
+                    rename_expression(message, renamed);
+                }
+            }
+            Statement::Inlined { args, body, .. } => {
+                args.iter_mut().for_each(|arg| rename_expression(arg, renamed));
+                rename_statements(body, renamed);
+            }
+            Statement::Function { .. } | Statement::Extern { .. } | Statement::Use(_) => {}
+        }
+    }
+}
+
+fn rename_expression(expr: &mut Expression, renamed: &HashMap<&str, String>) {
+    match expr {
+        Expression::Variable(name) => {
+            if let Some(new) = renamed.get(name.as_str()) {
+                *name = new.clone();
+            }
+        }
+        Expression::FunctionCall { name, args } => {
+            // Calls through a parameter or local holding a function value
+            if let Some(new) = renamed.get(name.as_str()) {
+                *name = new.clone();
+            }
+            args.iter_mut().for_each(|arg| rename_expression(arg, renamed));
+        }
+        Expression::BinaryOp { left, right, .. } => {
+            rename_expression(left, renamed);
+            rename_expression(right, renamed);
+        }
+        Expression::Index { value, index } => {
+            rename_expression(value, renamed);
+            rename_expression(index, renamed);
+        }
+        Expression::Format(parts) => {
+            for part in parts {
+                if let FormatPart::Value { expr, .. } = part {
+                    rename_expression(expr, renamed);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+// Functions that can reach themselves through calls or function values
+fn recursive_functions(program: &[Statement]) -> HashSet<&str> {
+    let mut calls: HashMap<&str, Vec<&str>> = HashMap::new();
+    for stmt in program {
+        if let Statement::Function { name, body, .. } = stmt {
+            calls.insert(name, references(body));
+        }
+    }
+
+    let mut recursive = HashSet::new();
+    for &start in calls.keys() {
+        let mut seen = HashSet::new();
+        let mut pending = calls[start].clone();
+        while let Some(name) = pending.pop() {
+            if name == start {
+                recursive.insert(start);
+                break;
+            }
+            if seen.insert(name) {
+                pending.extend(calls.get(name).into_iter().flatten());
+            }
+        }
+    }
+    recursive
+}
+
+// Names a body calls or takes as a value
+fn references(body: &[Statement]) -> Vec<&str> {
+    let mut found = Vec::new();
+    for stmt in statements(body) {
+        if let Statement::FunctionCall { name, .. } = stmt {
+            found.push(name.as_str());
+        }
+        for expr in expressions(stmt) {
+            match expr {
+                Expression::FunctionCall { name, .. }
+                | Expression::FunctionRef(name)
+                | Expression::Closure { function: name, .. } => found.push(name),
+                _ => {}
+            }
+        }
+    }
+    found
+}
+
+// Number of statements and expressions in a body
+fn size(body: &[Statement]) -> usize {
+    statements(body).into_iter().map(|stmt| 1 + expressions(stmt).len()).sum()
+}
+
+fn creates_closure(body: &[Statement]) -> bool {
+    statements(body).into_iter().flat_map(expressions).any(|expr| matches!(expr, Expression::Closure { .. }))
+}
+
+// Every statement of a body, including those in nested blocks
+fn statements(body: &[Statement]) -> Vec<&Statement> {
+    let mut found = Vec::new();
+    for stmt in body {
+        found.push(stmt);
+        match stmt {
+            Statement::If { then_body, else_body, .. } => {
+                found.extend(statements(then_body));
+                if let Some(else_body) = else_body {
+                    found.extend(statements(else_body));
+                }
+            }
+            Statement::While { body, .. } | Statement::Inlined { body, .. } => found.extend(statements(body)),
+            _ => {}
+        }
+    }
+    found
+}
+
+// Every expression a statement evaluates itself, with their subexpressions
+fn expressions(stmt: &Statement) -> Vec<&Expression> {
+    let mut pending: Vec<&Expression> = match stmt {
+        Statement::Assign { value, .. } | Statement::Send(value) => vec![value],
+        Statement::Print { args, .. } | Statement::FunctionCall { args, .. } | Statement::Inlined { args, .. } => {
+            args.iter().collect()
+        }
+        Statement::If { condition, .. } | Statement::While { condition, .. } => vec![condition],
+        Statement::Assert { condition, message, .. } => std::iter::once(condition).chain(message).collect(),
+        Statement::Function { .. } | Statement::Extern { .. } | Statement::Use(_) => Vec::new(),
+    };
+    let mut found = Vec::new();
+    while let Some(expr) = pending.pop() {
+        found.push(expr);
+        match expr {
+            Expression::BinaryOp { left, right, .. } | Expression::Index { value: left, index: right } => {
+                pending.push(left);
+                pending.push(right);
+            }
+            Expression::FunctionCall { args, .. } => pending.extend(args),
+            Expression::Lambda { body, .. } => pending.push(body),
+            Expression::Format(parts) => {
+                for part in parts {
+                    if let FormatPart::Value { expr, .. } = part {
+                        pending.push(expr);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    found
+}
@@ -0,0 +1,875 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use crate::ast::{BinaryOperator, Expression, FormatPart, FormatSpec, Location, Statement, Type};
+use crate::builtins;
+use crate::types::Signatures;
+
+// Mid-level intermediate representation. A function is a control-flow graph
+// of basic blocks, each holding three-address instructions over as many
+// virtual registers as it needs and ending in an explicit terminator. Block 0
+// is the entry and only jumps on, so it has no predecessors.
+//
+// Only functions working purely on integers are lowered; the rest go through
+// the stack-based code generator.
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct VReg(pub usize);
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Operand {
+    Reg(VReg),
+    Imm(i64),
+}
+
+#[derive(Clone)]
+pub enum PrintPart {
+    Text(String),
+    Value(Operand, FormatSpec),
+}
+
+#[derive(Clone)]
+pub enum Inst {
+    Copy { dst: VReg, src: Operand },
+    // Arithmetic, or a comparison giving 0 or 1
+    Binary { op: BinaryOperator, dst: VReg, lhs: Operand, rhs: Operand, location: Location },
+    // Only introduced by optimizations, for multiplying and dividing by powers of two
+    Shift { op: Shift, dst: VReg, src: Operand, amount: u32 },
+    Call { dst: Option<VReg>, name: String, args: Vec<Operand> },
+    Print { parts: Vec<PrintPart>, newline: bool },
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Shift {
+    Left,
+    // Copies the sign bit in
+    Right,
+    // Shifts zeros in
+    RightLogical,
+}
+
+#[derive(Clone)]
+pub enum Terminator {
+    Jump(usize),
+    // Continues in `then_block` if the comparison holds, else in `else_block`
+    Branch { op: BinaryOperator, lhs: Operand, rhs: Operand, then_block: usize, else_block: usize },
+    Return(Operand),
+    // Leaves this function's frame and continues in another function
+    TailCall { name: String, args: Vec<Operand> },
+}
+
+// `dst` takes the operand listed for the predecessor control came from
+#[derive(Clone)]
+pub struct Phi {
+    pub dst: VReg,
+    pub incoming: Vec<(usize, Operand)>,
+}
+
+#[derive(Clone)]
+pub struct Block {
+    // Only present in SSA form
+    pub phis: Vec<Phi>,
+    pub insts: Vec<Inst>,
+    pub terminator: Terminator,
+}
+
+#[derive(Clone)]
+pub struct Function {
+    pub name: String,
+    pub params: Vec<VReg>,
+    pub blocks: Vec<Block>,
+    pub vreg_count: usize,
+    // Whether every virtual register is assigned exactly once
+    pub ssa: bool,
+}
+
+// Integer arguments a call can pass in registers, and printf values after the format
+pub const MAX_ARGS: usize = 6;
+pub const MAX_PRINT_VALUES: usize = 5;
+
+fn registers(operands: &[Operand]) -> Vec<VReg> {
+    operands
+        .iter()
+        .filter_map(|operand| match operand {
+            Operand::Reg(vreg) => Some(*vreg),
+            Operand::Imm(_) => None,
+        })
+        .collect()
+}
+
+impl Inst {
+    pub fn uses(&self) -> Vec<VReg> {
+        match self {
+            Inst::Copy { src, .. } | Inst::Shift { src, .. } => registers(&[*src]),
+            Inst::Binary { lhs, rhs, .. } => registers(&[*lhs, *rhs]),
+            Inst::Call { args, .. } => registers(args),
+            Inst::Print { parts, .. } => {
+                let values: Vec<Operand> = parts
+                    .iter()
+                    .filter_map(|part| match part {
+                        PrintPart::Value(operand, _) => Some(*operand),
+                        PrintPart::Text(_) => None,
+                    })
+                    .collect();
+                registers(&values)
+            }
+        }
+    }
+
+    pub fn def(&self) -> Option<VReg> {
+        match self {
+            Inst::Copy { dst, .. } | Inst::Binary { dst, .. } | Inst::Shift { dst, .. } => Some(*dst),
+            Inst::Call { dst, .. } => *dst,
+            Inst::Print { .. } => None,
+        }
+    }
+
+    // Whether the instruction calls out and clobbers the caller-saved registers
+    pub fn is_call(&self) -> bool {
+        matches!(self, Inst::Call { .. } | Inst::Print { .. })
+    }
+
+    pub fn operands_mut(&mut self) -> Vec<&mut Operand> {
+        match self {
+            Inst::Copy { src, .. } | Inst::Shift { src, .. } => vec![src],
+            Inst::Binary { lhs, rhs, .. } => vec![lhs, rhs],
+            Inst::Call { args, .. } => args.iter_mut().collect(),
+            Inst::Print { parts, .. } => parts
+                .iter_mut()
+                .filter_map(|part| match part {
+                    PrintPart::Value(operand, _) => Some(operand),
+                    PrintPart::Text(_) => None,
+                })
+                .collect(),
+        }
+    }
+
+    pub fn def_mut(&mut self) -> Option<&mut VReg> {
+        match self {
+            Inst::Copy { dst, .. } | Inst::Binary { dst, .. } | Inst::Shift { dst, .. } => Some(dst),
+            Inst::Call { dst, .. } => dst.as_mut(),
+            Inst::Print { .. } => None,
+        }
+    }
+}
+
+impl Terminator {
+    pub fn uses(&self) -> Vec<VReg> {
+        match self {
+            Terminator::Jump(_) => vec![],
+            Terminator::Branch { lhs, rhs, .. } => registers(&[*lhs, *rhs]),
+            Terminator::Return(value) => registers(&[*value]),
+            Terminator::TailCall { args, .. } => registers(args),
+        }
+    }
+
+    pub fn successors(&self) -> Vec<usize> {
+        match self {
+            Terminator::Jump(target) => vec![*target],
+            Terminator::Branch { then_block, else_block, .. } => vec![*then_block, *else_block],
+            Terminator::Return(_) | Terminator::TailCall { .. } => vec![],
+        }
+    }
+
+    pub fn operands_mut(&mut self) -> Vec<&mut Operand> {
+        match self {
+            Terminator::Jump(_) => vec![],
+            Terminator::Branch { lhs, rhs, .. } => vec![lhs, rhs],
+            Terminator::Return(value) => vec![value],
+            Terminator::TailCall { args, .. } => args.iter_mut().collect(),
+        }
+    }
+
+    pub fn successors_mut(&mut self) -> Vec<&mut usize> {
+        match self {
+            Terminator::Jump(target) => vec![target],
+            Terminator::Branch { then_block, else_block, .. } => vec![then_block, else_block],
+            Terminator::Return(_) | Terminator::TailCall { .. } => vec![],
+        }
+    }
+}
+
+impl Function {
+    pub fn new_vreg(&mut self) -> VReg {
+        self.vreg_count += 1;
+        VReg(self.vreg_count - 1)
+    }
+
+    pub fn predecessors(&self) -> Vec<Vec<usize>> {
+        let mut predecessors = vec![Vec::new(); self.blocks.len()];
+        for (index, block) in self.blocks.iter().enumerate() {
+            for successor in block.terminator.successors() {
+                if !predecessors[successor].contains(&index) {
+                    predecessors[successor].push(index);
+                }
+            }
+        }
+        predecessors
+    }
+
+    // Blocks in reverse postorder from the entry
+    pub fn reverse_postorder(&self) -> Vec<usize> {
+        let mut order = Vec::new();
+        let mut visited = vec![false; self.blocks.len()];
+        // Depth-first, with each block's successors still to visit
+        let mut stack = vec![(0, self.blocks[0].terminator.successors())];
+        visited[0] = true;
+        while let Some((block, successors)) = stack.last_mut() {
+            match successors.pop() {
+                Some(next) if !visited[next] => {
+                    visited[next] = true;
+                    stack.push((next, self.blocks[next].terminator.successors()));
+                }
+                Some(_) => {}
+                None => {
+                    order.push(*block);
+                    stack.pop();
+                }
+            }
+        }
+        order.reverse();
+        order
+    }
+
+    // Immediate dominator of each block, the entry being its own, by the
+    // iterative algorithm of Cooper, Harvey and Kennedy
+    pub fn dominators(&self) -> Vec<usize> {
+        let order = self.reverse_postorder();
+        let mut rank = vec![usize::MAX; self.blocks.len()];
+        for (i, &block) in order.iter().enumerate() {
+            rank[block] = i;
+        }
+        let predecessors = self.predecessors();
+        let mut idom = vec![usize::MAX; self.blocks.len()];
+        idom[0] = 0;
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &block in &order[1..] {
+                let mut processed = predecessors[block].iter().copied().filter(|&pred| idom[pred] != usize::MAX);
+                let Some(first) = processed.next() else { continue };
+                let mut new_idom = first;
+                for pred in processed {
+                    // Walk both up the tree to their closest common dominator
+                    let (mut a, mut b) = (pred, new_idom);
+                    while a != b {
+                        while rank[a] > rank[b] {
+                            a = idom[a];
+                        }
+                        while rank[b] > rank[a] {
+                            b = idom[b];
+                        }
+                    }
+                    new_idom = a;
+                }
+                if idom[block] != new_idom {
+                    idom[block] = new_idom;
+                    changed = true;
+                }
+            }
+        }
+        idom
+    }
+
+    // Puts a block at `index`, renumbering the blocks from there on
+    pub fn insert_block(&mut self, index: usize, block: Block) {
+        self.blocks.insert(index, block);
+        for block in &mut self.blocks {
+            for target in block.terminator.successors_mut() {
+                if *target >= index {
+                    *target += 1;
+                }
+            }
+            for phi in &mut block.phis {
+                for (pred, _) in &mut phi.incoming {
+                    if *pred >= index {
+                        *pred += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    // Registers whose value on entry to each block may still be read, outside SSA form
+    pub fn live_in(&self) -> Vec<HashSet<VReg>> {
+        let mut live_in = vec![HashSet::new(); self.blocks.len()];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (index, block) in self.blocks.iter().enumerate().rev() {
+                let mut live: HashSet<VReg> =
+                    block.terminator.successors().into_iter().flat_map(|next| live_in[next].iter().copied()).collect();
+                live.extend(block.terminator.uses());
+                for inst in block.insts.iter().rev() {
+                    if let Some(vreg) = inst.def() {
+                        live.remove(&vreg);
+                    }
+                    live.extend(inst.uses());
+                }
+                if live != live_in[index] {
+                    live_in[index] = live;
+                    changed = true;
+                }
+            }
+        }
+        live_in
+    }
+
+    // Drops blocks no path from the entry reaches, renumbering the others
+    pub fn remove_unreachable_blocks(&mut self) {
+        let mut reachable = self.reverse_postorder();
+        reachable.sort();
+        let mut renumbered = vec![usize::MAX; self.blocks.len()];
+        for (new, &old) in reachable.iter().enumerate() {
+            renumbered[old] = new;
+        }
+        let blocks = std::mem::take(&mut self.blocks);
+        for (old, mut block) in blocks.into_iter().enumerate() {
+            if renumbered[old] == usize::MAX {
+                continue;
+            }
+            for target in block.terminator.successors_mut() {
+                *target = renumbered[*target];
+            }
+            for phi in &mut block.phis {
+                phi.incoming.retain(|(pred, _)| renumbered[*pred] != usize::MAX);
+                for (pred, _) in &mut phi.incoming {
+                    *pred = renumbered[*pred];
+                }
+            }
+            self.blocks.push(block);
+        }
+    }
+}
+
+struct Lowerer<'a> {
+    signatures: &'a Signatures,
+    externs: &'a [String],
+    function: Function,
+    vars: HashMap<String, VReg>,
+    // Block instructions are added to, and whether it still lacks its terminator
+    current: usize,
+    open: bool,
+    // Register and continuation block a `send` in an inlined body goes to
+    exit: Option<(VReg, usize)>,
+}
+
+// Lowers a function, or returns None when it uses anything beyond integer
+// variables, arithmetic, comparisons, control flow, direct calls and printing
+pub fn lower_function(
+    function: &Statement,
+    signatures: &Signatures,
+    externs: &[String],
+) -> Option<Function> {
+    let Statement::Function { name, params, returns, body, captures, .. } = function else {
+        return None;
+    };
+    // main(args) receives a list built by the runtime
+    if *returns != Type::Int || !captures.is_empty() || (name == "main" && !params.is_empty()) {
+        return None;
+    }
+    if params.len() > MAX_ARGS || params.iter().any(|(_, ty)| ty.unwrap_or(Type::Int) != Type::Int) {
+        return None;
+    }
+
+    let mut lowerer = Lowerer {
+        signatures,
+        externs,
+        function: Function {
+            name: name.clone(),
+            params: Vec::new(),
+            blocks: Vec::new(),
+            vreg_count: 0,
+            ssa: false,
+        },
+        vars: HashMap::new(),
+        current: 0,
+        open: false,
+        exit: None,
+    };
+    for (param, _) in params {
+        let vreg = lowerer.function.new_vreg();
+        lowerer.vars.insert(param.clone(), vreg);
+        lowerer.function.params.push(vreg);
+    }
+    // Self tail calls jump back to the body, so it gets a block of its own
+    let entry = lowerer.new_block();
+    let body_block = lowerer.new_block();
+    lowerer.start(entry);
+    lowerer.terminate(Terminator::Jump(body_block));
+    lowerer.start(body_block);
+    lowerer.lower_statements(body, body_block)?;
+    // Falling off the end returns 0
+    lowerer.terminate(Terminator::Return(Operand::Imm(0)));
+
+    let mut function = lowerer.function;
+    function.remove_unreachable_blocks();
+    Some(function)
+}
+
+impl Lowerer<'_> {
+    fn new_block(&mut self) -> usize {
+        self.function.blocks.push(Block {
+            phis: Vec::new(),
+            insts: Vec::new(),
+            terminator: Terminator::Return(Operand::Imm(0)),
+        });
+        self.function.blocks.len() - 1
+    }
+
+    fn start(&mut self, block: usize) {
+        self.current = block;
+        self.open = true;
+    }
+
+    fn emit(&mut self, inst: Inst) {
+        if !self.open {
+            // Nothing jumps here; the block is removed once lowering is done
+            let block = self.new_block();
+            self.start(block);
+        }
+        self.function.blocks[self.current].insts.push(inst);
+    }
+
+    // Ends the current block, unless a `send` already has
+    fn terminate(&mut self, terminator: Terminator) {
+        if self.open {
+            self.function.blocks[self.current].terminator = terminator;
+            self.open = false;
+        }
+    }
+
+    fn lower_statements(&mut self, body: &[Statement], body_block: usize) -> Option<()> {
+        for stmt in body {
+            match stmt {
+                Statement::Assign { name, value } => {
+                    let first_temp = self.function.vreg_count;
+                    let value = self.lower_expression(value)?;
+                    let var = match self.vars.get(name) {
+                        Some(&var) => var,
+                        None => {
+                            let var = self.function.new_vreg();
+                            self.vars.insert(name.clone(), var);
+                            var
+                        }
+                    };
+                    // Write a freshly computed value straight into the variable
+                    let last = self.function.blocks[self.current].insts.last_mut().filter(|_| self.open);
+                    match (value, last.and_then(|inst| inst.def_mut())) {
+                        (Operand::Reg(temp), Some(dst)) if *dst == temp && temp.0 >= first_temp => *dst = var,
+                        _ => self.emit(Inst::Copy { dst: var, src: value }),
+                    }
+                }
+                Statement::If { condition, then_body, else_body } => {
+                    let then_block = self.new_block();
+                    let else_block = else_body.as_ref().map(|_| self.new_block());
+                    let end_block = self.new_block();
+                    self.lower_branch(condition, then_block, else_block.unwrap_or(end_block))?;
+                    self.start(then_block);
+                    self.lower_statements(then_body, body_block)?;
+                    self.terminate(Terminator::Jump(end_block));
+                    if let (Some(else_block), Some(else_body)) = (else_block, else_body) {
+                        self.start(else_block);
+                        self.lower_statements(else_body, body_block)?;
+                        self.terminate(Terminator::Jump(end_block));
+                    }
+                    self.start(end_block);
+                }
+                Statement::While { condition, body } => {
+                    let header = self.new_block();
+                    let loop_body = self.new_block();
+                    let end_block = self.new_block();
+                    self.terminate(Terminator::Jump(header));
+                    self.start(header);
+                    self.lower_branch(condition, loop_body, end_block)?;
+                    self.start(loop_body);
+                    self.lower_statements(body, body_block)?;
+                    self.terminate(Terminator::Jump(header));
+                    self.start(end_block);
+                }
+                Statement::Send(expr) if self.exit.is_some() => {
+                    let (result, end_block) = self.exit.unwrap();
+                    let value = self.lower_expression(expr)?;
+                    self.emit(Inst::Copy { dst: result, src: value });
+                    self.terminate(Terminator::Jump(end_block));
+                }
+                Statement::Send(Expression::FunctionCall { name, args }) if self.is_tail_callable(name) => {
+                    let args = self.lower_arguments(name, args)?;
+                    if *name == self.function.name {
+                        // Reassign the parameters and start the body over. Arguments
+                        // that are other parameters are copied first, since those
+                        // may be reassigned before they are read.
+                        let params = self.function.params.clone();
+                        let args: Vec<Operand> = args
+                            .into_iter()
+                            .zip(&params)
+                            .map(|(arg, param)| match arg {
+                                Operand::Reg(vreg) if vreg != *param && params.contains(&vreg) => {
+                                    let temp = self.function.new_vreg();
+                                    self.emit(Inst::Copy { dst: temp, src: arg });
+                                    Operand::Reg(temp)
+                                }
+                                _ => arg,
+                            })
+                            .collect();
+                        for (param, arg) in params.into_iter().zip(args) {
+                            if arg != Operand::Reg(param) {
+                                self.emit(Inst::Copy { dst: param, src: arg });
+                            }
+                        }
+                        self.terminate(Terminator::Jump(body_block));
+                    } else {
+                        self.terminate(Terminator::TailCall { name: name.clone(), args });
+                    }
+                }
+                Statement::Send(expr) => {
+                    let value = self.lower_expression(expr)?;
+                    self.terminate(Terminator::Return(value));
+                }
+                Statement::FunctionCall { name, args } => {
+                    let args = self.lower_arguments(name, args)?;
+                    self.emit(Inst::Call { dst: None, name: name.clone(), args });
+                }
+                Statement::Print { args, newline } => {
+                    let mut parts = Vec::new();
+                    for (i, arg) in args.iter().enumerate() {
+                        if i > 0 {
+                            parts.push(PrintPart::Text(" ".into()));
+                        }
+                        match arg {
+                            Expression::StringLiteral(text) => parts.push(PrintPart::Text(text.clone())),
+                            Expression::Format(format) => {
+                                for part in format {
+                                    parts.push(match part {
+                                        FormatPart::Text(text) => PrintPart::Text(text.clone()),
+                                        FormatPart::Value { expr, spec } => {
+                                            PrintPart::Value(self.lower_expression(expr)?, spec.clone())
+                                        }
+                                    });
+                                }
+                            }
+                            _ => parts.push(PrintPart::Value(self.lower_expression(arg)?, FormatSpec::default())),
+                        }
+                    }
+                    let values = parts.iter().filter(|part| matches!(part, PrintPart::Value(..))).count();
+                    if values > MAX_PRINT_VALUES {
+                        return None;
+                    }
+                    self.emit(Inst::Print { parts, newline: *newline });
+                }
+                Statement::Inlined { params, returns, args, body, result, .. } => {
+                    if *returns != Type::Int || params.iter().any(|(_, ty)| ty.unwrap_or(Type::Int) != Type::Int) {
+                        return None;
+                    }
+                    for ((param, _), arg) in params.iter().zip(args) {
+                        let value = self.lower_expression(arg)?;
+                        let var = self.function.new_vreg();
+                        self.emit(Inst::Copy { dst: var, src: value });
+                        self.vars.insert(param.clone(), var);
+                    }
+                    let var = self.function.new_vreg();
+                    let end_block = self.new_block();
+                    let outer = self.exit.replace((var, end_block));
+                    self.lower_statements(body, body_block)?;
+                    self.exit = outer;
+                    // Falling off the end sends 0
+                    self.emit(Inst::Copy { dst: var, src: Operand::Imm(0) });
+                    self.terminate(Terminator::Jump(end_block));
+                    self.start(end_block);
+                    self.vars.insert(result.clone(), var);
+                }
+                _ => return None,
+            }
+        }
+        Some(())
+    }
+
+    // Ends the current block with a branch on whether the condition is nonzero
+    fn lower_branch(&mut self, condition: &Expression, then_block: usize, else_block: usize) -> Option<()> {
+        let (op, lhs, rhs) = match condition {
+            Expression::BinaryOp { left, op, right, .. } if op.is_comparison() => {
+                (op.clone(), self.lower_expression(left)?, self.lower_expression(right)?)
+            }
+            _ => (BinaryOperator::NEq, self.lower_expression(condition)?, Operand::Imm(0)),
+        };
+        self.terminate(Terminator::Branch { op, lhs, rhs, then_block, else_block });
+        Some(())
+    }
+
+    fn lower_expression(&mut self, expr: &Expression) -> Option<Operand> {
+        match expr {
+            Expression::Integer(value) => Some(Operand::Imm(*value)),
+            Expression::Variable(name) => self.vars.get(name).map(|&var| Operand::Reg(var)),
+            Expression::BinaryOp { left, op, right, location } => {
+                let lhs = self.lower_expression(left)?;
+                let rhs = self.lower_expression(right)?;
+                let dst = self.function.new_vreg();
+                self.emit(Inst::Binary { op: op.clone(), dst, lhs, rhs, location: location.clone() });
+                Some(Operand::Reg(dst))
+            }
+            Expression::FunctionCall { name, args } => {
+                if self.signatures.get(name).is_none_or(|(_, returns)| *returns != Type::Int) {
+                    return None;
+                }
+                let args = self.lower_arguments(name, args)?;
+                let dst = self.function.new_vreg();
+                self.emit(Inst::Call { dst: Some(dst), name: name.clone(), args });
+                Some(Operand::Reg(dst))
+            }
+            _ => None,
+        }
+    }
+
+    // Arguments of a direct call to a function taking only integers
+    fn lower_arguments(&mut self, name: &str, args: &[Expression]) -> Option<Vec<Operand>> {
+        if builtins::arity(name).is_some() || self.vars.contains_key(name) || args.len() > MAX_ARGS {
+            return None;
+        }
+        let (params, _) = self.signatures.get(name)?;
+        if params.iter().any(|ty| ty.unwrap_or(Type::Int) != Type::Int) {
+            return None;
+        }
+        args.iter().map(|arg| self.lower_expression(arg)).collect()
+    }
+
+    // Same rule as the stack-based code generator: Bonk functions returning
+    // the same type, which is always an integer here
+    fn is_tail_callable(&self, name: &str) -> bool {
+        builtins::arity(name).is_none()
+            && !self.vars.contains_key(name)
+            && !self.externs.iter().any(|e| e == name)
+            && self.signatures.get(name).is_some_and(|(_, returns)| *returns == Type::Int)
+    }
+}
+
+// Checks the invariants the passes and code generators rely on: branch
+// targets exist, every register read is written somewhere, and in SSA form
+// each register is written once, before every read, with phis covering
+// exactly the predecessors of their block
+pub fn verify(function: &Function) -> Result<(), String> {
+    let error = |message: String| Err(format!("Invalid IR in {}: {}", function.name, message));
+    let blocks = function.blocks.len();
+    if blocks == 0 {
+        return error("no blocks".into());
+    }
+    for (index, block) in function.blocks.iter().enumerate() {
+        if let Some(target) = block.terminator.successors().into_iter().find(|&target| target >= blocks) {
+            return error(format!("b{} jumps to missing block b{}", index, target));
+        }
+        if !function.ssa && !block.phis.is_empty() {
+            return error(format!("b{} has phis outside SSA form", index));
+        }
+    }
+    let predecessors = function.predecessors();
+    if !predecessors[0].is_empty() {
+        return error("the entry block has predecessors".into());
+    }
+
+    // Where each register is written: Some(block, position) or None for parameters
+    let mut defs: HashMap<VReg, Vec<Option<(usize, usize)>>> = HashMap::new();
+    for &param in &function.params {
+        defs.entry(param).or_default().push(None);
+    }
+    for (index, block) in function.blocks.iter().enumerate() {
+        for phi in &block.phis {
+            defs.entry(phi.dst).or_default().push(Some((index, 0)));
+        }
+        for (position, inst) in block.insts.iter().enumerate() {
+            if let Some(vreg) = inst.def() {
+                defs.entry(vreg).or_default().push(Some((index, position + 1)));
+            }
+        }
+    }
+    if let Some(vreg) = defs.keys().find(|vreg| vreg.0 >= function.vreg_count) {
+        return error(format!("v{} is beyond the register count {}", vreg.0, function.vreg_count));
+    }
+
+    if !function.ssa {
+        for (index, block) in function.blocks.iter().enumerate() {
+            let uses = block.insts.iter().flat_map(Inst::uses).chain(block.terminator.uses());
+            if let Some(vreg) = uses.into_iter().find(|vreg| !defs.contains_key(vreg)) {
+                return error(format!("v{} is read in b{} but never written", vreg.0, index));
+            }
+        }
+        return Ok(());
+    }
+
+    if let Some((vreg, _)) = defs.iter().find(|(_, sites)| sites.len() > 1) {
+        return error(format!("v{} is written more than once", vreg.0));
+    }
+    let idom = function.dominators();
+    let reachable: HashSet<usize> = function.reverse_postorder().into_iter().collect();
+    let dominates = |a: usize, mut b: usize| loop {
+        if a == b {
+            return true;
+        }
+        if b == 0 {
+            return false;
+        }
+        b = idom[b];
+    };
+    // Whether the write of `vreg` comes before `position` in `block` on every path
+    let available = |vreg: &VReg, block: usize, position: usize| match defs.get(vreg).map(|sites| sites[0]) {
+        None => false,
+        Some(None) => true,
+        Some(Some((def_block, def_position))) => {
+            if def_block == block {
+                def_position < position
+            } else {
+                dominates(def_block, block)
+            }
+        }
+    };
+
+    for (index, block) in function.blocks.iter().enumerate() {
+        if !reachable.contains(&index) {
+            return error(format!("b{} is unreachable", index));
+        }
+        for phi in &block.phis {
+            let mut sources: Vec<usize> = phi.incoming.iter().map(|(pred, _)| *pred).collect();
+            sources.sort();
+            let mut expected = predecessors[index].clone();
+            expected.sort();
+            if sources != expected {
+                return error(format!("phi for v{} in b{} does not match the predecessors", phi.dst.0, index));
+            }
+            for (pred, operand) in &phi.incoming {
+                if let Operand::Reg(vreg) = operand {
+                    if !available(vreg, *pred, usize::MAX) {
+                        return error(format!("v{} reaches the phi in b{} from b{} before it is written", vreg.0, index, pred));
+                    }
+                }
+            }
+        }
+        for (position, inst) in block.insts.iter().enumerate() {
+            if let Some(vreg) = inst.uses().into_iter().find(|vreg| !available(vreg, index, position + 1)) {
+                return error(format!("v{} is read in b{} before it is written", vreg.0, index));
+            }
+        }
+        if let Some(vreg) = block.terminator.uses().into_iter().find(|vreg| !available(vreg, index, usize::MAX)) {
+            return error(format!("v{} is read in b{} before it is written", vreg.0, index));
+        }
+    }
+    Ok(())
+}
+
+impl fmt::Display for VReg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "v{}", self.0)
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operand::Reg(vreg) => write!(f, "{}", vreg),
+            Operand::Imm(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+fn operator_name(op: &BinaryOperator) -> &'static str {
+    match op {
+        BinaryOperator::Add => "add",
+        BinaryOperator::Sub => "sub",
+        BinaryOperator::Mul => "mul",
+        BinaryOperator::Div => "div",
+        BinaryOperator::Eq => "eq",
+        BinaryOperator::NEq => "ne",
+        BinaryOperator::Lt => "lt",
+        BinaryOperator::LtEq => "le",
+        BinaryOperator::Gt => "gt",
+    }
+}
+
+// Named after the x86 instructions
+pub fn shift_name(op: Shift) -> &'static str {
+    match op {
+        Shift::Left => "shl",
+        Shift::Right => "sar",
+        Shift::RightLogical => "shr",
+    }
+}
+
+fn operand_list(operands: &[Operand]) -> String {
+    operands.iter().map(Operand::to_string).collect::<Vec<_>>().join(", ")
+}
+
+impl fmt::Display for Inst {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Inst::Copy { dst, src } => write!(f, "{} = copy {}", dst, src),
+            Inst::Binary { op, dst, lhs, rhs, .. } => write!(f, "{} = {} {}, {}", dst, operator_name(op), lhs, rhs),
+            Inst::Shift { op, dst, src, amount } => write!(f, "{} = {} {}, {}", dst, shift_name(*op), src, amount),
+            Inst::Call { dst: Some(dst), name, args } => write!(f, "{} = call {}({})", dst, name, operand_list(args)),
+            Inst::Call { dst: None, name, args } => write!(f, "call {}({})", name, operand_list(args)),
+            Inst::Print { parts, newline } => {
+                let parts: Vec<String> = parts
+                    .iter()
+                    .map(|part| match part {
+                        PrintPart::Text(text) => format!("{:?}", text),
+                        PrintPart::Value(value, spec) => format!("{}{}", value, spec_suffix(spec)),
+                    })
+                    .collect();
+                write!(f, "{} {}", if *newline { "println" } else { "print" }, parts.join(", "))
+            }
+        }
+    }
+}
+
+// A format spec as written after the value in string interpolation, e.g. `:08x`
+fn spec_suffix(spec: &FormatSpec) -> String {
+    let mut suffix = String::new();
+    if spec.left_align {
+        suffix.push('<');
+    }
+    if spec.zero_pad {
+        suffix.push('0');
+    }
+    if let Some(width) = spec.width {
+        suffix.push_str(&width.to_string());
+    }
+    if let Some(precision) = spec.precision {
+        suffix.push_str(&format!(".{}", precision));
+    }
+    if let Some(kind) = spec.kind {
+        suffix.push(kind);
+    }
+    if suffix.is_empty() {
+        suffix
+    } else {
+        format!(":{}", suffix)
+    }
+}
+
+impl fmt::Display for Terminator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Terminator::Jump(target) => write!(f, "jump b{}", target),
+            Terminator::Branch { op, lhs, rhs, then_block, else_block } => {
+                write!(f, "branch {} {}, {}, b{}, b{}", operator_name(op), lhs, rhs, then_block, else_block)
+            }
+            Terminator::Return(value) => write!(f, "return {}", value),
+            Terminator::TailCall { name, args } => write!(f, "tailcall {}({})", name, operand_list(args)),
+        }
+    }
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let params: Vec<String> = self.params.iter().map(VReg::to_string).collect();
+        writeln!(f, "function {}({}) {{", self.name, params.join(", "))?;
+        for (index, block) in self.blocks.iter().enumerate() {
+            writeln!(f, "b{}:", index)?;
+            for phi in &block.phis {
+                let incoming: Vec<String> = phi.incoming.iter().map(|(pred, value)| format!("b{}: {}", pred, value)).collect();
+                writeln!(f, "    {} = phi {}", phi.dst, incoming.join(", "))?;
+            }
+            for inst in &block.insts {
+                writeln!(f, "    {}", inst)?;
+            }
+            writeln!(f, "    {}", block.terminator)?;
+        }
+        write!(f, "}}")
+    }
+}
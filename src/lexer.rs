@@ -1,8 +1,11 @@
-use crate::tokens::Token;
+use crate::tokens::{Span, Token};
 
 pub struct Lexer {
     input: String,
     position: usize,
+    line: usize,
+    // Position of each token returned by tokenise, index for index
+    spans: Vec<Span>,
 }
 
 impl Lexer {
@@ -10,6 +13,8 @@ impl Lexer {
         Lexer {
             input,
             position: 0,
+            line: 1,
+            spans: Vec::new(),
         }
     }
     fn peek(&self) -> Option<char> {
@@ -20,8 +25,11 @@ impl Lexer {
         }
     }
     fn advance(&mut self) {
-        if self.position < self.input.len() {
-            self.position += 1;
+        if let Some(c) = self.peek() {
+            self.position += c.len_utf8();
+            if c == '\n' {
+                self.line += 1;
+            }
         }
     }
     fn skip_whitespace(&mut self) {
@@ -33,8 +41,46 @@ impl Lexer {
             }
         }
     }
-    fn lex_number(&mut self) -> i64 {
+    fn skip_comment(&mut self) {
+        while let Some(c) = self.peek() {
+            self.advance();
+            if c == '\n' {
+                break;
+            }
+        }
+    }
+    fn lex_number(&mut self) -> Token {
         let start = self.position;
+        self.skip_digits();
+        let mut is_float = false;
+
+        // Fraction: `3.14`
+        if self.peek() == Some('.') && self.peek_next().is_some_and(|c| c.is_ascii_digit()) {
+            is_float = true;
+            self.advance();
+            self.skip_digits();
+        }
+        // Exponent: `1e-3`, `2.5E+8`
+        if matches!(self.peek(), Some('e') | Some('E')) {
+            let rest = &self.input[self.position + 1..];
+            let sign = usize::from(rest.starts_with('+') || rest.starts_with('-'));
+            if rest[sign..].starts_with(|c: char| c.is_ascii_digit()) {
+                is_float = true;
+                for _ in 0..=sign {
+                    self.advance();
+                }
+                self.skip_digits();
+            }
+        }
+
+        let text = &self.input[start..self.position];
+        if is_float {
+            Token::Float(text.parse().unwrap_or(0.0))
+        } else {
+            Token::Number(text.parse().unwrap_or(0))
+        }
+    }
+    fn skip_digits(&mut self) {
         while let Some(c) = self.peek() {
             if c.is_ascii_digit() {
                 self.advance();
@@ -42,12 +88,20 @@ impl Lexer {
                 break;
             }
         }
-        self.input[start..self.position].parse().unwrap_or(0)
+    }
+    fn peek_next(&self) -> Option<char> {
+        let mut chars = self.input[self.position..].chars();
+        chars.next();
+        chars.next()
     }
     fn lex_identifier(&mut self) -> Token {
         let start = self.position;
         while let Some(c) = self.peek() {
-            if c.is_alphanumeric() || c == '~' {
+            if c.is_alphanumeric() || c == '_' || c == '~' {
+                self.advance();
+            } else if self.input[self.position..].starts_with("::") {
+                // Module paths such as `std::math` and `~math::gcd` stay a single token
+                self.advance();
                 self.advance();
             } else {
                 break;
@@ -59,36 +113,56 @@ impl Lexer {
         }
         match identifier {
             "run" => Token::Function,
+            "fn" => Token::Fn,
+            "extern" => Token::Extern,
+            "use" => Token::Use,
             "end" => Token::End,
             "while" => Token::While,
             "do" => Token::Do,
             "print" => Token::Print,
+            "write" => Token::Write,
             "send" => Token::Send,
+            "assert" => Token::Assert,
             "if" => Token::If,
             "then" => Token::Then,
             "else" => Token::Else,
             _ => Token::Identifier(identifier.to_string()),
         }
     }
-    fn lex_operator(&mut self) -> Token {
+    fn lex_operator(&mut self) -> Result<Token, String> {
         let c = self.peek().unwrap();
         if matches!(c, '=') {
             self.advance();
             if self.peek() == Some('=') {
                 self.advance();
-                return Token::Eq;
+                return Ok(Token::Eq);
+            } else if self.peek() == Some('>') {
+                self.advance();
+                return Ok(Token::FatArrow);
             } else {
-                return Token::Assign;
+                return Ok(Token::Assign);
             }
         }
-        match c {
+        let token = match c {
             '+' => Token::Plus,
-            '-' => Token::Minus,
+            '-' => {
+                self.advance();
+                if self.peek() == Some('>') {
+                    self.advance();
+                    Token::Arrow
+                } else {
+                    Token::Minus
+                }
+            }
             '*' => Token::Multiply,
             '/' => Token::Divide,
             '(' => Token::LParen,
             ')' => Token::RParen,
+            '[' => Token::LBracket,
+            ']' => Token::RBracket,
             ',' => Token::Comma,
+            ':' => Token::Colon,
+            '&' => Token::Ampersand,
             '<' => {
                 self.advance();
                 if self.peek() == Some('=') {
@@ -105,7 +179,7 @@ impl Lexer {
                     self.advance();
                     Token::NotEq
                 } else {
-                    panic!("Unexpected character after '!': {}", self.peek().unwrap()); 
+                    return Err(format!("Expected '=' after '!' on line {}", self.line));
                 }
             }
             ';' => Token::Semicolon,
@@ -122,27 +196,44 @@ impl Lexer {
                 }
                 Token::StringLiteral(self.input[start..self.position - 1].to_string())
             }
-            _ => panic!("Unexpected character: {}", c),
-        }
+            _ => return Err(format!("Unexpected character {:?} on line {}", c, self.line)),
+        };
+        Ok(token)
+    }
+    pub fn spans(&self) -> &[Span] {
+        &self.spans
     }
-    pub fn tokenise(&mut self) -> Vec<Token> {
+    pub fn tokenise(&mut self) -> Result<Vec<Token>, String> {
         let mut tokens = Vec::new();
         while let Some(c) = self.peek() {
+            let (line, start) = (self.line, self.position);
             if c.is_whitespace() {
                 self.skip_whitespace();
+            } else if c == '#' {
+                self.skip_comment();
+            } else if c == '@' {
+                self.advance();
+                match self.lex_identifier() {
+                    Token::Identifier(name) if !name.is_empty() => tokens.push(Token::Attribute(name)),
+                    _ => return Err(format!("Expected an attribute name after @ on line {}", line)),
+                }
+                self.spans.push(Span { line, start, end: self.position });
             } else if c.is_ascii_digit() {
-                tokens.push(Token::Number(self.lex_number()));
-            } else if c.is_alphabetic() || c == '~' {
+                tokens.push(self.lex_number());
+                self.spans.push(Span { line, start, end: self.position });
+            } else if c.is_alphabetic() || c == '_' || c == '~' {
                 tokens.push(self.lex_identifier());
+                self.spans.push(Span { line, start, end: self.position });
             } else {
-                let tok = self.lex_operator();
+                let tok = self.lex_operator()?;
                 tokens.push(tok.clone());
                 match tok {
-                    Token::Eq | Token::NotEq | Token::Less | Token::LessEq | Token::StringLiteral(_) => {}
+                    Token::Eq | Token::Assign | Token::FatArrow | Token::NotEq | Token::Less | Token::LessEq | Token::Minus | Token::Arrow | Token::StringLiteral(_) => {}
                     _ => self.advance(),
                 }
+                self.spans.push(Span { line, start, end: self.position });
             }
         }
-        tokens
+        Ok(tokens)
     }
 }
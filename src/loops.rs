@@ -0,0 +1,299 @@
+use std::collections::HashMap;
+
+use crate::ast::BinaryOperator;
+use crate::compiler::Overflow;
+use crate::ir::{Block, Function, Inst, Operand, Shift, Terminator, VReg};
+
+// Loop optimizations on the IR, outside SSA form. Natural loops are found from
+// their back edges, edges to a block that dominates where they come from, and
+// each gets a preheader: a block that every entry into the loop passes
+// through. Then, innermost loops first:
+//   - instructions whose operands do not change in the loop are hoisted into
+//     the preheader,
+//   - products of an induction variable, one increased by the same constant
+//     once per iteration, and a constant become a running sum,
+// and throughout the function, multiplication and division by a power of two
+// become shifts.
+//
+// With --overflow=trap nothing that could trap is hoisted and no
+// multiplication is rewritten, since that would change where, or whether, the
+// program stops.
+
+struct Loop {
+    header: usize,
+    // The blocks of the loop, the header included
+    blocks: Vec<usize>,
+}
+
+pub fn optimize(function: &mut Function, overflow: Overflow) {
+    assert!(!function.ssa, "{} must leave SSA form before loop optimization", function.name);
+    let wraps = overflow == Overflow::Wrap;
+    insert_preheaders(function);
+    let mut loops = natural_loops(function);
+    loops.sort_by_key(|l| l.blocks.len());
+    for l in &loops {
+        hoist_invariants(function, l, wraps);
+        if wraps {
+            reduce_strength(function, l);
+        }
+    }
+    use_shifts(function, wraps);
+}
+
+fn natural_loops(function: &Function) -> Vec<Loop> {
+    let idom = function.dominators();
+    let dominates = |a: usize, mut b: usize| loop {
+        if a == b {
+            return true;
+        }
+        if b == 0 {
+            return false;
+        }
+        b = idom[b];
+    };
+    let predecessors = function.predecessors();
+    let mut loops: Vec<Loop> = Vec::new();
+    for (source, block) in function.blocks.iter().enumerate() {
+        for header in block.terminator.successors() {
+            if !dominates(header, source) {
+                continue;
+            }
+            // Everything that reaches the back edge without passing the header
+            let mut blocks = vec![header];
+            let mut pending = vec![source];
+            while let Some(block) = pending.pop() {
+                if !blocks.contains(&block) {
+                    blocks.push(block);
+                    pending.extend(&predecessors[block]);
+                }
+            }
+            // Back edges to the same header make one loop
+            match loops.iter_mut().find(|l| l.header == header) {
+                Some(l) => {
+                    blocks.retain(|block| !l.blocks.contains(block));
+                    l.blocks.extend(blocks);
+                }
+                None => loops.push(Loop { header, blocks }),
+            }
+        }
+    }
+    for l in &mut loops {
+        l.blocks.sort();
+    }
+    loops
+}
+
+// The only block outside the loop that jumps to its header, if that is all it does
+fn preheader(function: &Function, l: &Loop, predecessors: &[Vec<usize>]) -> Option<usize> {
+    match predecessors[l.header].iter().filter(|pred| !l.blocks.contains(pred)).collect::<Vec<_>>()[..] {
+        [&pred] if function.blocks[pred].terminator.successors() == [l.header] => Some(pred),
+        _ => None,
+    }
+}
+
+fn insert_preheaders(function: &mut Function) {
+    loop {
+        let predecessors = function.predecessors();
+        let loops = natural_loops(function);
+        let Some(l) = loops.iter().find(|l| preheader(function, l, &predecessors).is_none()) else {
+            return;
+        };
+        // Placed just before the header, so that it falls through into it
+        let header = l.header + 1;
+        let outside: Vec<usize> = predecessors[l.header]
+            .iter()
+            .filter(|pred| !l.blocks.contains(pred))
+            .map(|&pred| if pred >= l.header { pred + 1 } else { pred })
+            .collect();
+        function.insert_block(l.header, Block { phis: Vec::new(), insts: Vec::new(), terminator: Terminator::Jump(header) });
+        for pred in outside {
+            for target in function.blocks[pred].terminator.successors_mut() {
+                if *target == header {
+                    *target = l.header;
+                }
+            }
+        }
+    }
+}
+
+// Moves instructions computing the same value on every iteration into the
+// preheader. The register written must be written nowhere else in the loop,
+// and its value on entry to the loop must be dead, so running the instruction
+// once up front, even when the loop would not have, changes nothing else.
+fn hoist_invariants(function: &mut Function, l: &Loop, wraps: bool) {
+    let preheader = preheader(function, l, &function.predecessors()).unwrap();
+    let live_in = function.live_in();
+    let mut writes: HashMap<VReg, usize> = HashMap::new();
+    for &block in &l.blocks {
+        for vreg in function.blocks[block].insts.iter().filter_map(Inst::def) {
+            *writes.entry(vreg).or_default() += 1;
+        }
+    }
+
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for &block in &l.blocks {
+            let mut i = 0;
+            while i < function.blocks[block].insts.len() {
+                let inst = &function.blocks[block].insts[i];
+                let invariant = |operand: &Operand| match operand {
+                    Operand::Reg(vreg) => !writes.contains_key(vreg),
+                    Operand::Imm(_) => true,
+                };
+                let movable = match inst {
+                    Inst::Copy { src, .. } | Inst::Shift { src, .. } => invariant(src),
+                    Inst::Binary { op, lhs, rhs, .. } => invariant(lhs) && invariant(rhs) && !can_trap(op, rhs, wraps),
+                    Inst::Call { .. } | Inst::Print { .. } => false,
+                };
+                let dst = inst.def();
+                if movable && dst.is_some_and(|dst| writes[&dst] == 1 && !live_in[l.header].contains(&dst)) {
+                    let inst = function.blocks[block].insts.remove(i);
+                    function.blocks[preheader].insts.push(inst);
+                    writes.remove(&dst.unwrap());
+                    changed = true;
+                } else {
+                    i += 1;
+                }
+            }
+        }
+    }
+}
+
+// Replaces `j = mul i, k` by a copy of a register holding i * k, kept up to
+// date by adding the step of i times k right after i's increment
+fn reduce_strength(function: &mut Function, l: &Loop) {
+    let preheader = preheader(function, l, &function.predecessors()).unwrap();
+    let mut writes: HashMap<VReg, Vec<(usize, usize)>> = HashMap::new();
+    for &block in &l.blocks {
+        for (position, inst) in function.blocks[block].insts.iter().enumerate() {
+            if let Some(vreg) = inst.def() {
+                writes.entry(vreg).or_default().push((block, position));
+            }
+        }
+    }
+    // Induction variables, with their step and where they are increased
+    let mut steps: HashMap<VReg, (i64, (usize, usize))> = HashMap::new();
+    for (&vreg, sites) in &writes {
+        let [(block, position)] = sites[..] else { continue };
+        let step = match &function.blocks[block].insts[position] {
+            Inst::Binary { op: BinaryOperator::Add, lhs: Operand::Reg(var), rhs: Operand::Imm(step), .. }
+            | Inst::Binary { op: BinaryOperator::Add, lhs: Operand::Imm(step), rhs: Operand::Reg(var), .. }
+                if *var == vreg =>
+            {
+                Some(*step)
+            }
+            Inst::Binary { op: BinaryOperator::Sub, lhs: Operand::Reg(var), rhs: Operand::Imm(step), .. } if *var == vreg => {
+                step.checked_neg()
+            }
+            _ => None,
+        };
+        if let Some(step) = step {
+            steps.insert(vreg, (step, (block, position)));
+        }
+    }
+
+    // Running sums by induction variable and factor, with their increment
+    let mut sums: Vec<(VReg, i64, VReg, i64)> = Vec::new();
+    for &block in &l.blocks {
+        for position in 0..function.blocks[block].insts.len() {
+            let Inst::Binary { op: BinaryOperator::Mul, dst, lhs, rhs, .. } = &function.blocks[block].insts[position] else {
+                continue;
+            };
+            let (var, factor) = match (lhs, rhs) {
+                (Operand::Reg(var), Operand::Imm(factor)) | (Operand::Imm(factor), Operand::Reg(var)) => (*var, *factor),
+                _ => continue,
+            };
+            let Some(&(step, _)) = steps.get(&var) else { continue };
+            let Some(increment) = step.checked_mul(factor) else { continue };
+            let dst = *dst;
+            let sum = match sums.iter().find(|(v, f, _, _)| *v == var && *f == factor) {
+                Some(&(_, _, sum, _)) => sum,
+                None => {
+                    let sum = function.new_vreg();
+                    sums.push((var, factor, sum, increment));
+                    sum
+                }
+            };
+            function.blocks[block].insts[position] = Inst::Copy { dst, src: Operand::Reg(sum) };
+        }
+    }
+
+    // Insert from the back so that the recorded positions stay valid
+    sums.sort_by_key(|&(var, ..)| std::cmp::Reverse(steps[&var].1));
+    for (var, factor, sum, increment) in sums {
+        let (block, position) = steps[&var].1;
+        let Inst::Binary { location, .. } = &function.blocks[block].insts[position] else { unreachable!() };
+        let location = location.clone();
+        let update = Inst::Binary {
+            op: BinaryOperator::Add,
+            dst: sum,
+            lhs: Operand::Reg(sum),
+            rhs: Operand::Imm(increment),
+            location: location.clone(),
+        };
+        function.blocks[block].insts.insert(position + 1, update);
+        let start = Inst::Binary { op: BinaryOperator::Mul, dst: sum, lhs: Operand::Reg(var), rhs: Operand::Imm(factor), location };
+        function.blocks[preheader].insts.push(start);
+    }
+}
+
+// Multiplication by 2^k becomes a left shift when overflow wraps, and signed
+// division by 2^k an arithmetic right shift, after adding 2^k - 1 to negative
+// dividends so that the quotient still rounds toward zero
+fn use_shifts(function: &mut Function, wraps: bool) {
+    for index in 0..function.blocks.len() {
+        let insts = std::mem::take(&mut function.blocks[index].insts);
+        let mut rewritten = Vec::new();
+        for inst in insts {
+            match inst {
+                Inst::Binary { op: BinaryOperator::Mul, dst, lhs, rhs, .. }
+                    if wraps && (power_of_two(&lhs).is_some() || power_of_two(&rhs).is_some()) =>
+                {
+                    let (src, amount) = match power_of_two(&rhs) {
+                        Some(amount) => (lhs, amount),
+                        None => (rhs, power_of_two(&lhs).unwrap()),
+                    };
+                    if amount == 0 {
+                        rewritten.push(Inst::Copy { dst, src });
+                    } else {
+                        rewritten.push(Inst::Shift { op: Shift::Left, dst, src, amount });
+                    }
+                }
+                Inst::Binary { op: BinaryOperator::Div, dst, lhs, rhs, location } if power_of_two(&rhs).is_some() => {
+                    let amount = power_of_two(&rhs).unwrap();
+                    if amount == 0 {
+                        rewritten.push(Inst::Copy { dst, src: lhs });
+                        continue;
+                    }
+                    let sign = function.new_vreg();
+                    let bias = function.new_vreg();
+                    let biased = function.new_vreg();
+                    rewritten.push(Inst::Shift { op: Shift::Right, dst: sign, src: lhs, amount: 63 });
+                    rewritten.push(Inst::Shift { op: Shift::RightLogical, dst: bias, src: Operand::Reg(sign), amount: 64 - amount });
+                    rewritten.push(Inst::Binary { op: BinaryOperator::Add, dst: biased, lhs, rhs: Operand::Reg(bias), location });
+                    rewritten.push(Inst::Shift { op: Shift::Right, dst, src: Operand::Reg(biased), amount });
+                }
+                other => rewritten.push(other),
+            }
+        }
+        function.blocks[index].insts = rewritten;
+    }
+}
+
+// Division traps unless the divisor is a constant other than 0 and -1, and
+// the other arithmetic with --overflow=trap
+fn can_trap(op: &BinaryOperator, rhs: &Operand, wraps: bool) -> bool {
+    match op {
+        BinaryOperator::Div => !matches!(rhs, Operand::Imm(divisor) if *divisor != 0 && *divisor != -1),
+        _ => !op.is_comparison() && !wraps,
+    }
+}
+
+// k when the operand is the constant 2^k
+fn power_of_two(operand: &Operand) -> Option<u32> {
+    match operand {
+        Operand::Imm(value) if *value > 0 && (*value as u64).is_power_of_two() => Some(value.trailing_zeros()),
+        _ => None,
+    }
+}
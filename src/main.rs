@@ -1,40 +1,193 @@
-use compiler::Compiler;
+use compiler::{Compiler, Options, Overflow, Target};
 
-use crate::lexer::Lexer;
-use crate::parser::parse_program;
-use std::fs;
+use crate::modules::ModuleLoader;
 use std::io::Write;
 use std::fs::File;
+use std::path::{Path, PathBuf};
+mod aarch64;
 mod ast;
+mod builtins;
+mod checker;
+mod closures;
 mod compiler;
+mod dead_code;
+mod encoder;
+mod fold;
+mod inline;
+mod ir;
 mod lexer;
+mod loops;
+mod modules;
+mod object;
 mod parser;
+mod passes;
+mod peephole;
+mod regalloc;
+mod runtime;
+mod ssa;
+mod stack;
+mod stdlib;
 mod tokens;
+mod types;
 
 fn main() {
     let args: Vec<String> = std::env::args().collect();
-    if args.len() < 3 {
-        eprintln!("Usage: {} <input.bonk> <output.asm>", args[0]);
+
+    // Module search path: -I flags first, then BONK_PATH
+    let mut search_paths = Vec::new();
+    let mut files = Vec::new();
+    let mut options = Options::default();
+    // asm, ir or obj
+    let mut emit = "asm";
+    let mut pass_list = None;
+    let mut rest = args.iter().skip(1);
+    while let Some(arg) = rest.next() {
+        if arg == "-I" {
+            match rest.next() {
+                Some(dir) => search_paths.push(PathBuf::from(dir)),
+                None => {
+                    eprintln!("Missing directory after -I");
+                    std::process::exit(1);
+                }
+            }
+        } else if let Some(mode) = arg.strip_prefix("--overflow=") {
+            options.overflow = match mode {
+                "wrap" => Overflow::Wrap,
+                "trap" => Overflow::Trap,
+                _ => {
+                    eprintln!("Unknown overflow mode {}, expected wrap or trap", mode);
+                    std::process::exit(1);
+                }
+            };
+        } else if let Some(level) = arg.strip_prefix("-O") {
+            options.opt_level = match level {
+                "0" => 0,
+                "1" => 1,
+                "2" => 2,
+                _ => {
+                    eprintln!("Unknown optimization level {}, expected 0, 1 or 2", level);
+                    std::process::exit(1);
+                }
+            };
+        } else if arg == "--emit" || arg.starts_with("--emit=") {
+            let kind = match arg.strip_prefix("--emit=") {
+                Some(kind) => kind,
+                None => rest.next().map_or("", String::as_str),
+            };
+            emit = match kind {
+                "asm" | "ir" | "obj" => kind,
+                _ => {
+                    eprintln!("Unknown output kind {}, expected asm, ir or obj", kind);
+                    std::process::exit(1);
+                }
+            };
+        } else if let Some(target) = arg.strip_prefix("--target=") {
+            options.target = match target {
+                "x86_64-macos" => Target::X86_64MacOs,
+                "x86_64-linux" => Target::X86_64Linux,
+                "aarch64-linux" => Target::Aarch64Linux,
+                _ => {
+                    eprintln!("Unknown target {}, expected x86_64-macos, x86_64-linux or aarch64-linux", target);
+                    std::process::exit(1);
+                }
+            };
+        } else if arg == "--freestanding" {
+            options.freestanding = true;
+        } else if arg == "--ssa" {
+            options.ssa = true;
+        } else if let Some(list) = arg.strip_prefix("--passes=") {
+            match passes::parse_list(list) {
+                Ok(list) => pass_list = Some(list),
+                Err(err) => {
+                    eprintln!("{}", err);
+                    std::process::exit(1);
+                }
+            }
+        } else if let Some(pass) = arg.strip_prefix("--print-after=") {
+            options.print_after = Some(pass.to_string());
+        } else if let Some(dir) = arg.strip_prefix("-I") {
+            search_paths.push(PathBuf::from(dir));
+        } else {
+            files.push(arg);
+        }
+    }
+    if let Ok(bonk_path) = std::env::var("BONK_PATH") {
+        search_paths.extend(std::env::split_paths(&bonk_path));
+    }
+
+    if files.len() < 2 {
+        eprintln!(
+            "Usage: {} [-I <dir>]... [--overflow=wrap|trap] [-O0|-O1|-O2] [--passes=<pass>,...] [--print-after=<pass>] [--emit=asm|ir|obj] [--target=x86_64-macos|x86_64-linux|aarch64-linux] [--freestanding] [--ssa] <input.bonk> <output>",
+            args[0]
+        );
         std::process::exit(1);
     }
 
-    let file_input = fs::read_to_string(&args[1])
-        .expect("Error reading input file");
+    // System calls are made the Linux way
+    if options.freestanding && options.target != Target::X86_64Linux {
+        eprintln!("--freestanding needs --target=x86_64-linux");
+        std::process::exit(1);
+    }
 
-    let mut lexer = Lexer::new(file_input);
-    let tokens = lexer.tokenise();
+    // The built-in encoder only knows x86-64
+    if emit == "obj" && options.target == Target::Aarch64Linux {
+        eprintln!("--emit=obj is not available for aarch64-linux; assemble the output with as");
+        std::process::exit(1);
+    }
+
+    // --passes replaces the passes of the optimization level, wherever it is given
+    options.passes = pass_list.unwrap_or_else(|| passes::default_passes(options.opt_level));
+    if let Some(pass) = &options.print_after {
+        if let Err(err) = passes::check_print_after(pass, &options) {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    }
 
-    let ast = parse_program(&tokens).expect("Parsing error");
+    let ast = match ModuleLoader::new(search_paths)
+        .load_program(Path::new(files[0]))
+        .and_then(|program| passes::run_ast(program, &options))
+    {
+        Ok(ast) => ast,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            std::process::exit(1);
+        }
+    };
 
-    let mut comp = Compiler::new();
-    let result = comp.compile(ast);
+    let target = options.target;
+    let mut comp = Compiler::new(options);
+    let compiled = if emit == "ir" { comp.emit_ir(ast) } else { comp.compile(ast) };
+    let result = match compiled {
+        Ok(result) => result,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            std::process::exit(1);
+        }
+    };
 
-    let output_path = &args[2];
+    let output_path = files[1];
     let mut file = File::create(output_path).expect("Unable to create output file");
 
+    if emit == "obj" {
+        match encoder::assemble(&result).and_then(|assembled| object::write(&assembled, target)) {
+            Ok(bytes) => file.write_all(&bytes).expect("Failed to write object file"),
+            Err(err) => {
+                eprintln!("Error: {}", err);
+                std::process::exit(1);
+            }
+        }
+        println!("Object file written to {}", output_path);
+        return;
+    }
+
     for line in result {
         writeln!(file, "{}", line).expect("Failed to write line");
     }
 
-    println!("Assembly written to {}", output_path);
+    if emit == "ir" {
+        println!("IR written to {}", output_path);
+    } else {
+        println!("Assembly written to {}", output_path);
+    }
 }
@@ -0,0 +1,324 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::ast::{assignments, Expression, FormatPart, Import, Statement, Type};
+use crate::lexer::Lexer;
+use crate::parser::parse_program;
+use crate::stdlib;
+
+// Pseudo directory the embedded standard library modules live under
+const STD_ROOT: &str = "<std>";
+
+// Loads the entry file and everything it `use`s into a single program.
+// Functions of imported modules are renamed to `<module>__<name>` so that two
+// modules can define functions with the same name; the entry file keeps its
+// names unchanged so `main` stays `main`.
+
+struct Module {
+    // Symbol prefix, empty for the entry file
+    prefix: String,
+    statements: Vec<Statement>,
+    // Qualifier used in calls (`~math::gcd`) mapped to the imported module
+    imports: HashMap<String, usize>,
+}
+
+pub struct ModuleLoader {
+    search_paths: Vec<PathBuf>,
+    modules: Vec<Module>,
+    loaded: HashMap<PathBuf, usize>,
+    // Files currently being loaded, used to report import cycles
+    loading: Vec<PathBuf>,
+    prefixes: HashSet<String>,
+}
+
+impl ModuleLoader {
+    pub fn new(search_paths: Vec<PathBuf>) -> ModuleLoader {
+        ModuleLoader {
+            search_paths,
+            modules: Vec::new(),
+            loaded: HashMap::new(),
+            loading: Vec::new(),
+            prefixes: HashSet::new(),
+        }
+    }
+
+    pub fn load_program(mut self, entry: &Path) -> Result<Vec<Statement>, String> {
+        let key = fs::canonicalize(entry)
+            .map_err(|err| format!("Cannot read {}: {}", entry.display(), err))?;
+        self.load(key, String::new())?;
+        self.link()
+    }
+
+    fn load(&mut self, key: PathBuf, prefix: String) -> Result<usize, String> {
+        if let Some(start) = self.loading.iter().position(|p| *p == key) {
+            let cycle: Vec<String> = self.loading[start..]
+                .iter()
+                .chain(std::iter::once(&key))
+                .map(|p| p.display().to_string())
+                .collect();
+            return Err(format!("Import cycle: {}", cycle.join(" -> ")));
+        }
+        if let Some(&id) = self.loaded.get(&key) {
+            return Ok(id);
+        }
+
+        let source = match key.strip_prefix(STD_ROOT) {
+            Ok(relative) => stdlib::source(relative)
+                .ok_or_else(|| format!("No standard library module {}", relative.display()))?
+                .to_string(),
+            Err(_) => fs::read_to_string(&key)
+                .map_err(|err| format!("Cannot read {}: {}", key.display(), err))?,
+        };
+        let mut lexer = Lexer::new(source.clone());
+        let tokens = lexer.tokenise().map_err(|err| format!("{} of {}", err, display_name(&key)))?;
+        let statements = parse_program(&tokens, lexer.spans(), &source, &display_name(&key))?;
+
+        self.loading.push(key.clone());
+        let mut imports = HashMap::new();
+        for stmt in &statements {
+            if let Statement::Use(import) = stmt {
+                let (alias, file) = self.resolve(import, &key)?;
+                if imports.contains_key(&alias) {
+                    return Err(format!("Module {} imported twice in {}", alias, key.display()));
+                }
+                let prefix = self.unique_prefix(&alias);
+                let id = self.load(file, prefix)?;
+                imports.insert(alias, id);
+            }
+        }
+        self.loading.pop();
+
+        let id = self.modules.len();
+        self.modules.push(Module { prefix, statements, imports });
+        self.loaded.insert(key, id);
+        Ok(id)
+    }
+
+    // Finds the file for an import and the qualifier it is called through
+    fn resolve(&self, import: &Import, importer: &Path) -> Result<(String, PathBuf), String> {
+        let importer_dir = importer.parent().unwrap_or(Path::new("."));
+        match import {
+            Import::File(file) => {
+                let mut path = importer_dir.join(file);
+                let alias = path
+                    .file_stem()
+                    .and_then(|stem| stem.to_str())
+                    .ok_or_else(|| format!("Invalid module path {}", file))?
+                    .to_string();
+                if !path.starts_with(STD_ROOT) {
+                    path = fs::canonicalize(&path)
+                        .map_err(|err| format!("Cannot read {}: {}", path.display(), err))?;
+                }
+                Ok((alias, path))
+            }
+            // The standard library is embedded in the compiler rather than searched for
+            Import::Module(segments) if segments[0] == "std" => {
+                let relative: PathBuf = segments[1..].iter().collect();
+                Ok((
+                    segments.last().unwrap().clone(),
+                    Path::new(STD_ROOT).join(relative.with_extension("bonk")),
+                ))
+            }
+            Import::Module(segments) => {
+                let relative: PathBuf = segments.iter().collect();
+                let relative = relative.with_extension("bonk");
+                std::iter::once(importer_dir)
+                    .chain(self.search_paths.iter().map(PathBuf::as_path))
+                    .map(|dir| dir.join(&relative))
+                    .find(|candidate| candidate.is_file())
+                    .and_then(|found| fs::canonicalize(found).ok())
+                    .map(|found| (segments.last().unwrap().clone(), found))
+                    .ok_or_else(|| format!("Module {} not found", segments.join("::")))
+            }
+        }
+    }
+
+    fn unique_prefix(&mut self, alias: &str) -> String {
+        let mut prefix = alias.to_string();
+        let mut n = 1;
+        while !self.prefixes.insert(prefix.clone()) {
+            prefix = format!("{}_{}", alias, n);
+            n += 1;
+        }
+        prefix
+    }
+
+    // Renames every function and call into its mangled symbol and merges the modules
+    fn link(self) -> Result<Vec<Statement>, String> {
+        let mut program = Vec::new();
+        let mut externs: HashMap<String, (Vec<Option<Type>>, Type, bool)> = HashMap::new();
+
+        for module in &self.modules {
+            for stmt in &module.statements {
+                match stmt {
+                    Statement::Function { name, params, returns, body, captures, attributes } => {
+                        // Calls through parameters and variables holding function values keep their name
+                        let locals: HashSet<&str> = params
+                            .iter()
+                            .map(|(param, _)| param.as_str())
+                            .chain(assignments(body).into_iter().map(|(name, _)| name))
+                            .collect();
+                        let mut body = body.clone();
+                        self.rename_statements(module, &locals, &mut body)?;
+                        program.push(Statement::Function {
+                            name: mangle(&module.prefix, name),
+                            params: params.clone(),
+                            returns: *returns,
+                            body,
+                            captures: captures.clone(),
+                            attributes: attributes.clone(),
+                        });
+                    }
+                    Statement::Extern { name, params, returns, long } => {
+                        // C symbols are global, so identical declarations are merged
+                        let decl: (Vec<Option<Type>>, Type, bool) =
+                            (params.iter().map(|(_, ty)| *ty).collect(), *returns, *long);
+                        match externs.get(name) {
+                            Some(existing) if *existing != decl => {
+                                return Err(format!("Conflicting extern declarations of {}", name));
+                            }
+                            Some(_) => {}
+                            None => {
+                                externs.insert(name.clone(), decl);
+                                program.push(stmt.clone());
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        Ok(program)
+    }
+
+    fn rename_statements(&self, module: &Module, locals: &HashSet<&str>, body: &mut [Statement]) -> Result<(), String> {
+        for stmt in body {
+            match stmt {
+                Statement::Assign { value, .. } => self.rename_expression(module, locals, value)?,
+                Statement::Print { args, .. } => {
+                    for arg in args {
+                        self.rename_expression(module, locals, arg)?;
+                    }
+                }
+                Statement::Send(expr) => self.rename_expression(module, locals, expr)?,
+                Statement::FunctionCall { name, args } => {
+                    if !locals.contains(name.as_str()) {
+                        *name = self.resolve_call(module, name)?;
+                    }
+                    for arg in args {
+                        self.rename_expression(module, locals, arg)?;
+                    }
+                }
+                Statement::If { condition, then_body, else_body } => {
+                    self.rename_expression(module, locals, condition)?;
+                    self.rename_statements(module, locals, then_body)?;
+                    if let Some(else_body) = else_body {
+                        self.rename_statements(module, locals, else_body)?;
+                    }
+                }
+                Statement::While { condition, body } => {
+                    self.rename_expression(module, locals, condition)?;
+                    self.rename_statements(module, locals, body)?;
+                }
+                Statement::Assert { condition, message, .. } => {
+                    self.rename_expression(module, locals, condition)?;
+                    if let Some(message) = message {
+                        self.rename_expression(module, locals, message)?;
+                    }
+                }
+                Statement::Function { .. } | Statement::Extern { .. } | Statement::Use(_) | Statement::Inlined { .. } => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn rename_expression(&self, module: &Module, locals: &HashSet<&str>, expr: &mut Expression) -> Result<(), String> {
+        match expr {
+            Expression::BinaryOp { left, right, .. } => {
+                self.rename_expression(module, locals, left)?;
+                self.rename_expression(module, locals, right)
+            }
+            Expression::FunctionCall { name, args } => {
+                if !locals.contains(name.as_str()) {
+                    *name = self.resolve_call(module, name)?;
+                }
+                for arg in args {
+                    self.rename_expression(module, locals, arg)?;
+                }
+                Ok(())
+            }
+            Expression::FunctionRef(name) => {
+                *name = self.resolve_call(module, name)?;
+                Ok(())
+            }
+            Expression::Lambda { params, body } => {
+                let names: Vec<String> = params.iter().map(|(param, _)| param.clone()).collect();
+                let mut inner = locals.clone();
+                inner.extend(names.iter().map(String::as_str));
+                self.rename_expression(module, &inner, body)
+            }
+            Expression::Index { value, index } => {
+                self.rename_expression(module, locals, value)?;
+                self.rename_expression(module, locals, index)
+            }
+            Expression::Format(parts) => {
+                for part in parts {
+                    if let FormatPart::Value { expr, .. } = part {
+                        self.rename_expression(module, locals, expr)?;
+                    }
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn resolve_call(&self, module: &Module, name: &str) -> Result<String, String> {
+        if let Some((qualifier, function)) = name.rsplit_once("::") {
+            let target = module
+                .imports
+                .get(qualifier)
+                .map(|&id| &self.modules[id])
+                .ok_or_else(|| format!("Unknown module {} in call to {}", qualifier, name))?;
+            if !defines(target, function) {
+                return Err(format!("Module {} has no function {}", qualifier, function));
+            }
+            return Ok(mangle(&target.prefix, function));
+        }
+
+        // Unqualified calls refer to the module's own functions, anything else
+        // (externs) is left for the checker
+        if defines(module, name) {
+            Ok(mangle(&module.prefix, name))
+        } else {
+            Ok(name.to_string())
+        }
+    }
+}
+
+fn defines(module: &Module, function: &str) -> bool {
+    module
+        .statements
+        .iter()
+        .any(|stmt| matches!(stmt, Statement::Function { name, .. } if name == function))
+}
+
+// Path shown in runtime error messages, relative to the working directory when possible
+fn display_name(key: &Path) -> String {
+    std::env::current_dir()
+        .ok()
+        .and_then(|dir| key.strip_prefix(dir).ok())
+        .unwrap_or(key)
+        .display()
+        .to_string()
+}
+
+fn mangle(prefix: &str, name: &str) -> String {
+    if prefix.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}__{}", prefix, name)
+    }
+}
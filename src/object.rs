@@ -0,0 +1,415 @@
+use crate::compiler::Target;
+use crate::encoder::{self, Object, Reference, Relocation, Symbol, SECTIONS};
+
+// Relocatable object files for the assembled program: ELF64 for Linux and
+// Mach-O for macOS, with the sections in encoder::SECTIONS order and every
+// label as a symbol. Empty sections other than .text are left out.
+
+pub fn write(object: &Object, target: Target) -> Result<Vec<u8>, String> {
+    match target {
+        Target::X86_64Linux => elf(object),
+        Target::X86_64MacOs => macho(object),
+        Target::Aarch64Linux => Err("The encoder writes x86-64 code only".to_string()),
+    }
+}
+
+// Indexes of the sections that go in the file
+fn present(object: &Object) -> Vec<usize> {
+    (0..SECTIONS.len()).filter(|&i| i == 0 || object.sections[i].size > 0).collect()
+}
+
+fn alignment(section: usize) -> u64 {
+    if section == 0 {
+        16
+    } else {
+        8
+    }
+}
+
+fn align(value: u64, to: u64) -> u64 {
+    value.div_ceil(to) * to
+}
+
+fn pad(bytes: &mut Vec<u8>, to: u64) {
+    bytes.resize(align(bytes.len() as u64, to) as usize, 0);
+}
+
+// Symbols in the order a symbol table wants them: locals, then defined
+// globals, then externs
+fn ordered(object: &Object) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..object.symbols.len()).collect();
+    order.sort_by_key(|&i| {
+        let symbol = &object.symbols[i];
+        match (symbol.global, symbol.definition) {
+            (false, _) => (0, String::new()),
+            (true, Some(_)) => (1, symbol.name.clone()),
+            (true, None) => (2, symbol.name.clone()),
+        }
+    });
+    order
+}
+
+struct Strings(Vec<u8>);
+
+impl Strings {
+    fn add(&mut self, name: &str) -> u32 {
+        let offset = self.0.len() as u32;
+        self.0.extend(name.as_bytes());
+        self.0.push(0);
+        offset
+    }
+}
+
+const ELF_SECTION_NAMES: [&str; 4] = [".text", ".rodata", ".data", ".bss"];
+
+// C symbols carry no underscore on Linux, so the one the code generator
+// writes for macOS is dropped from globals and externs
+fn elf_name(symbol: &Symbol) -> &str {
+    match symbol.global {
+        true => symbol.name.strip_prefix('_').unwrap_or(&symbol.name),
+        false => &symbol.name,
+    }
+}
+
+fn elf(object: &Object) -> Result<Vec<u8>, String> {
+    const R_X86_64_64: u64 = 1;
+    const R_X86_64_PC32: u64 = 2;
+    const R_X86_64_PLT32: u64 = 4;
+
+    let sections = present(object);
+    // Section header indexes: the null section, the program's sections, a
+    // .rela section for each that has relocations, then the tables
+    let index_of = |section: usize| sections.iter().position(|&s| s == section).unwrap() + 1;
+    let relocated: Vec<usize> = sections.iter().copied().filter(|&s| !object.sections[s].relocations.is_empty()).collect();
+    let note = sections.len() + relocated.len() + 1;
+    let symtab = note + 1;
+    let strtab = symtab + 1;
+    let shstrtab = strtab + 1;
+
+    // The symbol table: null, a symbol per section for relocations against
+    // it, then the program's symbols
+    let mut strings = Strings(vec![0]);
+    let mut symbols = vec![0u8; 24];
+    let mut symbol_index = vec![0; object.symbols.len()];
+    for &section in &sections {
+        symbols.extend(elf_symbol(0, 0x03, index_of(section) as u16, 0));
+    }
+    let order = ordered(object);
+    let mut first_global = None;
+    for (position, &i) in order.iter().enumerate() {
+        let symbol = &object.symbols[i];
+        symbol_index[i] = sections.len() + 1 + position;
+        if symbol.global && first_global.is_none() {
+            first_global = Some(symbol_index[i]);
+        }
+        let name = strings.add(elf_name(symbol));
+        let binding = if symbol.global { 0x10 } else { 0x00 };
+        let (shndx, value) = match symbol.definition {
+            Some((section, offset)) => (index_of(section) as u16, offset),
+            None => (0, 0),
+        };
+        symbols.extend(elf_symbol(name, binding, shndx, value));
+    }
+    let first_global = first_global.unwrap_or(sections.len() + 1 + order.len());
+
+    let mut relas = Vec::new();
+    for &section in &relocated {
+        let mut rela = Vec::new();
+        for relocation in &object.sections[section].relocations {
+            let (symbol, offset) = match relocation.target {
+                encoder::Target::Symbol(symbol) => (symbol_index[symbol], 0),
+                encoder::Target::Section(target, offset) => (index_of(target), offset as i64),
+            };
+            let kind = match relocation.kind {
+                Reference::Call if matches!(relocation.target, encoder::Target::Symbol(_)) => R_X86_64_PLT32,
+                Reference::Call | Reference::Relative => R_X86_64_PC32,
+                Reference::Absolute => R_X86_64_64,
+            };
+            rela.extend(relocation.offset.to_le_bytes());
+            rela.extend(((symbol as u64) << 32 | kind).to_le_bytes());
+            rela.extend((relocation.addend + offset).to_le_bytes());
+        }
+        relas.push(rela);
+    }
+
+    let mut names = Strings(vec![0]);
+    let mut file = vec![0u8; 64];
+    // name, type, flags, offset, size, link, info, alignment, entry size
+    let mut headers: Vec<[u64; 9]> = vec![[0; 9]];
+    for &section in &sections {
+        let data = &object.sections[section];
+        let (kind, flags) = match section {
+            0 => (1, 0x6),
+            1 => (1, 0x2),
+            2 => (1, 0x3),
+            _ => (8, 0x3),
+        };
+        pad(&mut file, alignment(section));
+        let name = names.add(ELF_SECTION_NAMES[section]) as u64;
+        headers.push([name, kind, flags, file.len() as u64, data.size, 0, 0, alignment(section), 0]);
+        file.extend(&data.bytes);
+    }
+    for (rela, &section) in relas.iter().zip(&relocated) {
+        pad(&mut file, 8);
+        let name = names.add(&format!(".rela{}", ELF_SECTION_NAMES[section])) as u64;
+        // SHF_INFO_LINK: the info field is the section the relocations apply to
+        headers.push([name, 4, 0x40, file.len() as u64, rela.len() as u64, symtab as u64, index_of(section) as u64, 8, 24]);
+        file.extend(rela);
+    }
+    // An empty .note.GNU-stack asks for a stack that is not executable
+    headers.push([names.add(".note.GNU-stack") as u64, 1, 0, file.len() as u64, 0, 0, 0, 1, 0]);
+    pad(&mut file, 8);
+    let name = names.add(".symtab") as u64;
+    headers.push([name, 2, 0, file.len() as u64, symbols.len() as u64, strtab as u64, first_global as u64, 8, 24]);
+    file.extend(&symbols);
+    let name = names.add(".strtab") as u64;
+    headers.push([name, 3, 0, file.len() as u64, strings.0.len() as u64, 0, 0, 1, 0]);
+    file.extend(&strings.0);
+    let name = names.add(".shstrtab") as u64;
+    let names = names.0;
+    headers.push([name, 3, 0, file.len() as u64, names.len() as u64, 0, 0, 1, 0]);
+    file.extend(&names);
+
+    pad(&mut file, 8);
+    let header_offset = file.len() as u64;
+    for [name, kind, flags, offset, size, link, info, alignment, entry] in &headers {
+        file.extend((*name as u32).to_le_bytes());
+        file.extend((*kind as u32).to_le_bytes());
+        file.extend(flags.to_le_bytes());
+        file.extend(0u64.to_le_bytes());
+        file.extend(offset.to_le_bytes());
+        file.extend(size.to_le_bytes());
+        file.extend((*link as u32).to_le_bytes());
+        file.extend((*info as u32).to_le_bytes());
+        file.extend(alignment.to_le_bytes());
+        file.extend(entry.to_le_bytes());
+    }
+
+    // ELF64, little endian, version 1, System V ABI
+    let mut header = vec![0x7F, b'E', b'L', b'F', 2, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    header.extend(1u16.to_le_bytes()); // relocatable
+    header.extend(62u16.to_le_bytes()); // x86-64
+    header.extend(1u32.to_le_bytes());
+    header.extend(0u64.to_le_bytes()); // entry
+    header.extend(0u64.to_le_bytes()); // program headers
+    header.extend(header_offset.to_le_bytes());
+    header.extend(0u32.to_le_bytes()); // flags
+    header.extend(64u16.to_le_bytes());
+    header.extend(0u16.to_le_bytes());
+    header.extend(0u16.to_le_bytes());
+    header.extend(64u16.to_le_bytes());
+    header.extend((headers.len() as u16).to_le_bytes());
+    header.extend((shstrtab as u16).to_le_bytes());
+    file[..64].copy_from_slice(&header);
+    Ok(file)
+}
+
+fn elf_symbol(name: u32, info: u8, shndx: u16, value: u64) -> Vec<u8> {
+    let mut bytes = name.to_le_bytes().to_vec();
+    bytes.push(info);
+    bytes.push(0);
+    bytes.extend(shndx.to_le_bytes());
+    bytes.extend(value.to_le_bytes());
+    bytes.extend(0u64.to_le_bytes());
+    bytes
+}
+
+// Where the pc-relative field of a relocation is measured from
+fn relocation_end(relocation: &Relocation) -> u64 {
+    (relocation.offset as i64 - relocation.addend) as u64
+}
+
+// Segment and section names NASM gives the sections in Mach-O
+const MACHO_SECTIONS: [(&str, &str); 4] = [("__TEXT", "__text"), ("__DATA", "__const"), ("__DATA", "__data"), ("__DATA", "__bss")];
+
+fn macho(object: &Object) -> Result<Vec<u8>, String> {
+    const X86_64_RELOC_UNSIGNED: u32 = 0;
+    const X86_64_RELOC_SIGNED: u32 = 1;
+    const X86_64_RELOC_BRANCH: u32 = 2;
+    const X86_64_RELOC_SIGNED_1: u32 = 6;
+
+    let sections = present(object);
+    let ordinal = |section: usize| sections.iter().position(|&s| s == section).unwrap() + 1;
+
+    // Addresses within the object, in section order
+    let mut addresses = vec![0u64; SECTIONS.len()];
+    let mut address = 0;
+    for &section in &sections {
+        address = align(address, alignment(section));
+        addresses[section] = address;
+        address += object.sections[section].size;
+    }
+    let vm_size = address;
+
+    let header_size = 32 + (72 + 80 * sections.len()) + 24 + 80;
+    let data_start = align(header_size as u64, 16);
+    let file_size: u64 = sections
+        .iter()
+        .filter(|&&s| s != 3)
+        .map(|&s| addresses[s] + object.sections[s].size)
+        .max()
+        .unwrap_or(0);
+
+    let order = ordered(object);
+    let mut symbol_index = vec![0; object.symbols.len()];
+    for (position, &i) in order.iter().enumerate() {
+        symbol_index[i] = position;
+    }
+
+    // Section contents, with pc-relative fields into other sections holding
+    // the displacement as if the object were loaded at its addresses
+    let mut body = Vec::new();
+    let mut relocation_tables = Vec::new();
+    for &section in &sections {
+        let data = &object.sections[section];
+        let mut bytes = data.bytes.clone();
+        let mut table = Vec::new();
+        for relocation in &data.relocations {
+            let field = relocation.offset as usize;
+            let pc_relative = relocation.kind != Reference::Absolute;
+            let (extern_, number, value) = match relocation.target {
+                encoder::Target::Symbol(symbol) => (1, symbol_index[symbol] as u32, 0),
+                encoder::Target::Section(target, offset) => {
+                    let target_address = (addresses[target] + offset) as i64;
+                    let value = if pc_relative {
+                        target_address - (addresses[section] + relocation_end(relocation)) as i64
+                    } else {
+                        target_address
+                    };
+                    (0, ordinal(target) as u32, value)
+                }
+            };
+            let (kind, length) = match relocation.kind {
+                Reference::Call => (X86_64_RELOC_BRANCH, 2),
+                Reference::Relative => match -relocation.addend - 4 {
+                    0 => (X86_64_RELOC_SIGNED, 2),
+                    trailing @ (1 | 2 | 4) => (X86_64_RELOC_SIGNED_1 + trailing.trailing_zeros(), 2),
+                    _ => return Err("Unsupported relocation".into()),
+                },
+                Reference::Absolute => (X86_64_RELOC_UNSIGNED, 3),
+            };
+            if length == 3 {
+                bytes[field..field + 8].copy_from_slice(&value.to_le_bytes());
+            } else {
+                bytes[field..field + 4].copy_from_slice(&(value as i32).to_le_bytes());
+            }
+            table.extend((relocation.offset as u32).to_le_bytes());
+            let info = number | (pc_relative as u32) << 24 | length << 25 | extern_ << 27 | kind << 28;
+            table.extend(info.to_le_bytes());
+        }
+        if section != 3 {
+            body.resize(addresses[section] as usize, 0);
+            body.extend(bytes);
+        }
+        relocation_tables.push(table);
+    }
+    body.resize(file_size as usize, 0);
+
+    let mut file = Vec::new();
+    let relocations_start = data_start + file_size;
+    let mut relocation_offsets = Vec::new();
+    let mut offset = align(relocations_start, 8);
+    for table in &relocation_tables {
+        relocation_offsets.push(offset);
+        offset += table.len() as u64;
+    }
+    let symbols_start = align(offset, 8);
+
+    let mut strings = Strings(vec![b' ', 0]);
+    let mut symbols = Vec::new();
+    let (mut locals, mut defined, mut undefined) = (0u32, 0u32, 0u32);
+    for &i in &order {
+        let symbol = &object.symbols[i];
+        symbols.extend(strings.add(&symbol.name).to_le_bytes());
+        match symbol.definition {
+            Some((section, offset)) => {
+                // N_SECT, with N_EXT for globals
+                symbols.push(if symbol.global { 0x0F } else { 0x0E });
+                symbols.push(ordinal(section) as u8);
+                symbols.extend(0u16.to_le_bytes());
+                symbols.extend((addresses[section] + offset).to_le_bytes());
+            }
+            None => {
+                symbols.push(0x01);
+                symbols.push(0);
+                symbols.extend(0u16.to_le_bytes());
+                symbols.extend(0u64.to_le_bytes());
+            }
+        }
+        match (symbol.global, symbol.definition) {
+            (false, _) => locals += 1,
+            (true, Some(_)) => defined += 1,
+            (true, None) => undefined += 1,
+        }
+    }
+    let strings_start = symbols_start + symbols.len() as u64;
+    pad(&mut strings.0, 8);
+
+    // mach_header_64: x86-64, all subtypes, object file
+    let commands_size = header_size as u32 - 32;
+    for word in [0xFEED_FACF, 0x0100_0007, 3, 1, 3, commands_size, 0, 0] {
+        file.extend(u32::to_le_bytes(word));
+    }
+
+    // LC_SEGMENT_64 holding every section
+    file.extend(0x19u32.to_le_bytes());
+    file.extend(((72 + 80 * sections.len()) as u32).to_le_bytes());
+    file.extend([0; 16]);
+    for value in [0, vm_size, data_start, file_size] {
+        file.extend(u64::to_le_bytes(value));
+    }
+    for value in [7, 7, sections.len() as u32, 0] {
+        file.extend(u32::to_le_bytes(value));
+    }
+    for (n, &section) in sections.iter().enumerate() {
+        let (segment, name) = MACHO_SECTIONS[section];
+        file.extend(fixed_name(name));
+        file.extend(fixed_name(segment));
+        file.extend(addresses[section].to_le_bytes());
+        file.extend(object.sections[section].size.to_le_bytes());
+        let offset = if section == 3 { 0 } else { data_start + addresses[section] };
+        file.extend((offset as u32).to_le_bytes());
+        file.extend(alignment(section).trailing_zeros().to_le_bytes());
+        let count = relocation_tables[n].len() / 8;
+        let relocation_offset = if count == 0 { 0 } else { relocation_offsets[n] };
+        file.extend((relocation_offset as u32).to_le_bytes());
+        file.extend((count as u32).to_le_bytes());
+        // Pure and some instructions for code, zero fill for .bss
+        let flags: u32 = match section {
+            0 => 0x8000_0400,
+            3 => 0x1,
+            _ => 0,
+        };
+        for value in [flags, 0, 0, 0] {
+            file.extend(value.to_le_bytes());
+        }
+    }
+
+    // LC_SYMTAB
+    for value in [0x2, 24, symbols_start as u32, order.len() as u32, strings_start as u32, strings.0.len() as u32] {
+        file.extend(u32::to_le_bytes(value));
+    }
+    // LC_DYSYMTAB: the ranges of local, defined external and undefined symbols
+    let mut dysymtab = vec![0xB, 80, 0, locals, locals, defined, locals + defined, undefined];
+    dysymtab.resize(20, 0);
+    for value in dysymtab {
+        file.extend(value.to_le_bytes());
+    }
+
+    file.resize(data_start as usize, 0);
+    file.extend(body);
+    file.resize(relocation_offsets.first().copied().unwrap_or(relocations_start) as usize, 0);
+    for table in relocation_tables {
+        file.extend(table);
+    }
+    file.resize(symbols_start as usize, 0);
+    file.extend(symbols);
+    file.extend(strings.0);
+    Ok(file)
+}
+
+fn fixed_name(name: &str) -> [u8; 16] {
+    let mut bytes = [0; 16];
+    bytes[..name.len()].copy_from_slice(name.as_bytes());
+    bytes
+}
@@ -1,25 +1,84 @@
-use crate::tokens::Token;
+use crate::tokens::{Span, Token};
 use std::{self, iter::Peekable};
+use std::rc::Rc;
 use std::slice::Iter;
-use crate::ast::{BinaryOperator, Expression, Statement};
+use crate::ast::{BinaryOperator, Expression, FormatPart, FormatSpec, Import, Location, Statement, Type};
+use crate::lexer::Lexer;
+
+// The token stream together with where each token came from
+struct Tokens<'a> {
+    iter: Peekable<Iter<'a, Token>>,
+    spans: &'a [Span],
+    source: &'a str,
+    file: Rc<str>,
+}
+
+impl<'a> Tokens<'a> {
+    fn new(tokens: &'a [Token], spans: &'a [Span], source: &'a str, file: Rc<str>) -> Self {
+        Tokens { iter: tokens.iter().peekable(), spans, source, file }
+    }
+
+    // Index of the next token
+    fn index(&self) -> usize {
+        self.spans.len() - self.iter.len()
+    }
+
+    // Source text from the token at `start` up to the last one consumed
+    fn text_from(&self, start: usize) -> &'a str {
+        match (self.spans.get(start), self.index().checked_sub(1)) {
+            (Some(first), Some(last)) if last >= start => &self.source[first.start..self.spans[last].end],
+            _ => "",
+        }
+    }
 
-pub fn parse_program(tokens: &[Token]) -> Option<Vec<Statement>>{
-    let mut iter = tokens.iter().peekable();
+    fn peek(&mut self) -> Option<&&'a Token> {
+        self.iter.peek()
+    }
+
+    // Location of the next token, or of the last one at the end of input
+    fn location(&self) -> Location {
+        let line = self.spans.get(self.index()).or(self.spans.last()).map_or(1, |span| span.line);
+        Location { file: self.file.clone(), line }
+    }
+}
+
+impl<'a> Iterator for Tokens<'a> {
+    type Item = &'a Token;
+
+    fn next(&mut self) -> Option<&'a Token> {
+        self.iter.next()
+    }
+}
+
+pub fn parse_program(tokens: &[Token], spans: &[Span], source: &str, file: &str) -> Result<Vec<Statement>, String> {
+    let mut iter = Tokens::new(tokens, spans, source, file.into());
     let mut statements = Vec::new();
 
     while iter.peek().is_some() {
-        let stmt = parse_statement(&mut iter);
-        statements.push(stmt.expect("Unexpected Statement"));
+        let stmt = parse_statement(&mut iter).map_err(|err| format!("{} at {}", err, iter.location()))?;
+        statements.push(stmt);
     }
 
-    Some(statements)
+    Ok(statements)
 }
 
 
-fn parse_statement(iter: &mut Peekable<Iter<Token>>) -> Result<Statement, String> {
+fn parse_statement(iter: &mut Tokens) -> Result<Statement, String> {
     
     let statement = match iter.peek() {
         Some(Token::Function) => parse_function(iter),
+        Some(Token::Attribute(_)) => parse_attributes(iter),
+        Some(Token::Extern) => parse_extern(iter),
+        Some(Token::Use) => {
+            iter.next();
+            match iter.next() {
+                Some(Token::StringLiteral(path)) => Ok(Statement::Use(Import::File(path.clone()))),
+                Some(Token::Identifier(path)) => Ok(Statement::Use(Import::Module(
+                    path.split("::").map(String::from).collect(),
+                ))),
+                other => Err(format!("Expected module after use, found {:?}", other)),
+            }
+        }
         Some(Token::FunctionCall(name)) => {
             let name = name.clone();
             iter.next();
@@ -38,10 +97,14 @@ fn parse_statement(iter: &mut Peekable<Iter<Token>>) -> Result<Statement, String
         },
         Some(Token::If) => parse_if(iter),
 
-        Some(Token::Print) => {
-            iter.next();
-            let expr = parse_expression(iter)?;
-            Ok(Statement::Print(expr))
+        Some(Token::Print) | Some(Token::Write) => {
+            let newline = matches!(iter.next(), Some(Token::Print));
+            let mut args = vec![parse_expression(iter)?];
+            while matches!(iter.peek(), Some(Token::Comma)) {
+                iter.next();
+                args.push(parse_expression(iter)?);
+            }
+            Ok(Statement::Print { args, newline })
         }
         Some(Token::Send) => {
             iter.next();
@@ -49,6 +112,7 @@ fn parse_statement(iter: &mut Peekable<Iter<Token>>) -> Result<Statement, String
             Ok(Statement::Send(expr))
         }
         Some(Token::While) => parse_while(iter),
+        Some(Token::Assert) => parse_assert(iter),
         Some(Token::Identifier(_)) => parse_assignment(iter),
         _ => Err(format!("Cannot parse found {:?}", iter.peek())),
     }?;
@@ -76,7 +140,7 @@ fn parse_statement(iter: &mut Peekable<Iter<Token>>) -> Result<Statement, String
     }
 }
 
-fn parse_function(iter: &mut Peekable<Iter<Token>>) -> Result<Statement, String> {
+fn parse_function(iter: &mut Tokens) -> Result<Statement, String> {
     iter.next();
     let name = match iter.peek() {
         Some(Token::Identifier(name)) => name.clone(),
@@ -87,12 +151,7 @@ fn parse_function(iter: &mut Peekable<Iter<Token>>) -> Result<Statement, String>
     if matches!(iter.peek(), Some(Token::LParen)) {
         iter.next();
         while !matches!(**iter.peek().unwrap(), Token::RParen) {
-            let arg = parse_arg(iter);
-            if let Ok(arg) = &arg {
-                args.push(arg.clone());
-            } else if let Err(err) = &arg {
-                panic!("{}", err);
-            }
+            args.push(parse_arg(iter)?);
 
             if matches!(**iter.peek().unwrap(), Token::Comma) {
                 iter.next();
@@ -102,7 +161,7 @@ fn parse_function(iter: &mut Peekable<Iter<Token>>) -> Result<Statement, String>
         }
         iter.next();
     }
-
+    let returns = parse_return_type(iter)?;
 
     let mut body = Vec::new();
     while !matches!(**iter.peek().unwrap(), Token::End) {
@@ -112,11 +171,95 @@ fn parse_function(iter: &mut Peekable<Iter<Token>>) -> Result<Statement, String>
     Ok(Statement::Function{
         name,
         params: args,
-        body
+        returns,
+        body,
+        captures: Vec::new(),
+        attributes: Vec::new(),
     })
 }
 
-fn parse_if(iter: &mut Peekable<Iter<Token>>) -> Result<Statement, String> {
+// Attributes understood on functions
+const ATTRIBUTES: &[&str] = &["export", "inline", "noinline"];
+
+fn parse_attributes(iter: &mut Tokens) -> Result<Statement, String> {
+    let mut names = Vec::new();
+    while let Some(Token::Attribute(name)) = iter.peek() {
+        if !ATTRIBUTES.contains(&name.as_str()) {
+            return Err(format!("Unknown attribute @{}", name));
+        }
+        names.push(name.clone());
+        iter.next();
+    }
+    expect_token(iter, Token::Function)?;
+    let mut function = parse_function(iter)?;
+    if let Statement::Function { attributes, .. } = &mut function {
+        *attributes = names;
+    }
+    Ok(function)
+}
+
+fn parse_extern(iter: &mut Tokens) -> Result<Statement, String> {
+    iter.next(); // Consuming extern
+    expect_token(iter, Token::Function)?;
+    iter.next();
+    let name = match iter.next() {
+        Some(Token::Identifier(name)) => name.clone(),
+        other => return Err(format!("Expected extern function name, found {:?}", other)),
+    };
+
+    let mut params = Vec::new();
+    expect_token(iter, Token::LParen)?;
+    iter.next();
+    while !matches!(iter.peek(), Some(Token::RParen)) {
+        // Unlike those of Bonk functions, untyped extern parameters are ints
+        let (param, ty) = parse_arg(iter)?;
+        params.push((param, Some(ty.unwrap_or(Type::Int))));
+
+        if matches!(iter.peek(), Some(Token::Comma)) {
+            iter.next();
+        } else if !matches!(iter.peek(), Some(Token::RParen)) {
+            return Err(format!("Extern {} declared incorrectly", name));
+        }
+    }
+    iter.next(); // consume RParen
+    // C functions can also return a 64-bit `long`, such as a size or a pointer
+    let mut long = false;
+    let returns = if matches!(iter.peek(), Some(Token::Arrow)) {
+        iter.next();
+        if matches!(iter.peek(), Some(Token::Identifier(ty)) if ty == "long") {
+            iter.next();
+            long = true;
+            Type::Int
+        } else {
+            parse_type(iter)?
+        }
+    } else {
+        Type::Int
+    };
+
+    Ok(Statement::Extern { name, params, returns, long })
+}
+
+// Optional `-> type` after a parameter list, defaulting to int
+fn parse_return_type(iter: &mut Tokens) -> Result<Type, String> {
+    if matches!(iter.peek(), Some(Token::Arrow)) {
+        iter.next();
+        parse_type(iter)
+    } else {
+        Ok(Type::Int)
+    }
+}
+
+fn parse_type(iter: &mut Tokens) -> Result<Type, String> {
+    match iter.next() {
+        Some(Token::Identifier(ty)) if ty == "int" => Ok(Type::Int),
+        Some(Token::Identifier(ty)) if ty == "str" => Ok(Type::Str),
+        Some(Token::Identifier(ty)) if ty == "float" => Ok(Type::Float),
+        other => Err(format!("Unknown type {:?}", other)),
+    }
+}
+
+fn parse_if(iter: &mut Tokens) -> Result<Statement, String> {
     iter.next(); // Consuming if
     let condition = parse_expression(iter)?;
     expect_token(iter, Token::Then)?;
@@ -144,19 +287,25 @@ fn parse_if(iter: &mut Peekable<Iter<Token>>) -> Result<Statement, String> {
     })
 }
 
-fn parse_arg(iter: &mut Peekable<Iter<Token>>) -> Result<Expression, String> {
+// A parameter name with an optional `: type` annotation
+fn parse_arg(iter: &mut Tokens) -> Result<(String, Option<Type>), String> {
     match iter.peek().unwrap() {
         Token::Identifier(na) => {
             let name = na.clone();
             iter.next();
-            Ok(Expression::FunctionArg(name))
+            if matches!(iter.peek(), Some(Token::Colon)) {
+                iter.next();
+                Ok((name, Some(parse_type(iter)?)))
+            } else {
+                Ok((name, None))
+            }
         },
         _ => Err("Arg defined incorrectly".to_string()),
     }
 
 }
 
-fn parse_assignment(iter: &mut Peekable<Iter<Token>>) -> Result<Statement, String> {
+fn parse_assignment(iter: &mut Tokens) -> Result<Statement, String> {
     let name_token = iter.next().ok_or("Expected identifier")?;
     let name = match name_token {
         Token::Identifier(n) => n.clone(),
@@ -189,7 +338,7 @@ fn get_operator(token: &Token) -> Result<BinaryOperator, String> {
 
 }
 
-fn expect_token(iter: &mut Peekable<Iter<Token>>, expected: Token) -> Result<(), String> {
+fn expect_token(iter: &mut Tokens, expected: Token) -> Result<(), String> {
     if let Some(token) = iter.peek() {
         if **token == expected {
             Ok(())
@@ -209,11 +358,11 @@ fn get_precedence(op: &Token) -> u8 {
     }
 }
 
-fn parse_expression(iter: &mut Peekable<Iter<Token>>) -> Result<Expression, String> {
+fn parse_expression(iter: &mut Tokens) -> Result<Expression, String> {
     parse_binary_expression(iter, 0) 
 }
 
-fn parse_binary_expression(iter: &mut Peekable<Iter<Token>>, min_prec: u8) -> Result<Expression, String> {
+fn parse_binary_expression(iter: &mut Tokens, min_prec: u8) -> Result<Expression, String> {
     let mut left = parse_atomics(iter)?;
 
     while let Some(op_token) = iter.peek() {
@@ -225,32 +374,84 @@ fn parse_binary_expression(iter: &mut Peekable<Iter<Token>>, min_prec: u8) -> Re
             break;
         }
         let op = get_operator(op_token)?;
+        let location = iter.location();
         iter.next(); 
         let right = parse_binary_expression(iter, prec + 1)?;
         left = Expression::BinaryOp {
             left: Box::new(left),
             op,
             right: Box::new(right),
+            location,
         };
     } 
     Ok(left)
 }
 
 
-fn parse_atomics(iter: &mut Peekable<Iter<Token>>) -> Result<Expression, String> {
+fn parse_atomics(iter: &mut Tokens) -> Result<Expression, String> {
+    let mut atom = parse_primary(iter)?;
+    while matches!(iter.peek(), Some(Token::LBracket)) {
+        iter.next();
+        let index = parse_expression(iter)?;
+        expect_token(iter, Token::RBracket)?;
+        iter.next();
+        atom = Expression::Index {
+            value: Box::new(atom),
+            index: Box::new(index),
+        };
+    }
+    Ok(atom)
+}
+
+fn parse_primary(iter: &mut Tokens) -> Result<Expression, String> {
     match iter.peek() {
         Some(Token::Number(n)) => {
             iter.next();
             Ok(Expression::Integer(*n))
         }
+        Some(Token::Float(f)) => {
+            iter.next();
+            Ok(Expression::Float(*f))
+        }
         Some(Token::StringLiteral(s)) => {
+            let location = iter.location();
             iter.next();
-            Ok(Expression::StringLiteral(s.clone()))
+            if s.contains(['{', '}']) {
+                parse_format(s, &location)
+            } else {
+                Ok(Expression::StringLiteral(s.clone()))
+            }
         }
         Some(Token::Identifier(name)) => {
             iter.next();
             Ok(Expression::Variable(name.clone()))
         }
+        Some(Token::Fn) => {
+            iter.next();
+            expect_token(iter, Token::LParen)?;
+            iter.next();
+            let mut params = Vec::new();
+            while !matches!(iter.peek(), Some(Token::RParen)) {
+                params.push(parse_arg(iter)?);
+                if matches!(iter.peek(), Some(Token::Comma)) {
+                    iter.next();
+                } else if !matches!(iter.peek(), Some(Token::RParen)) {
+                    return Err("Anonymous function parameters declared incorrectly".into());
+                }
+            }
+            iter.next(); // consume RParen
+            expect_token(iter, Token::FatArrow)?;
+            iter.next();
+            let body = parse_expression(iter)?;
+            Ok(Expression::Lambda { params, body: Box::new(body) })
+        }
+        Some(Token::Ampersand) => {
+            iter.next();
+            match iter.next() {
+                Some(Token::Identifier(name)) => Ok(Expression::FunctionRef(name.clone())),
+                other => Err(format!("Expected function name after &, found {:?}", other)),
+            }
+        }
         Some(Token::FunctionCall(_)) => {
             let name = match iter.next() {
                 Some(Token::FunctionCall(n)) => n.clone(),
@@ -273,7 +474,23 @@ fn parse_atomics(iter: &mut Peekable<Iter<Token>>) -> Result<Expression, String>
     }
 }
 
-fn parse_while(iter: &mut Peekable<Iter<Token>>) -> Result<Statement, String> {
+fn parse_assert(iter: &mut Tokens) -> Result<Statement, String> {
+    let location = iter.location();
+    iter.next(); // Consuming assert
+    let start = iter.index();
+    let condition = parse_expression(iter)?;
+    // Conditions spanning several lines are reported on one
+    let text = iter.text_from(start).split_whitespace().collect::<Vec<_>>().join(" ");
+
+    let mut message = None;
+    if matches!(iter.peek(), Some(Token::Comma)) {
+        iter.next();
+        message = Some(parse_expression(iter)?);
+    }
+    Ok(Statement::Assert { condition, message, text, location })
+}
+
+fn parse_while(iter: &mut Tokens) -> Result<Statement, String> {
     iter.next(); // Consuming while
     let condition = parse_expression(iter)?;
     expect_token(iter, Token::Do)?;
@@ -291,4 +508,112 @@ fn parse_while(iter: &mut Peekable<Iter<Token>>) -> Result<Statement, String> {
     })
 }
 
+// Splits an interpolated string into text and `{expression:spec}` parts.
+// `{{` and `}}` stand for literal braces.
+fn parse_format(s: &str, location: &Location) -> Result<Expression, String> {
+    let mut parts = Vec::new();
+    let mut text = String::new();
+    let mut chars = s.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                text.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                text.push('}');
+            }
+            '{' => {
+                let mut inner = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => inner.push(c),
+                        None => return Err(format!("Unclosed {{ in \"{}\"", s)),
+                    }
+                }
+                if !text.is_empty() {
+                    parts.push(FormatPart::Text(std::mem::take(&mut text)));
+                }
+                parts.push(parse_format_value(&inner, location)?);
+            }
+            '}' => return Err(format!("Unmatched }} in \"{}\"", s)),
+            _ => text.push(c),
+        }
+    }
+    if !text.is_empty() {
+        parts.push(FormatPart::Text(text));
+    }
+    Ok(Expression::Format(parts))
+}
+
+fn parse_format_value(inner: &str, location: &Location) -> Result<FormatPart, String> {
+    // The spec follows a single colon; `::` belongs to module paths
+    let bytes = inner.as_bytes();
+    let colon = (0..bytes.len()).rev().find(|&i| {
+        bytes[i] == b':'
+            && (i == 0 || bytes[i - 1] != b':')
+            && bytes.get(i + 1) != Some(&b':')
+    });
+    let (source, spec) = match colon {
+        Some(i) => (&inner[..i], parse_format_spec(&inner[i + 1..])?),
+        None => (inner, FormatSpec::default()),
+    };
+
+    let mut lexer = Lexer::new(source.to_string());
+    let tokens = lexer.tokenise()?;
+    // The whole value is reported at the line of its string literal
+    let spans: Vec<Span> = lexer.spans().iter().map(|span| Span { line: location.line, ..*span }).collect();
+    let mut iter = Tokens::new(&tokens, &spans, source, location.file.clone());
+    let expr = parse_expression(&mut iter)?;
+    if let Some(extra) = iter.next() {
+        return Err(format!("Unexpected {:?} in {{{}}}", extra, inner));
+    }
+    Ok(FormatPart::Value { expr, spec })
+}
+
+fn parse_format_spec(spec: &str) -> Result<FormatSpec, String> {
+    let mut result = FormatSpec::default();
+    let mut chars = spec.chars().peekable();
+
+    match chars.peek() {
+        Some('<') => {
+            result.left_align = true;
+            chars.next();
+        }
+        Some('>') => {
+            chars.next();
+        }
+        _ => {}
+    }
+    if chars.peek() == Some(&'0') {
+        result.zero_pad = true;
+        chars.next();
+    }
+    result.width = take_number(&mut chars);
+    if chars.peek() == Some(&'.') {
+        chars.next();
+        result.precision = Some(take_number(&mut chars).ok_or("Expected precision after '.'")?);
+    }
+    if let Some(kind) = chars.next() {
+        if !"dxXofes".contains(kind) {
+            return Err(format!("Unknown format type '{}'", kind));
+        }
+        result.kind = Some(kind);
+    }
+    if let Some(extra) = chars.next() {
+        return Err(format!("Unexpected '{}' in format spec {}", extra, spec));
+    }
+    Ok(result)
+}
 
+fn take_number(chars: &mut Peekable<std::str::Chars>) -> Option<usize> {
+    let mut digits = String::new();
+    while let Some(c) = chars.peek().filter(|c| c.is_ascii_digit()) {
+        digits.push(*c);
+        chars.next();
+    }
+    digits.parse().ok()
+}
@@ -0,0 +1,222 @@
+use crate::ast::{self, Statement};
+use crate::checker::check_program;
+use crate::closures::convert_closures;
+use crate::compiler::Options;
+use crate::dead_code::eliminate_dead_code;
+use crate::fold::fold_constants;
+use crate::inline::inline_functions;
+use crate::ir;
+use crate::loops;
+use crate::peephole;
+
+// The pass manager. Passes are grouped in stages by what they work on: the
+// whole program as an AST, each function lowered to IR, and the generated
+// instructions. The stages always run in that order; within a stage the
+// selected passes run in the order they are listed, and may be listed twice.
+// The front end, closure conversion and the checker, runs before any of them
+// whatever is selected.
+
+pub type Program = Vec<Statement>;
+
+pub trait Pass<T> {
+    fn name(&self) -> &'static str;
+    fn run(&self, input: T, options: &Options) -> Result<T, String>;
+}
+
+// What a stage works on
+pub trait Unit {
+    // Comment marker for the header of a dump
+    const COMMENT: &'static str;
+    fn dump(&self) -> Vec<String>;
+    // Consistency checks run after every pass
+    fn verify(&self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+impl Unit for Program {
+    const COMMENT: &'static str = "#";
+    fn dump(&self) -> Vec<String> {
+        ast::dump(self)
+    }
+}
+
+impl Unit for ir::Function {
+    const COMMENT: &'static str = ";";
+    fn dump(&self) -> Vec<String> {
+        self.to_string().lines().map(String::from).collect()
+    }
+    fn verify(&self) -> Result<(), String> {
+        ir::verify(self)
+    }
+}
+
+impl Unit for Vec<String> {
+    const COMMENT: &'static str = ";";
+    fn dump(&self) -> Vec<String> {
+        self.clone()
+    }
+}
+
+struct Closures;
+struct Check;
+struct Inline;
+struct Fold;
+struct DeadCode;
+struct Loops;
+struct Peephole;
+
+impl Pass<Program> for Closures {
+    fn name(&self) -> &'static str {
+        "closures"
+    }
+    fn run(&self, program: Program, _: &Options) -> Result<Program, String> {
+        convert_closures(program)
+    }
+}
+
+impl Pass<Program> for Check {
+    fn name(&self) -> &'static str {
+        "check"
+    }
+    fn run(&self, program: Program, options: &Options) -> Result<Program, String> {
+        check_program(&program, options.freestanding)?;
+        Ok(program)
+    }
+}
+
+impl Pass<Program> for Inline {
+    fn name(&self) -> &'static str {
+        "inline"
+    }
+    fn run(&self, program: Program, _: &Options) -> Result<Program, String> {
+        inline_functions(program)
+    }
+}
+
+impl Pass<Program> for Fold {
+    fn name(&self) -> &'static str {
+        "fold"
+    }
+    fn run(&self, program: Program, _: &Options) -> Result<Program, String> {
+        fold_constants(program)
+    }
+}
+
+impl Pass<Program> for DeadCode {
+    fn name(&self) -> &'static str {
+        "dce"
+    }
+    fn run(&self, program: Program, _: &Options) -> Result<Program, String> {
+        let (program, warnings) = eliminate_dead_code(program);
+        for warning in warnings {
+            eprintln!("Warning: {}", warning);
+        }
+        Ok(program)
+    }
+}
+
+impl Pass<ir::Function> for Loops {
+    fn name(&self) -> &'static str {
+        "loops"
+    }
+    fn run(&self, mut function: ir::Function, options: &Options) -> Result<ir::Function, String> {
+        loops::optimize(&mut function, options.overflow);
+        Ok(function)
+    }
+}
+
+impl Pass<Vec<String>> for Peephole {
+    fn name(&self) -> &'static str {
+        "peephole"
+    }
+    fn run(&self, lines: Vec<String>, _: &Options) -> Result<Vec<String>, String> {
+        Ok(peephole::optimize(lines))
+    }
+}
+
+const FRONT_END: [&dyn Pass<Program>; 2] = [&Closures, &Check];
+const AST_PASSES: [&dyn Pass<Program>; 3] = [&Inline, &Fold, &DeadCode];
+const IR_PASSES: [&dyn Pass<ir::Function>; 1] = [&Loops];
+const ASM_PASSES: [&dyn Pass<Vec<String>>; 1] = [&Peephole];
+
+// The passes an optimization level selects. Inlining comes first so that
+// folding sees the arguments in the inlined bodies.
+pub fn default_passes(opt_level: u8) -> Vec<String> {
+    let names: &[&str] = match opt_level {
+        0 => &["fold", "dce"],
+        1 => &["inline", "fold", "dce", "peephole"],
+        _ => &["inline", "fold", "dce", "loops", "peephole"],
+    };
+    names.iter().map(|name| name.to_string()).collect()
+}
+
+// Optional passes, in the order of their stages
+fn optional_names() -> Vec<&'static str> {
+    let ast = AST_PASSES.iter().map(|pass| pass.name());
+    let ir = IR_PASSES.iter().map(|pass| pass.name());
+    ast.chain(ir).chain(ASM_PASSES.iter().map(|pass| pass.name())).collect()
+}
+
+// A comma-separated list of passes, as given to --passes
+pub fn parse_list(list: &str) -> Result<Vec<String>, String> {
+    let known = optional_names();
+    let mut passes = Vec::new();
+    for name in list.split(',').filter(|name| !name.is_empty()) {
+        if !known.contains(&name) {
+            return Err(format!("Unknown pass {}, expected one of {}", name, known.join(", ")));
+        }
+        passes.push(name.to_string());
+    }
+    Ok(passes)
+}
+
+// Checks the pass given to --print-after runs at all
+pub fn check_print_after(name: &str, options: &Options) -> Result<(), String> {
+    if FRONT_END.iter().any(|pass| pass.name() == name) || options.passes.iter().any(|pass| pass == name) {
+        return Ok(());
+    }
+    if optional_names().contains(&name) {
+        Err(format!("Pass {} is not selected, so there is nothing to print after it", name))
+    } else {
+        Err(format!("Unknown pass {}", name))
+    }
+}
+
+pub fn run_ast(program: Program, options: &Options) -> Result<Program, String> {
+    let mut program = program;
+    for pass in FRONT_END {
+        program = run_pass(pass, program, options)?;
+    }
+    run_stage(&AST_PASSES, program, options)
+}
+
+pub fn run_ir(function: ir::Function, options: &Options) -> Result<ir::Function, String> {
+    run_stage(&IR_PASSES, function, options)
+}
+
+pub fn run_asm(lines: Vec<String>, options: &Options) -> Result<Vec<String>, String> {
+    run_stage(&ASM_PASSES, lines, options)
+}
+
+fn run_stage<T: Unit>(stage: &[&dyn Pass<T>], unit: T, options: &Options) -> Result<T, String> {
+    let mut unit = unit;
+    for name in &options.passes {
+        if let Some(pass) = stage.iter().find(|pass| pass.name() == name) {
+            unit = run_pass(*pass, unit, options)?;
+        }
+    }
+    Ok(unit)
+}
+
+fn run_pass<T: Unit>(pass: &dyn Pass<T>, unit: T, options: &Options) -> Result<T, String> {
+    let unit = pass.run(unit, options)?;
+    unit.verify()?;
+    if options.print_after.as_deref() == Some(pass.name()) {
+        println!("{} after {}", T::COMMENT, pass.name());
+        for line in unit.dump() {
+            println!("{}", line);
+        }
+    }
+    Ok(unit)
+}
@@ -0,0 +1,159 @@
+// Peephole optimizer over the generated instructions. The code generator is a
+// stack machine, so most expressions go through `push rax` / `pop rcx`, and
+// conditions other than those of `if` and `while`, such as asserts, are
+// materialised as 0 or 1 before they are tested. The rules
+// below rewrite short windows of instructions into cheaper equivalents and
+// are applied until none matches.
+//
+// They rely on two properties of the generated code: rcx only carries a left
+// operand from its `pop rcx` to the instruction that combines it with rax, and
+// rax holds nothing across a conditional branch on a condition value.
+
+pub fn optimize(lines: Vec<String>) -> Vec<String> {
+    let mut lines = lines;
+    loop {
+        let before = lines.len();
+        lines = rewrite(lines);
+        lines = remove_dead_jumps(lines);
+        if lines.len() == before {
+            return lines;
+        }
+    }
+}
+
+// An instruction split into its mnemonic and operands; None for labels and directives
+fn parse(line: &str) -> Option<(&str, Vec<&str>)> {
+    if !line.starts_with(' ') {
+        return None;
+    }
+    let line = line.trim();
+    let (op, rest) = line.split_once(' ').unwrap_or((line, ""));
+    let operands = if rest.is_empty() { Vec::new() } else { rest.split(", ").collect() };
+    Some((op, operands))
+}
+
+fn rewrite(lines: Vec<String>) -> Vec<String> {
+    let mut result: Vec<String> = Vec::with_capacity(lines.len());
+    let mut i = 0;
+    while i < lines.len() {
+        let window: Vec<Option<(&str, Vec<&str>)>> = lines[i..lines.len().min(i + 4)].iter().map(|line| parse(line)).collect();
+        match apply_rules(&window) {
+            Some((consumed, replacement)) => {
+                result.extend(replacement);
+                i += consumed;
+            }
+            None => {
+                result.push(lines[i].clone());
+                i += 1;
+            }
+        }
+    }
+    result
+}
+
+// Returns how many instructions a rule matched and what replaces them
+fn apply_rules(window: &[Option<(&str, Vec<&str>)>]) -> Option<(usize, Vec<String>)> {
+    let get = |i: usize| window.get(i).and_then(|parsed| parsed.as_ref()).map(|(op, args)| (*op, args.as_slice()));
+
+    // push rax / pop rcx => mov rcx, rax (and a push straight back into rax is nothing)
+    if let (Some(("push", ["rax"])), Some(("pop", [reg]))) = (get(0), get(1)) {
+        return Some(match *reg {
+            "rax" => (2, vec![]),
+            reg => (2, vec![format!("    mov {}, rax", reg)]),
+        });
+    }
+
+    // push rax / mov rax, x / pop rcx => mov rcx, rax / mov rax, x
+    if let (Some(("push", ["rax"])), Some((load @ ("mov" | "lea"), ["rax", source])), Some(("pop", ["rcx"]))) =
+        (get(0), get(1), get(2))
+    {
+        if !source.contains("rcx") && !source.contains("rsp") {
+            return Some((3, vec!["    mov rcx, rax".into(), format!("    {} rax, {}", load, source)]));
+        }
+    }
+
+    // mov rcx, rax / mov rax, x / <op> rax, rcx => <op> rax, x
+    if let (Some(("mov", ["rcx", "rax"])), Some(("mov", ["rax", operand]))) = (get(0), get(1)) {
+        if is_operand(operand) {
+            match get(2) {
+                Some((op @ ("add" | "imul"), ["rax", "rcx"])) => {
+                    return Some((3, vec![format!("    {} rax, {}", op, operand)]));
+                }
+                // Equality is symmetric and `cmp rcx, rax` already has the operands in order
+                Some(("cmp", ["rax", "rcx"] | ["rcx", "rax"])) => {
+                    return Some((3, vec![format!("    cmp rax, {}", operand)]));
+                }
+                Some(("sub", ["rcx", "rax"])) if get(3) == Some(("mov", &["rax", "rcx"][..])) => {
+                    return Some((4, vec![format!("    sub rax, {}", operand)]));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // setcc al / movzx rax, al / cmp rax, 0 / je|jne label => jump on the condition directly
+    if let (Some((set, ["al"])), Some(("movzx", ["rax", "al"])), Some(("cmp", ["rax", "0"])), Some((jump, [label]))) =
+        (get(0), get(1), get(2), get(3))
+    {
+        if let Some(condition) = set.strip_prefix("set") {
+            let condition = match jump {
+                "je" => inverse(condition),
+                "jne" => Some(condition),
+                _ => None,
+            };
+            if let Some(condition) = condition {
+                return Some((4, vec![format!("    j{} {}", condition, label)]));
+            }
+        }
+    }
+
+    None
+}
+
+// Operands that can replace rcx in an instruction: 32-bit immediates and stack slots
+fn is_operand(operand: &str) -> bool {
+    let immediate = operand.parse::<i64>().is_ok_and(|value| i32::try_from(value).is_ok());
+    immediate || (operand.starts_with("[rbp - ") && operand.ends_with(']'))
+}
+
+fn inverse(condition: &str) -> Option<&'static str> {
+    Some(match condition {
+        "e" => "ne",
+        "ne" => "e",
+        "l" => "ge",
+        "le" => "g",
+        "g" => "le",
+        "ge" => "l",
+        "a" => "be",
+        "ae" => "b",
+        _ => return None,
+    })
+}
+
+// Drops instructions after an unconditional jump up to the next label, then
+// jumps to the label that immediately follows
+fn remove_dead_jumps(lines: Vec<String>) -> Vec<String> {
+    let mut result: Vec<String> = Vec::with_capacity(lines.len());
+    let mut reachable = true;
+    for line in lines {
+        match parse(&line) {
+            Some(_) if !reachable => continue,
+            Some((op, _)) => {
+                reachable = op != "jmp" && op != "ret";
+                result.push(line);
+            }
+            None => {
+                reachable = true;
+                let label = line.strip_suffix(':');
+                let jumps_here = result.last().and_then(|last| parse(last)).is_some_and(|(op, args)| {
+                    op == "jmp" && label.is_some_and(|label| args == [label])
+                });
+                if jumps_here {
+                    result.pop();
+                }
+                result.push(line);
+            }
+        }
+    }
+    result
+}
@@ -0,0 +1,208 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::ir::{Function, VReg};
+
+// Linear-scan register allocation. The instructions and terminators of the
+// blocks, in order, are numbered as one list of points; liveness analysis
+// over the control-flow graph then gives every virtual register one live
+// interval covering each point where it may hold a value still needed.
+// Intervals are visited in order of their start and handed a free register;
+// when none is free, whichever interval ends last is spilled to a frame slot
+// for its whole lifetime.
+//
+// Positions are twice the point index for reads and one more for writes, so
+// a value last read by an instruction can share a register with the one it
+// defines.
+
+pub struct Registers {
+    // Clobbered by calls: values live across a call are saved around it
+    pub caller_saved: &'static [&'static str],
+    // Preserved by calls: saved once by the function if it uses them
+    pub callee_saved: &'static [&'static str],
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Assignment {
+    Register(&'static str),
+    // Index of a frame slot
+    Spilled(usize),
+}
+
+pub struct Allocation {
+    pub assignments: HashMap<VReg, Assignment>,
+    pub spill_slots: usize,
+    // Callee-saved registers the function uses, in the order of Registers
+    pub callee_saved: Vec<&'static str>,
+    // Caller-saved registers holding live values across the call at each
+    // point, numbering instructions and terminators of the blocks in order
+    pub saved_across: HashMap<usize, Vec<&'static str>>,
+}
+
+struct Interval {
+    vreg: VReg,
+    start: i64,
+    end: i64,
+    crosses_call: bool,
+}
+
+// An instruction or terminator, reduced to what liveness needs
+struct Point {
+    uses: Vec<VReg>,
+    def: Option<VReg>,
+    is_call: bool,
+    successors: Vec<usize>,
+}
+
+pub fn allocate(function: &Function, registers: &Registers) -> Allocation {
+    assert!(!function.ssa, "{} must leave SSA form before register allocation", function.name);
+    let points = points(function);
+    let intervals = live_intervals(function, &points);
+    let mut assignments = HashMap::new();
+    let mut spill_slots = 0;
+    // Intervals currently holding a register
+    let mut active: Vec<(&Interval, &'static str)> = Vec::new();
+
+    for interval in &intervals {
+        active.retain(|(other, _)| other.end >= interval.start);
+        let taken: Vec<&str> = active.iter().map(|(_, reg)| *reg).collect();
+        // Values that survive a call are best kept where the call preserves them
+        let preferred = if interval.crosses_call {
+            registers.callee_saved.iter().chain(registers.caller_saved)
+        } else {
+            registers.caller_saved.iter().chain(registers.callee_saved)
+        };
+        if let Some(&reg) = preferred.into_iter().find(|reg| !taken.contains(reg)) {
+            assignments.insert(interval.vreg, Assignment::Register(reg));
+            active.push((interval, reg));
+            continue;
+        }
+
+        let (furthest, _) = active.iter().enumerate().max_by_key(|(_, (other, _))| other.end).unwrap();
+        if active[furthest].0.end > interval.end {
+            // Take the register of the interval that stays live longest
+            let (spilled, reg) = active.remove(furthest);
+            assignments.insert(spilled.vreg, Assignment::Spilled(spill_slots));
+            assignments.insert(interval.vreg, Assignment::Register(reg));
+            active.push((interval, reg));
+        } else {
+            assignments.insert(interval.vreg, Assignment::Spilled(spill_slots));
+        }
+        spill_slots += 1;
+    }
+
+    let callee_saved = registers
+        .callee_saved
+        .iter()
+        .copied()
+        .filter(|reg| assignments.values().any(|assignment| *assignment == Assignment::Register(reg)))
+        .collect();
+
+    let mut saved_across = HashMap::new();
+    for (index, point) in points.iter().enumerate() {
+        if !point.is_call {
+            continue;
+        }
+        let position = index as i64 * 2 + 1;
+        let mut saved = Vec::new();
+        for interval in &intervals {
+            if let Some(Assignment::Register(reg)) = assignments.get(&interval.vreg) {
+                if interval.start < position && interval.end > position && registers.caller_saved.contains(reg) {
+                    saved.push(*reg);
+                }
+            }
+        }
+        saved_across.insert(index, saved);
+    }
+
+    Allocation { assignments, spill_slots, callee_saved, saved_across }
+}
+
+fn points(function: &Function) -> Vec<Point> {
+    let mut starts = Vec::new();
+    let mut count = 0;
+    for block in &function.blocks {
+        starts.push(count);
+        count += block.insts.len() + 1;
+    }
+    let mut points = Vec::new();
+    for block in &function.blocks {
+        for inst in &block.insts {
+            let next = points.len() + 1;
+            points.push(Point { uses: inst.uses(), def: inst.def(), is_call: inst.is_call(), successors: vec![next] });
+        }
+        points.push(Point {
+            uses: block.terminator.uses(),
+            def: None,
+            is_call: false,
+            successors: block.terminator.successors().into_iter().map(|target| starts[target]).collect(),
+        });
+    }
+    points
+}
+
+// Intervals of every virtual register that is read or written, ordered by start
+fn live_intervals(function: &Function, points: &[Point]) -> Vec<Interval> {
+    let live_out = live_out(points);
+    let mut ranges: HashMap<VReg, (i64, i64)> = HashMap::new();
+    let mut extend = |vreg: VReg, position: i64| {
+        let range = ranges.entry(vreg).or_insert((position, position));
+        range.0 = range.0.min(position);
+        range.1 = range.1.max(position);
+    };
+
+    // Parameters arrive before the first instruction
+    for &param in &function.params {
+        extend(param, -1);
+    }
+    for (index, point) in points.iter().enumerate() {
+        let position = index as i64 * 2;
+        for &vreg in &point.uses {
+            extend(vreg, position);
+        }
+        if let Some(vreg) = point.def {
+            extend(vreg, position + 1);
+        }
+        for &vreg in &live_out[index] {
+            extend(vreg, position + 1);
+            extend(vreg, position + 2);
+        }
+    }
+
+    let calls: Vec<i64> = (0..points.len())
+        .filter(|&index| points[index].is_call)
+        .map(|index| index as i64 * 2 + 1)
+        .collect();
+    let mut intervals: Vec<Interval> = ranges
+        .into_iter()
+        .map(|(vreg, (start, end))| Interval {
+            vreg,
+            start,
+            end,
+            crosses_call: calls.iter().any(|&call| start < call && end > call),
+        })
+        .collect();
+    intervals.sort_by_key(|interval| (interval.start, interval.vreg.0));
+    intervals
+}
+
+// Virtual registers live after each point, by iterating the dataflow
+// equations backwards to a fixed point
+fn live_out(points: &[Point]) -> Vec<HashSet<VReg>> {
+    let mut live_in: Vec<HashSet<VReg>> = vec![HashSet::new(); points.len()];
+    let mut live_out: Vec<HashSet<VReg>> = vec![HashSet::new(); points.len()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (index, point) in points.iter().enumerate().rev() {
+            let out: HashSet<VReg> = point.successors.iter().flat_map(|&next| live_in[next].iter().copied()).collect();
+            let mut inn: HashSet<VReg> = out.iter().copied().filter(|&vreg| Some(vreg) != point.def).collect();
+            inn.extend(point.uses.iter().copied());
+            if inn != live_in[index] || out != live_out[index] {
+                live_in[index] = inn;
+                live_out[index] = out;
+                changed = true;
+            }
+        }
+    }
+    live_out
+}
@@ -0,0 +1,1204 @@
+// Runtime support routines, written in assembly and appended to the output
+// only when the compiled program uses them. Everything the generated code
+// needs beyond plain arithmetic goes through a `bonk_*` routine so the
+// C library dependencies stay in one place. With --freestanding, the routines
+// that need the C library are replaced by versions built on Linux system
+// calls, where the language can do without it. AArch64 programs get their own
+// versions of the routines, in GNU as syntax.
+
+pub struct Routine {
+    pub name: &'static str,
+    // C library functions the routine calls
+    pub externs: &'static [&'static str],
+    // Other runtime routines the routine calls
+    pub uses: &'static [&'static str],
+    // Read-only data the routine refers to
+    pub data: &'static [&'static str],
+    // Zero-initialised writable storage the routine refers to
+    pub bss: &'static [&'static str],
+    pub code: &'static str,
+}
+
+const ROUTINES: &[Routine] = &[
+    // rdi = size -> rax = heap block of that many bytes
+    Routine {
+        name: "bonk_alloc",
+        externs: &["malloc"],
+        uses: &[],
+        data: &[],
+        bss: &[],
+        code: "
+_bonk_alloc:
+    push rbp
+    mov rbp, rsp
+    call _malloc
+    pop rbp
+    ret",
+    },
+    // rdi = a, rsi = b -> rax = newly allocated a followed by b
+    Routine {
+        name: "bonk_str_concat",
+        externs: &["strlen", "malloc", "memcpy"],
+        uses: &[],
+        data: &[],
+        bss: &[],
+        code: "
+_bonk_str_concat:
+    push rbp
+    mov rbp, rsp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    sub rsp, 8
+    mov rbx, rdi
+    mov r12, rsi
+    call _strlen
+    mov r13, rax
+    mov rdi, r12
+    call _strlen
+    mov r14, rax
+    lea rdi, [r13 + r14 + 1]
+    call _malloc
+    mov r15, rax
+    mov rdi, r15
+    mov rsi, rbx
+    mov rdx, r13
+    call _memcpy
+    lea rdi, [r15 + r13]
+    mov rsi, r12
+    lea rdx, [r14 + 1]
+    call _memcpy
+    mov rax, r15
+    add rsp, 8
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    ret",
+    },
+    // rdi = a, rsi = b -> rax = negative, zero or positive like strcmp
+    Routine {
+        name: "bonk_str_compare",
+        externs: &["strcmp"],
+        uses: &[],
+        data: &[],
+        bss: &[],
+        code: "
+_bonk_str_compare:
+    push rbp
+    mov rbp, rsp
+    call _strcmp
+    movsxd rax, eax
+    pop rbp
+    ret",
+    },
+    // rdi = s -> rax = length in bytes
+    Routine {
+        name: "bonk_str_len",
+        externs: &["strlen"],
+        uses: &[],
+        data: &[],
+        bss: &[],
+        code: "
+_bonk_str_len:
+    push rbp
+    mov rbp, rsp
+    call _strlen
+    pop rbp
+    ret",
+    },
+    // rdi = n -> rax = newly allocated decimal representation
+    Routine {
+        name: "bonk_str_from_int",
+        externs: &["malloc", "snprintf"],
+        uses: &[],
+        data: &["bonk_fmt_int: db \"%ld\", 0"],
+        bss: &[],
+        code: "
+_bonk_str_from_int:
+    push rbp
+    mov rbp, rsp
+    push rbx
+    push r12
+    mov rbx, rdi
+    mov rdi, 24
+    call _malloc
+    mov r12, rax
+    mov rdi, r12
+    mov rsi, 24
+    lea rdx, [rel bonk_fmt_int]
+    mov rcx, rbx
+    mov rax, 0
+    call _snprintf
+    mov rax, r12
+    pop r12
+    pop rbx
+    pop rbp
+    ret",
+    },
+    // xmm0 = x -> rax = newly allocated shortest representation (%g)
+    Routine {
+        name: "bonk_str_from_float",
+        externs: &["malloc", "snprintf"],
+        uses: &[],
+        data: &["bonk_fmt_float: db \"%g\", 0"],
+        bss: &[],
+        code: "
+_bonk_str_from_float:
+    push rbp
+    mov rbp, rsp
+    push r12
+    sub rsp, 8
+    movq [rsp], xmm0
+    mov rdi, 32
+    call _malloc
+    mov r12, rax
+    mov rdi, r12
+    mov rsi, 32
+    lea rdx, [rel bonk_fmt_float]
+    movq xmm0, [rsp]
+    mov rax, 1
+    call _snprintf
+    mov rax, r12
+    add rsp, 8
+    pop r12
+    pop rbp
+    ret",
+    },
+    // rdi = s -> rax = leading decimal integer of s, or 0
+    Routine {
+        name: "bonk_str_to_int",
+        externs: &["strtol"],
+        uses: &[],
+        data: &[],
+        bss: &[],
+        code: "
+_bonk_str_to_int:
+    push rbp
+    mov rbp, rsp
+    mov rsi, 0
+    mov rdx, 10
+    call _strtol
+    pop rbp
+    ret",
+    },
+    // -> rax = next whitespace-separated integer on stdin; at end of input or
+    // on malformed input returns 0 and sets the eof flag
+    Routine {
+        name: "bonk_read_int",
+        externs: &["scanf"],
+        uses: &[],
+        data: &["bonk_fmt_read_int: db \" %ld\", 0"],
+        bss: &["bonk_eof_flag: resq 1"],
+        code: "
+_bonk_read_int:
+    push rbp
+    mov rbp, rsp
+    sub rsp, 16
+    mov qword [rel bonk_eof_flag], 0
+    lea rdi, [rel bonk_fmt_read_int]
+    lea rsi, [rbp - 8]
+    mov rax, 0
+    call _scanf
+    cmp eax, 1
+    je .done
+    mov qword [rel bonk_eof_flag], 1
+    mov qword [rbp - 8], 0
+.done:
+    mov rax, [rbp - 8]
+    mov rsp, rbp
+    pop rbp
+    ret",
+    },
+    // -> rax = next line of stdin without its newline, newly allocated; at end
+    // of input returns an empty string and sets the eof flag
+    Routine {
+        name: "bonk_read_line",
+        externs: &["getchar", "malloc", "realloc"],
+        uses: &[],
+        data: &[],
+        bss: &["bonk_eof_flag: resq 1"],
+        code: "
+_bonk_read_line:
+    push rbp
+    mov rbp, rsp
+    push rbx
+    push r12
+    push r13
+    push r14
+    mov qword [rel bonk_eof_flag], 0
+    mov r13, 64
+    mov rdi, r13
+    call _malloc
+    mov rbx, rax
+    mov r12, 0
+.next:
+    call _getchar
+    cmp eax, -1
+    je .end_of_input
+    cmp eax, 10
+    je .done
+    lea rcx, [r12 + 1]
+    cmp rcx, r13
+    jb .store
+    mov r14, rax
+    shl r13, 1
+    mov rdi, rbx
+    mov rsi, r13
+    call _realloc
+    mov rbx, rax
+    mov rax, r14
+.store:
+    mov [rbx + r12], al
+    inc r12
+    jmp .next
+.end_of_input:
+    cmp r12, 0
+    jne .done
+    mov qword [rel bonk_eof_flag], 1
+.done:
+    mov byte [rbx + r12], 0
+    mov rax, rbx
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    ret",
+    },
+    // -> rax = 1 if the last read hit end of input, otherwise 0
+    Routine {
+        name: "bonk_eof",
+        externs: &[],
+        uses: &[],
+        data: &[],
+        bss: &["bonk_eof_flag: resq 1"],
+        code: "
+_bonk_eof:
+    mov rax, [rel bonk_eof_flag]
+    ret",
+    },
+    // rdi = argc, rsi = argv -> rax = list of the argument strings, laid out
+    // like std::list as [length, capacity, items...]
+    Routine {
+        name: "bonk_args",
+        externs: &["calloc"],
+        uses: &[],
+        data: &[],
+        bss: &[],
+        code: "
+_bonk_args:
+    push rbp
+    mov rbp, rsp
+    push rbx
+    push r12
+    mov rbx, rdi
+    mov r12, rsi
+    lea rdi, [rbx + 2]
+    mov rsi, 8
+    call _calloc
+    mov [rax], rbx
+    mov [rax + 8], rbx
+    mov rcx, 0
+.copy:
+    cmp rcx, rbx
+    jge .done
+    mov rdx, [r12 + rcx * 8]
+    mov [rax + rcx * 8 + 16], rdx
+    inc rcx
+    jmp .copy
+.done:
+    pop r12
+    pop rbx
+    pop rbp
+    ret",
+    },
+    // rdi = argument list, rsi = index -> rax = that argument, or an empty
+    // string when the index is out of range
+    Routine {
+        name: "bonk_arg",
+        externs: &[],
+        uses: &[],
+        data: &["bonk_empty_str: db 0"],
+        bss: &[],
+        code: "
+_bonk_arg:
+    lea rax, [rel bonk_empty_str]
+    cmp rsi, 0
+    jl .done
+    cmp rsi, [rdi]
+    jge .done
+    mov rax, [rdi + rsi * 8 + 16]
+.done:
+    ret",
+    },
+    // rdi = status; flushes output and ends the process
+    Routine {
+        name: "bonk_exit",
+        externs: &["exit"],
+        uses: &[],
+        data: &[],
+        bss: &[],
+        code: "
+_bonk_exit:
+    push rbp
+    mov rbp, rsp
+    call _exit",
+    },
+    // rdi = message; reports a failed runtime check on stderr and exits with
+    // status 70, after flushing what the program printed so far
+    Routine {
+        name: "bonk_trap",
+        externs: &["fflush", "strlen", "write", "exit"],
+        uses: &[],
+        data: &[],
+        bss: &[],
+        code: "
+_bonk_trap:
+    push rbp
+    mov rbp, rsp
+    push rbx
+    sub rsp, 8
+    mov rbx, rdi
+    mov rdi, 0
+    call _fflush
+    mov rdi, rbx
+    call _strlen
+    mov rdx, rax
+    mov rsi, rbx
+    mov rdi, 2
+    call _write
+    mov rdi, 70
+    call _exit",
+    },
+    // rdi = header, rsi = detail; reports a failed assertion on stderr as one
+    // line and exits with status 1, after flushing what the program printed
+    Routine {
+        name: "bonk_assert_fail",
+        externs: &["fflush", "strlen", "write", "exit"],
+        uses: &[],
+        data: &["bonk_newline: db 10"],
+        bss: &[],
+        code: "
+_bonk_assert_fail:
+    push rbp
+    mov rbp, rsp
+    push rbx
+    push r12
+    mov rbx, rdi
+    mov r12, rsi
+    mov rdi, 0
+    call _fflush
+    mov rdi, rbx
+    call _strlen
+    mov rdx, rax
+    mov rsi, rbx
+    mov rdi, 2
+    call _write
+    mov rdi, r12
+    call _strlen
+    mov rdx, rax
+    mov rsi, r12
+    mov rdi, 2
+    call _write
+    mov rdi, 2
+    lea rsi, [rel bonk_newline]
+    mov rdx, 1
+    call _write
+    mov rdi, 1
+    call _exit",
+    },
+];
+
+// Versions of the routines for --freestanding, which write with the `write`
+// system call and allocate by moving the program break with `brk`. Memory is
+// never given back. Routines that need no C library are shared with ROUTINES.
+const FREESTANDING: &[Routine] = &[
+    // rdi = size -> rax = heap block of that many bytes, or 0 when the break
+    // cannot be moved
+    Routine {
+        name: "bonk_alloc",
+        externs: &[],
+        uses: &[],
+        data: &[],
+        bss: &["bonk_heap_end: resq 1"],
+        code: "
+_bonk_alloc:
+    push rbx
+    push r12
+    lea rbx, [rdi + 15]
+    and rbx, -16
+    mov r12, [rel bonk_heap_end]
+    cmp r12, 0
+    jne .grow
+    mov rax, 12
+    mov rdi, 0
+    syscall
+    mov r12, rax
+.grow:
+    lea rdi, [r12 + rbx]
+    mov rax, 12
+    syscall
+    mov [rel bonk_heap_end], rax
+    cmp rax, rdi
+    mov rax, r12
+    jae .done
+    mov rax, 0
+.done:
+    pop r12
+    pop rbx
+    ret",
+    },
+    // rdi = a, rsi = b -> rax = newly allocated a followed by b
+    Routine {
+        name: "bonk_str_concat",
+        externs: &[],
+        uses: &["bonk_str_len", "bonk_alloc"],
+        data: &[],
+        bss: &[],
+        code: "
+_bonk_str_concat:
+    push rbp
+    mov rbp, rsp
+    push rbx
+    push r12
+    push r13
+    push r14
+    mov rbx, rdi
+    mov r12, rsi
+    call _bonk_str_len
+    mov r13, rax
+    mov rdi, r12
+    call _bonk_str_len
+    mov r14, rax
+    lea rdi, [r13 + r14 + 1]
+    call _bonk_alloc
+    mov rcx, 0
+.copy_a:
+    cmp rcx, r13
+    je .copy_b_start
+    movzx rdx, byte [rbx + rcx]
+    mov [rax + rcx], dl
+    inc rcx
+    jmp .copy_a
+.copy_b_start:
+    lea rdi, [rax + r13]
+    mov rcx, 0
+.copy_b:
+    movzx rdx, byte [r12 + rcx]
+    mov [rdi + rcx], dl
+    inc rcx
+    cmp rdx, 0
+    jne .copy_b
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    ret",
+    },
+    // rdi = a, rsi = b -> rax = negative, zero or positive like strcmp
+    Routine {
+        name: "bonk_str_compare",
+        externs: &[],
+        uses: &[],
+        data: &[],
+        bss: &[],
+        code: "
+_bonk_str_compare:
+    mov rcx, 0
+.next:
+    movzx rax, byte [rdi + rcx]
+    movzx rdx, byte [rsi + rcx]
+    cmp rax, rdx
+    jne .done
+    cmp rax, 0
+    je .done
+    inc rcx
+    jmp .next
+.done:
+    sub rax, rdx
+    ret",
+    },
+    // rdi = s -> rax = length in bytes
+    Routine {
+        name: "bonk_str_len",
+        externs: &[],
+        uses: &[],
+        data: &[],
+        bss: &[],
+        code: "
+_bonk_str_len:
+    mov rax, 0
+.next:
+    movzx rcx, byte [rdi + rax]
+    cmp rcx, 0
+    je .done
+    inc rax
+    jmp .next
+.done:
+    ret",
+    },
+    // rdi = s -> rax = leading decimal integer of s, or 0; like strtol but
+    // wrapping on overflow
+    Routine {
+        name: "bonk_str_to_int",
+        externs: &[],
+        uses: &[],
+        data: &[],
+        bss: &[],
+        code: "
+_bonk_str_to_int:
+    mov rax, 0
+    mov r8, 0
+.space:
+    movzx rdx, byte [rdi]
+    cmp rdx, 32
+    je .skip
+    sub rdx, 9
+    cmp rdx, 4
+    ja .sign
+.skip:
+    inc rdi
+    jmp .space
+.sign:
+    movzx rdx, byte [rdi]
+    cmp rdx, 43
+    je .after_sign
+    cmp rdx, 45
+    jne .digit
+    mov r8, 1
+.after_sign:
+    inc rdi
+.digit:
+    movzx rdx, byte [rdi]
+    sub rdx, 48
+    cmp rdx, 9
+    ja .done
+    imul rax, 10
+    add rax, rdx
+    inc rdi
+    jmp .digit
+.done:
+    cmp r8, 0
+    je .positive
+    neg rax
+.positive:
+    ret",
+    },
+    // rdi = n, rsi = end of a buffer of at least 21 bytes -> rax = start of
+    // the decimal representation, written NUL-terminated at the buffer's end
+    Routine {
+        name: "bonk_decimal",
+        externs: &[],
+        uses: &[],
+        data: &[],
+        bss: &[],
+        code: "
+_bonk_decimal:
+    mov rax, rdi
+    mov rcx, 10
+    dec rsi
+    mov byte [rsi], 0
+    cmp rax, 0
+    jge .digit
+    neg rax
+.digit:
+    mov rdx, 0
+    div rcx
+    add rdx, 48
+    dec rsi
+    mov [rsi], dl
+    cmp rax, 0
+    jne .digit
+    cmp rdi, 0
+    jge .done
+    dec rsi
+    mov byte [rsi], 45
+.done:
+    mov rax, rsi
+    ret",
+    },
+    // rdi = n -> rax = newly allocated decimal representation
+    Routine {
+        name: "bonk_str_from_int",
+        externs: &[],
+        uses: &["bonk_alloc", "bonk_decimal"],
+        data: &[],
+        bss: &[],
+        code: "
+_bonk_str_from_int:
+    push rbx
+    mov rbx, rdi
+    mov rdi, 24
+    call _bonk_alloc
+    mov rdi, rbx
+    lea rsi, [rax + 24]
+    call _bonk_decimal
+    pop rbx
+    ret",
+    },
+    // rdi = file descriptor, rsi = s; writes s without its NUL
+    Routine {
+        name: "bonk_write_str",
+        externs: &[],
+        uses: &["bonk_str_len"],
+        data: &[],
+        bss: &[],
+        code: "
+_bonk_write_str:
+    push rbx
+    push r12
+    mov rbx, rdi
+    mov r12, rsi
+    mov rdi, rsi
+    call _bonk_str_len
+    mov rdx, rax
+    mov rsi, r12
+.write:
+    cmp rdx, 0
+    je .done
+    mov rdi, rbx
+    mov rax, 1
+    syscall
+    cmp rax, 0
+    jle .done
+    add rsi, rax
+    sub rdx, rax
+    jmp .write
+.done:
+    pop r12
+    pop rbx
+    ret",
+    },
+    // rdi = n; writes n in decimal to stdout
+    Routine {
+        name: "bonk_write_int",
+        externs: &[],
+        uses: &["bonk_decimal", "bonk_write_str"],
+        data: &[],
+        bss: &[],
+        code: "
+_bonk_write_int:
+    push rbp
+    mov rbp, rsp
+    sub rsp, 32
+    mov rsi, rbp
+    call _bonk_decimal
+    mov rsi, rax
+    mov rdi, 1
+    call _bonk_write_str
+    mov rsp, rbp
+    pop rbp
+    ret",
+    },
+    // rdi = argc, rsi = argv -> rax = list of the argument strings, laid out
+    // like std::list as [length, capacity, items...]
+    Routine {
+        name: "bonk_args",
+        externs: &[],
+        uses: &["bonk_alloc"],
+        data: &[],
+        bss: &[],
+        code: "
+_bonk_args:
+    push rbx
+    push r12
+    mov rbx, rdi
+    mov r12, rsi
+    lea rdi, [rbx + 2]
+    shl rdi, 3
+    call _bonk_alloc
+    mov [rax], rbx
+    mov [rax + 8], rbx
+    mov rcx, 0
+.copy:
+    cmp rcx, rbx
+    jge .done
+    mov rdx, [r12 + rcx * 8]
+    mov [rax + rcx * 8 + 16], rdx
+    inc rcx
+    jmp .copy
+.done:
+    pop r12
+    pop rbx
+    ret",
+    },
+    // rdi = status; ends the process, there being no buffered output
+    Routine {
+        name: "bonk_exit",
+        externs: &[],
+        uses: &[],
+        data: &[],
+        bss: &[],
+        code: "
+_bonk_exit:
+    mov rax, 60
+    syscall",
+    },
+    // rdi = message; reports a failed runtime check on stderr and exits with
+    // status 70
+    Routine {
+        name: "bonk_trap",
+        externs: &[],
+        uses: &["bonk_write_str"],
+        data: &[],
+        bss: &[],
+        code: "
+_bonk_trap:
+    mov rsi, rdi
+    mov rdi, 2
+    call _bonk_write_str
+    mov rdi, 70
+    mov rax, 60
+    syscall",
+    },
+    // rdi = header, rsi = detail; reports a failed assertion on stderr as one
+    // line and exits with status 1
+    Routine {
+        name: "bonk_assert_fail",
+        externs: &[],
+        uses: &["bonk_write_str"],
+        data: &["bonk_newline: db 10, 0"],
+        bss: &[],
+        code: "
+_bonk_assert_fail:
+    push rbx
+    mov rbx, rsi
+    mov rsi, rdi
+    mov rdi, 2
+    call _bonk_write_str
+    mov rsi, rbx
+    mov rdi, 2
+    call _bonk_write_str
+    lea rsi, [rel bonk_newline]
+    mov rdi, 2
+    call _bonk_write_str
+    mov rdi, 1
+    mov rax, 60
+    syscall",
+    },
+];
+
+// Versions of the routines for AArch64 Linux, following AAPCS64: arguments in
+// x0..x7 or d0..d7 and the result in x0. They use the same C library functions
+// as ROUTINES.
+const AARCH64: &[Routine] = &[
+    // x0 = size -> x0 = heap block of that many bytes
+    Routine {
+        name: "bonk_alloc",
+        externs: &["malloc"],
+        uses: &[],
+        data: &[],
+        bss: &[],
+        code: "
+bonk_alloc:
+    b malloc",
+    },
+    // x0 = a, x1 = b -> x0 = newly allocated a followed by b
+    Routine {
+        name: "bonk_str_concat",
+        externs: &["strlen", "malloc", "memcpy"],
+        uses: &[],
+        data: &[],
+        bss: &[],
+        code: "
+bonk_str_concat:
+    stp x29, x30, [sp, #-64]!
+    mov x29, sp
+    stp x19, x20, [sp, #16]
+    stp x21, x22, [sp, #32]
+    str x23, [sp, #48]
+    mov x19, x0
+    mov x20, x1
+    bl strlen
+    mov x21, x0
+    mov x0, x20
+    bl strlen
+    mov x22, x0
+    add x0, x21, x22
+    add x0, x0, #1
+    bl malloc
+    mov x23, x0
+    mov x1, x19
+    mov x2, x21
+    bl memcpy
+    add x0, x23, x21
+    mov x1, x20
+    add x2, x22, #1
+    bl memcpy
+    mov x0, x23
+    ldr x23, [sp, #48]
+    ldp x21, x22, [sp, #32]
+    ldp x19, x20, [sp, #16]
+    ldp x29, x30, [sp], #64
+    ret",
+    },
+    // x0 = a, x1 = b -> x0 = negative, zero or positive like strcmp
+    Routine {
+        name: "bonk_str_compare",
+        externs: &["strcmp"],
+        uses: &[],
+        data: &[],
+        bss: &[],
+        code: "
+bonk_str_compare:
+    stp x29, x30, [sp, #-16]!
+    mov x29, sp
+    bl strcmp
+    sxtw x0, w0
+    ldp x29, x30, [sp], #16
+    ret",
+    },
+    // x0 = s -> x0 = length in bytes
+    Routine {
+        name: "bonk_str_len",
+        externs: &["strlen"],
+        uses: &[],
+        data: &[],
+        bss: &[],
+        code: "
+bonk_str_len:
+    b strlen",
+    },
+    // x0 = n -> x0 = newly allocated decimal representation
+    Routine {
+        name: "bonk_str_from_int",
+        externs: &["malloc", "snprintf"],
+        uses: &[],
+        data: &["bonk_fmt_int: .asciz \"%ld\""],
+        bss: &[],
+        code: "
+bonk_str_from_int:
+    stp x29, x30, [sp, #-32]!
+    mov x29, sp
+    stp x19, x20, [sp, #16]
+    mov x19, x0
+    mov x0, #24
+    bl malloc
+    mov x20, x0
+    mov x1, #24
+    adrp x2, bonk_fmt_int
+    add x2, x2, :lo12:bonk_fmt_int
+    mov x3, x19
+    bl snprintf
+    mov x0, x20
+    ldp x19, x20, [sp, #16]
+    ldp x29, x30, [sp], #32
+    ret",
+    },
+    // d0 = x -> x0 = newly allocated shortest representation (%g)
+    Routine {
+        name: "bonk_str_from_float",
+        externs: &["malloc", "snprintf"],
+        uses: &[],
+        data: &["bonk_fmt_float: .asciz \"%g\""],
+        bss: &[],
+        code: "
+bonk_str_from_float:
+    stp x29, x30, [sp, #-32]!
+    mov x29, sp
+    str x19, [sp, #16]
+    str d0, [sp, #24]
+    mov x0, #32
+    bl malloc
+    mov x19, x0
+    mov x1, #32
+    adrp x2, bonk_fmt_float
+    add x2, x2, :lo12:bonk_fmt_float
+    ldr d0, [sp, #24]
+    bl snprintf
+    mov x0, x19
+    ldr x19, [sp, #16]
+    ldp x29, x30, [sp], #32
+    ret",
+    },
+    // x0 = s -> x0 = leading decimal integer of s, or 0
+    Routine {
+        name: "bonk_str_to_int",
+        externs: &["strtol"],
+        uses: &[],
+        data: &[],
+        bss: &[],
+        code: "
+bonk_str_to_int:
+    mov x1, #0
+    mov x2, #10
+    b strtol",
+    },
+    // -> x0 = next whitespace-separated integer on stdin; at end of input or
+    // on malformed input returns 0 and sets the eof flag
+    Routine {
+        name: "bonk_read_int",
+        externs: &["scanf"],
+        uses: &[],
+        data: &["bonk_fmt_read_int: .asciz \" %ld\""],
+        bss: &["bonk_eof_flag: .zero 8"],
+        code: "
+bonk_read_int:
+    stp x29, x30, [sp, #-32]!
+    mov x29, sp
+    adrp x1, bonk_eof_flag
+    add x1, x1, :lo12:bonk_eof_flag
+    str xzr, [x1]
+    adrp x0, bonk_fmt_read_int
+    add x0, x0, :lo12:bonk_fmt_read_int
+    add x1, sp, #16
+    bl scanf
+    cmp w0, #1
+    b.eq .Lbonk_read_int_done
+    adrp x1, bonk_eof_flag
+    add x1, x1, :lo12:bonk_eof_flag
+    mov x0, #1
+    str x0, [x1]
+    str xzr, [sp, #16]
+.Lbonk_read_int_done:
+    ldr x0, [sp, #16]
+    ldp x29, x30, [sp], #32
+    ret",
+    },
+    // -> x0 = next line of stdin without its newline, newly allocated; at end
+    // of input returns an empty string and sets the eof flag
+    Routine {
+        name: "bonk_read_line",
+        externs: &["getchar", "malloc", "realloc"],
+        uses: &[],
+        data: &[],
+        bss: &["bonk_eof_flag: .zero 8"],
+        code: "
+bonk_read_line:
+    stp x29, x30, [sp, #-48]!
+    mov x29, sp
+    stp x19, x20, [sp, #16]
+    str x21, [sp, #32]
+    adrp x0, bonk_eof_flag
+    add x0, x0, :lo12:bonk_eof_flag
+    str xzr, [x0]
+    mov x21, #64
+    mov x0, x21
+    bl malloc
+    mov x19, x0
+    mov x20, #0
+.Lbonk_read_line_next:
+    bl getchar
+    cmn w0, #1
+    b.eq .Lbonk_read_line_end
+    cmp w0, #10
+    b.eq .Lbonk_read_line_done
+    add x1, x20, #1
+    cmp x1, x21
+    b.lo .Lbonk_read_line_store
+    str x0, [sp, #40]
+    lsl x21, x21, #1
+    mov x0, x19
+    mov x1, x21
+    bl realloc
+    mov x19, x0
+    ldr x0, [sp, #40]
+.Lbonk_read_line_store:
+    strb w0, [x19, x20]
+    add x20, x20, #1
+    b .Lbonk_read_line_next
+.Lbonk_read_line_end:
+    cbnz x20, .Lbonk_read_line_done
+    adrp x0, bonk_eof_flag
+    add x0, x0, :lo12:bonk_eof_flag
+    mov x1, #1
+    str x1, [x0]
+.Lbonk_read_line_done:
+    strb wzr, [x19, x20]
+    mov x0, x19
+    ldr x21, [sp, #32]
+    ldp x19, x20, [sp, #16]
+    ldp x29, x30, [sp], #48
+    ret",
+    },
+    // -> x0 = 1 if the last read hit end of input, otherwise 0
+    Routine {
+        name: "bonk_eof",
+        externs: &[],
+        uses: &[],
+        data: &[],
+        bss: &["bonk_eof_flag: .zero 8"],
+        code: "
+bonk_eof:
+    adrp x0, bonk_eof_flag
+    add x0, x0, :lo12:bonk_eof_flag
+    ldr x0, [x0]
+    ret",
+    },
+    // x0 = argc, x1 = argv -> x0 = list of the argument strings, laid out
+    // like std::list as [length, capacity, items...]
+    Routine {
+        name: "bonk_args",
+        externs: &["calloc"],
+        uses: &[],
+        data: &[],
+        bss: &[],
+        code: "
+bonk_args:
+    stp x29, x30, [sp, #-32]!
+    mov x29, sp
+    stp x19, x20, [sp, #16]
+    mov x19, x0
+    mov x20, x1
+    add x0, x19, #2
+    mov x1, #8
+    bl calloc
+    str x19, [x0]
+    str x19, [x0, #8]
+    mov x1, #0
+.Lbonk_args_copy:
+    cmp x1, x19
+    b.ge .Lbonk_args_done
+    ldr x2, [x20, x1, lsl #3]
+    add x3, x0, x1, lsl #3
+    str x2, [x3, #16]
+    add x1, x1, #1
+    b .Lbonk_args_copy
+.Lbonk_args_done:
+    ldp x19, x20, [sp, #16]
+    ldp x29, x30, [sp], #32
+    ret",
+    },
+    // x0 = argument list, x1 = index -> x0 = that argument, or an empty
+    // string when the index is out of range
+    Routine {
+        name: "bonk_arg",
+        externs: &[],
+        uses: &[],
+        data: &["bonk_empty_str: .byte 0"],
+        bss: &[],
+        code: "
+bonk_arg:
+    mov x2, x0
+    adrp x0, bonk_empty_str
+    add x0, x0, :lo12:bonk_empty_str
+    cmp x1, #0
+    b.lt .Lbonk_arg_done
+    ldr x3, [x2]
+    cmp x1, x3
+    b.ge .Lbonk_arg_done
+    add x2, x2, x1, lsl #3
+    ldr x0, [x2, #16]
+.Lbonk_arg_done:
+    ret",
+    },
+    // x0 = status; flushes output and ends the process
+    Routine {
+        name: "bonk_exit",
+        externs: &["exit"],
+        uses: &[],
+        data: &[],
+        bss: &[],
+        code: "
+bonk_exit:
+    stp x29, x30, [sp, #-16]!
+    mov x29, sp
+    bl exit",
+    },
+    // x0 = message; reports a failed runtime check on stderr and exits with
+    // status 70, after flushing what the program printed so far
+    Routine {
+        name: "bonk_trap",
+        externs: &["fflush", "strlen", "write", "exit"],
+        uses: &[],
+        data: &[],
+        bss: &[],
+        code: "
+bonk_trap:
+    stp x29, x30, [sp, #-32]!
+    mov x29, sp
+    str x19, [sp, #16]
+    mov x19, x0
+    mov x0, #0
+    bl fflush
+    mov x0, x19
+    bl strlen
+    mov x2, x0
+    mov x1, x19
+    mov x0, #2
+    bl write
+    mov x0, #70
+    bl exit",
+    },
+    // x0 = header, x1 = detail; reports a failed assertion on stderr as one
+    // line and exits with status 1, after flushing what the program printed
+    Routine {
+        name: "bonk_assert_fail",
+        externs: &["fflush", "strlen", "write", "exit"],
+        uses: &[],
+        data: &["bonk_newline: .byte 10"],
+        bss: &[],
+        code: "
+bonk_assert_fail:
+    stp x29, x30, [sp, #-32]!
+    mov x29, sp
+    stp x19, x20, [sp, #16]
+    mov x19, x0
+    mov x20, x1
+    mov x0, #0
+    bl fflush
+    mov x0, x19
+    bl strlen
+    mov x2, x0
+    mov x1, x19
+    mov x0, #2
+    bl write
+    mov x0, x20
+    bl strlen
+    mov x2, x0
+    mov x1, x20
+    mov x0, #2
+    bl write
+    mov x0, #2
+    adrp x1, bonk_newline
+    add x1, x1, :lo12:bonk_newline
+    mov x2, #1
+    bl write
+    mov x0, #1
+    bl exit",
+    },
+];
+
+// Entry point of --freestanding programs, which calls main with argc and
+// argv from the initial stack and exits with what it returns. The ELF symbol
+// is `_start`: the object writer drops the macOS underscore of every global.
+pub const START: &str = "
+global __start
+__start:
+    mov rdi, [rsp]
+    lea rsi, [rsp + 8]
+    call _main
+    mov rdi, rax
+    mov rax, 60
+    syscall";
+
+// The routine with this name; with --freestanding, its system call version
+pub fn routine(name: &str, freestanding: bool) -> &'static Routine {
+    if freestanding {
+        if let Some(routine) = FREESTANDING.iter().find(|routine| routine.name == name) {
+            return routine;
+        }
+    }
+    let routine = ROUTINES
+        .iter()
+        .find(|routine| routine.name == name)
+        .unwrap_or_else(|| panic!("Unknown runtime routine {}", name));
+    if freestanding && !routine.externs.is_empty() {
+        panic!("{} needs the C library, which --freestanding does not link", name.trim_start_matches("bonk_"));
+    }
+    routine
+}
+
+// The AArch64 version of the routine with this name
+pub fn aarch64_routine(name: &str) -> &'static Routine {
+    AARCH64
+        .iter()
+        .find(|routine| routine.name == name)
+        .unwrap_or_else(|| panic!("Unknown runtime routine {}", name))
+}
@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+
+use crate::ir::{Block, Function, Inst, Operand, Phi, Terminator, VReg};
+
+// Conversion to and from static single assignment form. Construction follows
+// Cytron et al.: phis go on the dominance frontiers of the blocks writing a
+// register, then a walk down the dominator tree gives every write a fresh
+// register and points each read at the write that reaches it. A read that no
+// write reaches sees 0.
+
+pub fn construct(function: &mut Function) {
+    if function.ssa {
+        return;
+    }
+    let idom = function.dominators();
+    let predecessors = function.predecessors();
+    let blocks = function.blocks.len();
+
+    let mut frontiers = vec![Vec::new(); blocks];
+    for (block, preds) in predecessors.iter().enumerate() {
+        if preds.len() < 2 {
+            continue;
+        }
+        for &pred in preds {
+            let mut runner = pred;
+            while runner != idom[block] {
+                if !frontiers[runner].contains(&block) {
+                    frontiers[runner].push(block);
+                }
+                runner = idom[runner];
+            }
+        }
+    }
+
+    // Blocks writing each register; the parameters are written on entry
+    let mut def_blocks: HashMap<VReg, Vec<usize>> = HashMap::new();
+    for &param in &function.params {
+        def_blocks.entry(param).or_default().push(0);
+    }
+    for (index, block) in function.blocks.iter().enumerate() {
+        for vreg in block.insts.iter().filter_map(Inst::def) {
+            let sites = def_blocks.entry(vreg).or_default();
+            if !sites.contains(&index) {
+                sites.push(index);
+            }
+        }
+    }
+
+    // Register each phi merges, alongside the phis of each block
+    let mut phi_vars: Vec<Vec<VReg>> = vec![Vec::new(); blocks];
+    let mut vars: Vec<&VReg> = def_blocks.keys().collect();
+    vars.sort_by_key(|vreg| vreg.0);
+    for &var in vars {
+        let mut worklist = def_blocks[&var].clone();
+        while let Some(block) = worklist.pop() {
+            for &frontier in &frontiers[block] {
+                if !phi_vars[frontier].contains(&var) {
+                    phi_vars[frontier].push(var);
+                    if !def_blocks[&var].contains(&frontier) {
+                        worklist.push(frontier);
+                    }
+                }
+            }
+        }
+    }
+    for (block, vars) in phi_vars.iter().enumerate() {
+        function.blocks[block].phis = vars.iter().map(|&var| Phi { dst: var, incoming: Vec::new() }).collect();
+    }
+
+    let mut children = vec![Vec::new(); blocks];
+    for block in 1..blocks {
+        children[idom[block]].push(block);
+    }
+    let mut stacks: HashMap<VReg, Vec<VReg>> = function.params.iter().map(|&param| (param, vec![param])).collect();
+    rename(function, 0, &children, &phi_vars, &mut stacks);
+    function.ssa = true;
+}
+
+fn rename(
+    function: &mut Function,
+    block: usize,
+    children: &[Vec<usize>],
+    phi_vars: &[Vec<VReg>],
+    stacks: &mut HashMap<VReg, Vec<VReg>>,
+) {
+    let current = |stacks: &HashMap<VReg, Vec<VReg>>, operand: Operand| match operand {
+        Operand::Reg(var) => stacks.get(&var).and_then(|stack| stack.last()).map_or(Operand::Imm(0), |&vreg| Operand::Reg(vreg)),
+        Operand::Imm(_) => operand,
+    };
+    let mut written = Vec::new();
+
+    for (i, &var) in phi_vars[block].iter().enumerate() {
+        let vreg = function.new_vreg();
+        function.blocks[block].phis[i].dst = vreg;
+        stacks.entry(var).or_default().push(vreg);
+        written.push(var);
+    }
+    for i in 0..function.blocks[block].insts.len() {
+        for operand in function.blocks[block].insts[i].operands_mut() {
+            *operand = current(stacks, *operand);
+        }
+        if let Some(var) = function.blocks[block].insts[i].def() {
+            let vreg = function.new_vreg();
+            *function.blocks[block].insts[i].def_mut().unwrap() = vreg;
+            stacks.entry(var).or_default().push(vreg);
+            written.push(var);
+        }
+    }
+    for operand in function.blocks[block].terminator.operands_mut() {
+        *operand = current(stacks, *operand);
+    }
+
+    for successor in function.blocks[block].terminator.successors() {
+        for (i, &var) in phi_vars[successor].iter().enumerate() {
+            let value = current(stacks, Operand::Reg(var));
+            let phi = &mut function.blocks[successor].phis[i];
+            if !phi.incoming.iter().any(|(pred, _)| *pred == block) {
+                phi.incoming.push((block, value));
+            }
+        }
+    }
+
+    for &child in &children[block] {
+        rename(function, child, children, phi_vars, stacks);
+    }
+    for var in written {
+        stacks.get_mut(&var).unwrap().pop();
+    }
+}
+
+// Replaces the phis with copies at the end of each predecessor. Edges from a
+// block with several successors get a block of their own for the copies, and
+// when a block has several phis their values all go through fresh registers
+// first, since one phi may read what another writes.
+pub fn destruct(function: &mut Function) {
+    if !function.ssa {
+        return;
+    }
+    for block in 0..function.blocks.len() {
+        let phis = std::mem::take(&mut function.blocks[block].phis);
+        if phis.is_empty() {
+            continue;
+        }
+        let mut preds: Vec<usize> = phis[0].incoming.iter().map(|(pred, _)| *pred).collect();
+        preds.sort();
+        for pred in preds {
+            let values: Vec<Operand> = phis
+                .iter()
+                .map(|phi| phi.incoming.iter().find(|(source, _)| *source == pred).unwrap().1)
+                .collect();
+            let copies_in = if function.blocks[pred].terminator.successors().len() > 1 {
+                let split = function.blocks.len();
+                function.blocks.push(Block { phis: Vec::new(), insts: Vec::new(), terminator: Terminator::Jump(block) });
+                for target in function.blocks[pred].terminator.successors_mut() {
+                    if *target == block {
+                        *target = split;
+                    }
+                }
+                split
+            } else {
+                pred
+            };
+
+            let mut copies = Vec::new();
+            if phis.len() == 1 {
+                copies.push(Inst::Copy { dst: phis[0].dst, src: values[0] });
+            } else {
+                let temps: Vec<VReg> = values.iter().map(|_| function.new_vreg()).collect();
+                for (&temp, &value) in temps.iter().zip(&values) {
+                    copies.push(Inst::Copy { dst: temp, src: value });
+                }
+                for (phi, &temp) in phis.iter().zip(&temps) {
+                    copies.push(Inst::Copy { dst: phi.dst, src: Operand::Reg(temp) });
+                }
+            }
+            function.blocks[copies_in].insts.extend(copies);
+        }
+    }
+    function.ssa = false;
+}
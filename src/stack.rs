@@ -0,0 +1,282 @@
+use std::collections::HashMap;
+
+use crate::ast::{BinaryOperator, Expression, FormatPart, FormatSpec, Statement, Type};
+use crate::builtins;
+use crate::compiler::printf_conversion;
+use crate::types::{self, Signatures};
+
+// The stack machine both code generators compile a function with when the IR
+// cannot express it. What does not depend on the target lives here: the order
+// operands are evaluated in, which values wait on the stack or in frame slots,
+// and how calls, inlined bodies, string operations and printf formats are put
+// together. A target implements the primitives, which emit its instructions
+// for a single result register, the stack and the frame.
+pub trait StackMachine {
+    // A frame slot, as the target addresses it
+    type Slot: Copy;
+
+    fn signatures(&self) -> &Signatures;
+    // Types of the variables of the function being compiled
+    fn variables(&self) -> &HashMap<String, Type>;
+    fn variable_slot(&self, name: &str) -> Option<Self::Slot>;
+    fn bind(&mut self, name: &str, slot: Self::Slot, ty: Type);
+    // Reserves an unnamed slot in the current frame
+    fn temp_slot(&mut self) -> Self::Slot;
+    fn new_label(&mut self, label: &str) -> String;
+    fn place_label(&mut self, label: &str);
+    fn is_extern(&self, name: &str) -> bool;
+    fn return_type(&self) -> Type;
+    fn current_function(&self) -> &str;
+    fn param_slots(&self) -> Vec<Self::Slot>;
+    // Where `send` in an inlined body stores its value and jumps to. Returns
+    // the previous exit, for the body of an enclosing inlined call.
+    fn set_inline_exit(&mut self, exit: Option<(String, Self::Slot, Type)>) -> Option<(String, Self::Slot, Type)>;
+
+    fn compile_statements(&mut self, body: &[Statement]);
+    // Evaluates an expression into the result register
+    fn compile_expression(&mut self, expr: &Expression);
+    // Converts the value in the result register between numeric types
+    fn convert(&mut self, from: Type, to: Type);
+    fn load_integer(&mut self, value: i64);
+    fn load_result(&mut self, slot: Self::Slot);
+    fn store_result(&mut self, slot: Self::Slot);
+    fn push_result(&mut self);
+    fn pop_result(&mut self);
+    // Pops the `index`th argument of its type into the register a call
+    // passes it in
+    fn pop_argument(&mut self, ty: Type, index: usize);
+    // Called once the arguments of a direct call are in their registers
+    fn arguments_loaded(&mut self, _float_count: usize) {}
+    // Pops the left operand of a binary operation into the first argument
+    // register and moves the right one, in the result register, to the second
+    fn pop_operands(&mut self);
+    // The same for floats, into the first two float registers
+    fn pop_float_operands(&mut self);
+    // Sets the result register to 1 if the strcmp-style result in it compares
+    // with zero as `op` does, and to 0 otherwise
+    fn set_if(&mut self, op: &BinaryOperator);
+    fn call_runtime(&mut self, name: &'static str);
+    fn string_literal(&mut self, s: &str) -> String;
+    // Calls a printf-family function with the `fixed` integer arguments, each
+    // a frame slot or None for 0, then the format string at `label`, then the
+    // values held in frame slots
+    fn emit_printf_call(&mut self, symbol: &str, fixed: &[Option<Self::Slot>], label: &str, values: &[(Self::Slot, Type)]);
+    // Stores the length snprintf returned, plus one for the terminator, in
+    // `slot` and passes it as the first argument of the next call
+    fn store_buffer_size(&mut self, slot: Self::Slot);
+    // Jumps back to the start of the current function's body
+    fn restart_body(&mut self);
+    // Drops the current frame and jumps to a function, which then returns
+    // straight to our caller
+    fn jump_to_function(&mut self, name: &str);
+
+    fn expr_type(&self, expr: &Expression) -> Type {
+        types::expr_type(expr, self.variables(), self.signatures())
+    }
+
+    fn compile_assignment(&mut self, name: &str, value: &Expression) {
+        let value_ty = self.expr_type(value);
+        let slot = match self.variable_slot(name) {
+            Some(slot) => slot,
+            None => {
+                let slot = self.temp_slot();
+                self.bind(name, slot, value_ty);
+                slot
+            }
+        };
+        let var_ty = self.variables()[name];
+        self.compile_expression(value);
+        self.convert(value_ty, var_ty);
+        self.store_result(slot);
+    }
+
+    // Evaluates the arguments of a direct call into the registers the callee
+    // expects them in and returns the callee's return type
+    fn load_arguments(&mut self, name: &str, args: &[Expression]) -> Type {
+        let returns = self.signatures()[name].1;
+        let param_types = self.push_arguments(name, args);
+
+        // Integers and floats take their own registers, each in order of appearance
+        let float_count = param_types.iter().filter(|&&ty| ty == Type::Float).count();
+        let mut int_index = param_types.len() - float_count;
+        let mut float_index = float_count;
+        // Pop into registers in reverse order
+        for &param in param_types.iter().rev() {
+            if param == Type::Float {
+                float_index -= 1;
+                self.pop_argument(param, float_index);
+            } else {
+                int_index -= 1;
+                self.pop_argument(param, int_index);
+            }
+        }
+        self.arguments_loaded(float_count);
+        returns
+    }
+
+    // Evaluates each argument converted to its parameter type and pushes it,
+    // returning the parameter types
+    fn push_arguments(&mut self, name: &str, args: &[Expression]) -> Vec<Type> {
+        let param_types: Vec<Type> = self.signatures()[name].0.iter().map(|ty| ty.unwrap_or(Type::Int)).collect();
+        for (arg, &param) in args.iter().zip(&param_types) {
+            let ty = self.expr_type(arg);
+            self.compile_expression(arg);
+            self.convert(ty, param);
+            self.push_result();
+        }
+        param_types
+    }
+
+    // `send ~f(...)` can reuse the current frame when f is a Bonk function
+    // returning the same type, so the call never returns here
+    fn is_tail_callable(&self, name: &str) -> bool {
+        builtins::arity(name).is_none()
+            && self.variable_slot(name).is_none()
+            && !self.is_extern(name)
+            && self.signatures().get(name).is_some_and(|(_, returns)| *returns == self.return_type())
+    }
+
+    fn compile_tail_call(&mut self, name: &str, args: &[Expression]) {
+        if name == self.current_function() {
+            // Reassign the parameters and start the body over
+            self.push_arguments(name, args);
+            for slot in self.param_slots().into_iter().rev() {
+                self.pop_result();
+                self.store_result(slot);
+            }
+            self.restart_body();
+        } else {
+            self.load_arguments(name, args);
+            self.jump_to_function(name);
+        }
+    }
+
+    // Binds the arguments like a call would, then runs the body in this frame
+    fn compile_inlined(
+        &mut self,
+        params: &[(String, Option<Type>)],
+        returns: Type,
+        args: &[Expression],
+        body: &[Statement],
+        result: &str,
+    ) {
+        for ((param, ty), arg) in params.iter().zip(args) {
+            let ty = ty.unwrap_or(Type::Int);
+            let value_ty = self.expr_type(arg);
+            self.compile_expression(arg);
+            self.convert(value_ty, ty);
+            let slot = self.temp_slot();
+            self.bind(param, slot, ty);
+            self.store_result(slot);
+        }
+        let slot = self.temp_slot();
+        self.bind(result, slot, returns);
+
+        let exit = self.new_label("inline_end");
+        let outer = self.set_inline_exit(Some((exit.clone(), slot, returns)));
+        self.compile_statements(body);
+        self.set_inline_exit(outer);
+        // Falling off the end sends 0
+        self.load_integer(0);
+        self.store_result(slot);
+        self.place_label(&exit);
+    }
+
+    fn compile_string_op(&mut self, left: &Expression, op: &BinaryOperator, right: &Expression) {
+        self.compile_string_operands(left, right);
+        if *op == BinaryOperator::Add {
+            self.call_runtime("bonk_str_concat");
+            return;
+        }
+        if !op.is_comparison() {
+            unreachable!("the checker rejects {} on strings", op);
+        }
+        // Compare contents, then test the strcmp-style result against zero
+        self.call_runtime("bonk_str_compare");
+        self.set_if(op);
+    }
+
+    // Evaluates both operands of a string operation into the first two
+    // argument registers
+    fn compile_string_operands(&mut self, left: &Expression, right: &Expression) {
+        self.compile_expression(left);
+        self.push_result();
+        self.compile_expression(right);
+        self.pop_operands();
+    }
+
+    // Evaluates both operands of a float operation, converted to float, into
+    // the first two float registers
+    fn compile_float_operands(&mut self, left: &Expression, right: &Expression) {
+        let left_ty = self.expr_type(left);
+        let right_ty = self.expr_type(right);
+        self.compile_expression(left);
+        self.convert(left_ty, Type::Float);
+        self.push_result();
+        self.compile_expression(right);
+        self.convert(right_ty, Type::Float);
+        self.pop_float_operands();
+    }
+
+    // The printf format `print` writes its arguments with, separated by
+    // spaces, and the slots holding the values it converts
+    fn print_format(&mut self, args: &[Expression], newline: bool) -> (String, Vec<(Self::Slot, Type)>) {
+        let mut format = String::new();
+        let mut values = Vec::new();
+        for (i, arg) in args.iter().enumerate() {
+            if i > 0 {
+                format.push(' ');
+            }
+            self.append_format(arg, &mut format, &mut values);
+        }
+        if newline {
+            format.push('\n');
+        }
+        (format, values)
+    }
+
+    // Interpolated strings used as values are formatted into a heap buffer:
+    // one snprintf to measure, one to fill
+    fn compile_format_string(&mut self, expr: &Expression) {
+        let mut format = String::new();
+        let mut values = Vec::new();
+        self.append_format(expr, &mut format, &mut values);
+        let label = self.string_literal(&format);
+
+        self.emit_printf_call("snprintf", &[None, None], &label, &values);
+        let size = self.temp_slot();
+        self.store_buffer_size(size);
+        self.call_runtime("bonk_alloc");
+        let buffer = self.temp_slot();
+        self.store_result(buffer);
+
+        self.emit_printf_call("snprintf", &[Some(buffer), Some(size)], &label, &values);
+        self.load_result(buffer);
+    }
+
+    // Appends an argument's printf conversions to `format`, evaluating the
+    // values it needs into frame slots
+    fn append_format(&mut self, arg: &Expression, format: &mut String, values: &mut Vec<(Self::Slot, Type)>) {
+        match arg {
+            Expression::StringLiteral(text) => format.push_str(&text.replace('%', "%%")),
+            Expression::Format(parts) => {
+                for part in parts {
+                    match part {
+                        FormatPart::Text(text) => format.push_str(&text.replace('%', "%%")),
+                        FormatPart::Value { expr, spec } => self.append_value(expr, spec, format, values),
+                    }
+                }
+            }
+            _ => self.append_value(arg, &FormatSpec::default(), format, values),
+        }
+    }
+
+    fn append_value(&mut self, expr: &Expression, spec: &FormatSpec, format: &mut String, values: &mut Vec<(Self::Slot, Type)>) {
+        let ty = self.expr_type(expr);
+        self.compile_expression(expr);
+        let slot = self.temp_slot();
+        self.store_result(slot);
+        format.push_str(&printf_conversion(spec, ty));
+        values.push((slot, ty));
+    }
+}
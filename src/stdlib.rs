@@ -0,0 +1,15 @@
+use std::path::Path;
+
+// Standard library modules shipped inside the compiler, imported with `use std::<name>;`
+const MODULES: &[(&str, &str)] = &[
+    ("math.bonk", include_str!("std/math.bonk")),
+    ("string.bonk", include_str!("std/string.bonk")),
+    ("list.bonk", include_str!("std/list.bonk")),
+];
+
+pub fn source(path: &Path) -> Option<&'static str> {
+    MODULES
+        .iter()
+        .find(|(name, _)| Path::new(name) == path)
+        .map(|(_, source)| *source)
+}
@@ -1,17 +1,25 @@
 #[derive(Debug, PartialEq, Clone)]
 pub enum Token {
     Number(i64),
+    Float(f64),
     Identifier(String),
     StringLiteral(String),
     Function,
+    Fn,
+    Extern,
+    Use,
     FunctionCall(String),
+    // `@name` before a function
+    Attribute(String),
     If,
     While,
     Do,
     Then,
     Else,       
     Print,
+    Write,
     Send,
+    Assert,
     Plus,
     Eq,
     NotEq,
@@ -24,8 +32,22 @@ pub enum Token {
     Assign,
     LParen,
     RParen,
+    LBracket,
+    RBracket,
     Comma,
+    Colon,
+    Arrow,
+    FatArrow,
+    Ampersand,
     Semicolon,
     End,
 }
 
+// Where a token is in the source, for error messages
+#[derive(Debug, Clone, Copy)]
+pub struct Span {
+    pub line: usize,
+    // Byte range of the token text
+    pub start: usize,
+    pub end: usize,
+}
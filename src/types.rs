@@ -0,0 +1,52 @@
+use std::collections::HashMap;
+
+use crate::ast::{Expression, Statement, Type};
+use crate::builtins;
+
+// Expression typing, shared by the checker, the constant folder and both code
+// generators so that they agree on the type of every value. A variable has
+// the type of its first assignment, arithmetic involving a float is a float,
+// comparisons are integers and calls return what their signature says.
+
+// Parameter and return types of every function and extern, by name
+pub type Signatures = HashMap<String, (Vec<Option<Type>>, Type)>;
+
+pub fn signatures(program: &[Statement]) -> Signatures {
+    let mut signatures = Signatures::new();
+    for stmt in program {
+        if let Statement::Function { name, params, returns, .. } | Statement::Extern { name, params, returns, .. } = stmt {
+            let types = params.iter().map(|(_, ty)| *ty).collect();
+            signatures.insert(name.clone(), (types, *returns));
+        }
+    }
+    signatures
+}
+
+// `variables` holds the type of each variable in scope, including function
+// values, whose calls always return an integer
+pub fn expr_type(expr: &Expression, variables: &HashMap<String, Type>, signatures: &Signatures) -> Type {
+    match expr {
+        Expression::Integer(_) => Type::Int,
+        Expression::Float(_) => Type::Float,
+        Expression::StringLiteral(_) | Expression::Format(_) => Type::Str,
+        Expression::Variable(name) => variables.get(name).copied().unwrap_or(Type::Int),
+        Expression::BinaryOp { op, .. } if op.is_comparison() => Type::Int,
+        Expression::BinaryOp { left, right, .. } => {
+            let (left, right) = (expr_type(left, variables, signatures), expr_type(right, variables, signatures));
+            if left == Type::Float || right == Type::Float {
+                Type::Float
+            } else {
+                left
+            }
+        }
+        Expression::FunctionCall { name, .. } if builtins::arity(name).is_none() && variables.contains_key(name) => {
+            Type::Int
+        }
+        Expression::FunctionCall { name, .. } => builtins::return_type(name)
+            .or_else(|| signatures.get(name).map(|(_, returns)| *returns))
+            .unwrap_or(Type::Int),
+        Expression::Index { .. } | Expression::FunctionRef(_) | Expression::Lambda { .. } | Expression::Closure { .. } => {
+            Type::Int
+        }
+    }
+}